@@ -0,0 +1,64 @@
+// Deserializes a flat array of a million small integers into a `Vec<i64>`
+// through serde. The padded document puts a PAD byte before every element,
+// which sends each one through the general tag dispatch instead of the
+// integer fast path, so the two timings show what the fast path saves.
+// Run with `cargo bench --bench scalar_ints`.
+//
+// Recorded timings, in ms/iter, median of four runs. Add a row for each
+// machine when the fast path changes; the ratio between the columns is
+// what matters. The padded document is also 60% longer, so part of
+// the gap is reading the extra bytes.
+//
+//     machine, rustc                       contiguous      padded
+//     Xeon VM, 1 vCPU, rustc 1.95.0               5.9        11.2
+
+extern crate serde;
+extern crate sereal_decoder;
+
+use std::time::Instant;
+
+use serde::Deserialize;
+use sereal_decoder::config::Config;
+use sereal_decoder::de::Deserializer;
+
+const ROUNDS: u32 = 20;
+const COUNT: u64 = 1_000_000;
+
+fn document(pad: bool) -> Vec<u8> {
+    // ARRAY of COUNT elements cycling through POS, NEG and VARINT.
+    let mut doc = vec![0x2b, 0xc0, 0x84, 0x3d];
+    for i in 0..COUNT {
+        if pad {
+            doc.push(0x3f);
+        }
+        match i % 3 {
+            0 => doc.push(0x05),
+            1 => doc.push(0x1f),
+            _ => doc.extend_from_slice(b"\x20\xac\x02"),
+        }
+    }
+    doc
+}
+
+fn decode(doc: &[u8]) {
+    let config = Config::default();
+    let mut de = Deserializer::from_body(&config, doc);
+    let v = Vec::<i64>::deserialize(&mut de).unwrap();
+    assert_eq!(v.len() as u64, COUNT);
+}
+
+fn run(name: &str, doc: &[u8]) {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        decode(doc);
+    }
+
+    let elapsed = start.elapsed();
+    let ms = elapsed.as_secs() as f64 * 1e3 + elapsed.subsec_nanos() as f64 / 1e6;
+    println!("{:>20}: {:8.2} ms/iter", name, ms / ROUNDS as f64);
+}
+
+fn main() {
+    run("contiguous", &document(false));
+    run("padded", &document(true));
+}
@@ -0,0 +1,51 @@
+// Decodes a document made of many short arrays with the default arena
+// builder and with the SmallVec-backed one. Run with
+// `cargo bench --features smallvec`.
+
+extern crate sereal_decoder;
+
+use std::time::Instant;
+
+use sereal_decoder::arena::{Arena, ArenaBuilder, SmallArenaBuilder};
+use sereal_decoder::config::Config;
+use sereal_decoder::parser::Parser;
+
+const ROUNDS: u32 = 20;
+
+fn document() -> Vec<u8> {
+    // [ [1, 2, 3], [1, 2, 3], ... ] with 100 000 inner arrays
+    let mut doc = vec![0x2b, 0xa0, 0x8d, 0x06];
+    for _ in 0..100_000 {
+        doc.extend_from_slice(b"\x43\x01\x02\x03");
+    }
+    doc
+}
+
+fn with_vec(doc: &[u8]) {
+    let config = Config::default();
+    let arena = Arena::new();
+    Parser::new(ArenaBuilder::new(&arena), &config, doc).parse().ok().unwrap();
+}
+
+fn with_smallvec(doc: &[u8]) {
+    let config = Config::default();
+    let arena = Arena::new();
+    Parser::new(SmallArenaBuilder::new(&arena), &config, doc).parse().ok().unwrap();
+}
+
+fn run(name: &str, doc: &[u8], f: fn(&[u8])) {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        f(doc);
+    }
+
+    let elapsed = start.elapsed();
+    let ms = elapsed.as_secs() as f64 * 1e3 + elapsed.subsec_nanos() as f64 / 1e6;
+    println!("{:>20}: {:8.2} ms/iter", name, ms / ROUNDS as f64);
+}
+
+fn main() {
+    let doc = document();
+    run("ArenaBuilder", &doc, with_vec);
+    run("SmallArenaBuilder", &doc, with_smallvec);
+}
@@ -1,7 +1,12 @@
-use std::collections::{HashSet, HashMap};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::sync::*;
 
+#[cfg(feature = "indexmap")]
+use indexmap::IndexMap;
+
+use cell::{self, Cell, Item, View};
 use parser;
 pub use parser::Error;
 pub use parser::Result;
@@ -17,12 +22,22 @@ pub enum Inner {
     Ref(Value),
     WeakRef(Value),
     Array(Vec<Value>),
-    Hash(HashMap<Vec<u8>, Value>),
-    Object(Vec<u8>, Value),
+    Hash(Map),
+    /// Class name and blessed value. `ArcBuilder` interns class names, so
+    /// all objects of a class share one allocation of the name.
+    Object(Arc<Vec<u8>>, Value),
     Bool(bool),
     Regexp(Vec<u8>, Vec<u8>),
 }
 
+/// Map behind `Inner::Hash`. With the `indexmap` feature it iterates in
+/// the order keys appear in the document, and `ser` writes them back in
+/// that order.
+#[cfg(not(feature = "indexmap"))]
+pub type Map = HashMap<Vec<u8>, Value>;
+#[cfg(feature = "indexmap")]
+pub type Map = IndexMap<Vec<u8>, Value>;
+
 #[derive(Clone)]
 pub enum Value {
     Strong(Arc<RwLock<Inner>>),
@@ -31,21 +46,94 @@ pub enum Value {
 
 impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.debug_fmt(f, &mut HashSet::new())
+        cell::debug_fmt(self, f, &mut HashSet::new())
+    }
+}
+
+/// Formats the value like Perl's `Data::Dumper` with `Indent = 0` and
+/// `Sortkeys = 1`, without the leading `$VAR1 = `.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.dump_fmt(f, &mut HashSet::new())
     }
 }
 
+/// Callbacks for `Value::walk`. Every method has an empty default, so
+/// implementations only override what they need.
+pub trait Visitor {
+    /// Called for every value that has no children: numbers, strings,
+    /// booleans, undef, regexps and expired weak references.
+    fn visit_scalar(&mut self, _value: &Inner) {}
+
+    /// Called instead of descending into a cell that was already visited,
+    /// either because it is shared or because it is part of a cycle.
+    fn visit_seen(&mut self) {}
+
+    fn enter_ref(&mut self, _weak: bool) {}
+    fn leave_ref(&mut self, _weak: bool) {}
+
+    fn enter_array(&mut self, _len: usize) {}
+    fn leave_array(&mut self) {}
+
+    fn enter_hash(&mut self, _len: usize) {}
+    /// Called before the value of each hash entry.
+    fn visit_key(&mut self, _key: &[u8]) {}
+    fn leave_hash(&mut self) {}
+
+    fn enter_object(&mut self, _class: &[u8]) {}
+    fn leave_object(&mut self) {}
+}
+
 impl Value {
     pub fn new(v: Inner) -> Value {
         Value::Strong(Arc::new(RwLock::new(v)))
     }
 
+    pub fn is_weak(&self) -> bool {
+        matches!(*self, Value::Weak(_))
+    }
+
+    /// Whether the cell still exists. Always true for strong values.
+    pub fn is_alive(&self) -> bool {
+        match *self {
+            Value::Strong(_) => true,
+            Value::Weak(ref w) => w.upgrade().is_some(),
+        }
+    }
+
+    /// A strong handle to the same cell, unless it has been dropped.
+    pub fn try_upgrade(&self) -> Option<Value> {
+        self.upgrade().map(Value::Strong)
+    }
+
+    /// Traverse the tree depth-first, visiting each cell once.
+    pub fn walk<V: Visitor>(&self, visitor: &mut V) {
+        self.walk_inner(visitor, &mut HashSet::new())
+    }
+
+    fn walk_inner<V: Visitor>(&self, visitor: &mut V, seen: &mut HashSet<usize>) {
+        match self.upgrade() {
+            Some(ref a) => {
+                let obj_id = a.as_ref() as *const _ as usize;
+                if seen.contains(&obj_id) {
+                    visitor.visit_seen();
+                } else {
+                    seen.insert(obj_id);
+                    let inner = a.read().unwrap().clone();
+                    inner.walk(visitor, seen);
+                }
+            }
+
+            None => visitor.visit_scalar(&Inner::Undef),
+        }
+    }
+
     fn read(&self) -> Inner {
-        match self {
-            &Value::Strong(ref a) => (&*a.read().unwrap()).clone(),
-            &Value::Weak(ref w) => {
+        match *self {
+            Value::Strong(ref a) => a.read().unwrap().clone(),
+            Value::Weak(ref w) => {
                 match w.upgrade() {
-                    Some(ref l) => (&*l.read().unwrap()).clone(),
+                    Some(ref l) => l.read().unwrap().clone(),
                     None => Inner::Undef,
                 }
             }
@@ -54,9 +142,9 @@ impl Value {
 
     /// Update the inner value.
     fn set(&self, v: Inner) {
-        match self {
-            &Value::Strong(ref a) => *a.write().unwrap() = v,
-            &Value::Weak(ref w) => {
+        match *self {
+            Value::Strong(ref a) => *a.write().unwrap() = v,
+            Value::Weak(ref w) => {
                 let a = w.upgrade().expect("writing expired weak-ref");
                 let mut l = a.write().unwrap();
                 *l = v;
@@ -64,11 +152,6 @@ impl Value {
         }
     }
 
-    /// Make this cell to alias another. Old value is discarded.
-    fn alias(&mut self, v: Value) {
-        *self = v;
-    }
-
     fn to_string(&self) -> Result<Vec<u8>> {
         self.read().to_string()
     }
@@ -81,13 +164,13 @@ impl Value {
     }
 
     fn upgrade(&self) -> Option<Arc<RwLock<Inner>>> {
-        match self {
-            &Value::Strong(ref a) => Some(a.clone()),
-            &Value::Weak(ref w) => w.upgrade(),
+        match *self {
+            Value::Strong(ref a) => Some(a.clone()),
+            Value::Weak(ref w) => w.upgrade(),
         }
     }
 
-    fn debug_fmt(&self, f: &mut fmt::Formatter, seen: &mut HashSet<usize>) -> fmt::Result {
+    fn dump_fmt(&self, f: &mut fmt::Formatter, seen: &mut HashSet<usize>) -> fmt::Result {
         match self.upgrade() {
             Some(ref a) => {
                 let obj_id = a.as_ref() as *const _ as usize;
@@ -95,15 +178,28 @@ impl Value {
                     write!(f, "<loop>")
                 } else {
                     seen.insert(obj_id);
-                    (&*a.read().unwrap()).debug_fmt(f, seen)
+                    let res = a.read().unwrap().dump_fmt(f, seen);
+                    seen.remove(&obj_id);
+                    res
                 }
             }
 
-            None => write!(f, "<dead weak ref>"),
+            None => write!(f, "undef"),
         }
     }
 }
 
+fn dump_str(f: &mut fmt::Formatter, s: &[u8]) -> fmt::Result {
+    write!(f, "'")?;
+    for c in String::from_utf8_lossy(s).chars() {
+        match c {
+            '\\' | '\'' => write!(f, "\\{}", c)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "'")
+}
+
 impl PartialEq for Value {
     fn eq(&self, other: &Value) -> bool {
         self.read() == other.read()
@@ -138,147 +234,354 @@ impl<'a> From<&'a [u8]> for Inner {
 }
 
 impl Inner {
-    fn to_string(&self) -> Result<Vec<u8>> {
-        match self {
-            &Inner::String(ref v) => Ok(v.clone()),
-            _ => Err(Error::InvalidType),
-        }
-    }
-
-    fn debug_fmt(&self, f: &mut fmt::Formatter, seen: &mut HashSet<usize>) -> fmt::Result {
-        match self {
-            &Inner::Ref(ref v) => {
-                write!(f, "\\")?;
-                v.debug_fmt(f, seen)?;
+    fn walk<V: Visitor>(&self, visitor: &mut V, seen: &mut HashSet<usize>) {
+        match *self {
+            Inner::Ref(ref v) => {
+                visitor.enter_ref(false);
+                v.walk_inner(visitor, seen);
+                visitor.leave_ref(false);
             }
 
-            &Inner::WeakRef(ref v) => {
-                write!(f, "\\?")?;
-                v.debug_fmt(f, seen)?;
+            Inner::WeakRef(ref v) => {
+                visitor.enter_ref(true);
+                v.walk_inner(visitor, seen);
+                visitor.leave_ref(true);
             }
 
-            &Inner::Array(ref a) => {
-                write!(f, "Array(")?;
+            Inner::Array(ref a) => {
+                visitor.enter_array(a.len());
                 for v in a {
-                    v.debug_fmt(f, seen)?;
+                    v.walk_inner(visitor, seen);
                 }
-                write!(f, ")")?;
+                visitor.leave_array();
             }
 
-            &Inner::Hash(ref h) => {
-                write!(f, "Hash(")?;
+            Inner::Hash(ref h) => {
+                visitor.enter_hash(h.len());
                 for (k, v) in h {
-                    write!(f, "{:?} => ", k)?;
-                    v.debug_fmt(f, seen)?;
+                    visitor.visit_key(k);
+                    v.walk_inner(visitor, seen);
                 }
-                write!(f, ")")?;
+                visitor.leave_hash();
             }
 
-            &Inner::Object(ref class, ref obj) => {
-                write!(f, "<{:?}=", class)?;
-                obj.debug_fmt(f, seen)?;
-                write!(f, ">")?;
+            Inner::Object(ref class, ref v) => {
+                visitor.enter_object(class);
+                v.walk_inner(visitor, seen);
+                visitor.leave_object();
             }
 
-            other => write!(f, "{:?}", other)?,
+            _ => visitor.visit_scalar(self),
         }
+    }
 
-        Ok(())
+    fn to_string(&self) -> Result<Vec<u8>> {
+        match *self {
+            Inner::String(ref v) => Ok(v.clone()),
+            _ => Err(Error::InvalidType),
+        }
+    }
+
+    fn dump_fmt(&self, f: &mut fmt::Formatter, seen: &mut HashSet<usize>) -> fmt::Result {
+        match *self {
+            Inner::Undef => write!(f, "undef"),
+            Inner::I64(v) => write!(f, "{}", v),
+            Inner::U64(v) => write!(f, "{}", v),
+            Inner::F32(v) => write!(f, "'{}'", v),
+            Inner::F64(v) => write!(f, "'{}'", v),
+            Inner::Bool(true) => write!(f, "1"),
+            Inner::Bool(false) => write!(f, "''"),
+            Inner::String(ref s) => dump_str(f, s),
+
+            Inner::Ref(ref v) | Inner::WeakRef(ref v) => {
+                match v.read() {
+                    Inner::Array(_) | Inner::Hash(_) => (),
+                    _ => write!(f, "\\")?,
+                }
+                v.dump_fmt(f, seen)
+            }
+
+            Inner::Array(ref a) => {
+                write!(f, "[")?;
+                for (i, v) in a.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    v.dump_fmt(f, seen)?;
+                }
+                write!(f, "]")
+            }
+
+            Inner::Hash(ref h) => {
+                let mut keys: Vec<_> = h.keys().collect();
+                keys.sort();
+
+                write!(f, "{{")?;
+                for (i, k) in keys.into_iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    dump_str(f, k)?;
+                    write!(f, " => ")?;
+                    h[k].dump_fmt(f, seen)?;
+                }
+                write!(f, "}}")
+            }
+
+            Inner::Object(ref class, ref obj) => {
+                write!(f, "bless( ")?;
+                obj.dump_fmt(f, seen)?;
+                write!(f, ", ")?;
+                dump_str(f, class)?;
+                write!(f, " )")
+            }
+
+            Inner::Regexp(ref pattern, ref flags) => {
+                write!(
+                    f,
+                    "qr/{}/{}",
+                    String::from_utf8_lossy(pattern),
+                    String::from_utf8_lossy(flags)
+                )
+            }
+        }
     }
 }
 
-impl<'buf> parser::Value<'buf> for Value {
+impl Cell for Value {
+    type Inner = Inner;
     type Array = Vec<Value>;
-    type Hash = HashMap<Vec<u8>, Value>;
+    type Hash = Map;
 
-    fn set_undef(&mut self) {
-        self.set(Inner::Undef);
+    fn with_inner<R, F: FnOnce(usize, &Inner) -> R>(&self, f: F) -> Option<R> {
+        self.upgrade().map(|a| f(a.as_ref() as *const _ as usize, &a.read().unwrap()))
     }
 
-    fn set_true(&mut self) {
-        self.set(Inner::Bool(true));
+    fn view(inner: &Inner) -> View<'_, Value> {
+        match *inner {
+            Inner::String(ref s) => View::String(s),
+            Inner::Ref(ref v) => View::Ref(v),
+            Inner::WeakRef(ref v) => View::WeakRef(v),
+            Inner::Array(ref a) => View::Array(a),
+            Inner::Hash(ref h) => View::Hash(h.iter().map(|(k, v)| (&k[..], v)).collect()),
+            Inner::Object(ref class, ref obj) => View::Object(class, obj),
+            _ => View::Scalar,
+        }
     }
 
-    fn set_false(&mut self) {
-        self.set(Inner::Bool(true));
+    fn store(&self, item: Item<Value>) {
+        self.set(match item {
+            Item::Undef => Inner::Undef,
+            Item::Bool(b) => Inner::Bool(b),
+            Item::I64(v) => Inner::I64(v),
+            Item::U64(v) => Inner::U64(v),
+            Item::F32(v) => Inner::F32(v),
+            Item::F64(v) => Inner::F64(v),
+            Item::String(s) => Inner::String(s),
+            Item::Ref(v) => Inner::Ref(v),
+            Item::WeakRef(v) => Inner::WeakRef(v),
+            Item::Array(a) => Inner::Array(a),
+            Item::Hash(h) => Inner::Hash(h),
+            Item::Object(class, v) => Inner::Object(Arc::new(class), v),
+            Item::Regexp(pattern, flags) => Inner::Regexp(pattern, flags),
+        })
     }
 
-    fn set_i64(&mut self, v: i64) {
-        self.set(Inner::I64(v))
+    fn weaken(self) -> Value {
+        self.downgrade()
     }
+}
 
-    fn set_u64(&mut self, v: u64) {
-        self.set(Inner::U64(v))
+/// Class names interned by `ArcBuilder` and `PooledBuilder`.
+#[derive(Debug, Default)]
+struct Classes(HashMap<Vec<u8>, Arc<Vec<u8>>>);
+
+impl Classes {
+    fn set_object(&mut self, value: &mut Value, class: Value, obj: Value) -> Result<()> {
+        let name = class.to_string()?;
+        let class = match self.0.get(&name) {
+            Some(class) => class.clone(),
+            None => {
+                let class = Arc::new(name.clone());
+                self.0.insert(name, class.clone());
+                class
+            }
+        };
+        value.set(Inner::Object(class, obj));
+        Ok(())
     }
 
-    fn set_f32(&mut self, v: f32) {
-        self.set(Inner::F32(v))
+    fn len(&self) -> u64 {
+        self.0.len() as u64
     }
+}
+
+/// Builds `Value`s, interning class names so that objects of the same
+/// class share one allocation of the name.
+#[derive(Debug, Default)]
+pub struct ArcBuilder {
+    classes: Classes,
+}
+
+impl<'buf> parser::Builder<'buf> for ArcBuilder {
+    type Value = Value;
+    type ArrayBuilder = Vec<Value>;
+    type HashBuilder = Map;
 
-    fn set_f64(&mut self, v: f64) {
-        self.set(Inner::F64(v))
+    fn new(&mut self) -> Value {
+        Value::new(Inner::Undef)
     }
 
-    fn set_ref(&mut self, o: Self) {
-        self.set(Inner::Ref(o));
+    fn build_array(&mut self, count: u64) -> Vec<Value> {
+        Vec::with_capacity(count as usize)
     }
 
-    fn set_weak_ref(&mut self, o: Self) {
-        self.set(Inner::WeakRef(o.downgrade()));
+    fn build_hash(&mut self, count: u64) -> Map {
+        Map::with_capacity(count as usize)
     }
 
-    fn set_alias(&mut self, o: Self) {
-        self.alias(o);
+    fn set_object(&mut self, value: &mut Value, class: Value, obj: Value) -> Result<()> {
+        self.classes.set_object(value, class, obj)
     }
 
-    fn set_array(&mut self, a: Self::Array) {
-        self.set(Inner::Array(a));
+    fn set_object_freeze(&mut self, value: &mut Value, class: Value, obj: Value) -> Result<()> {
+        self.classes.set_object(value, class, obj)
     }
 
-    fn set_hash(&mut self, h: Self::Hash) {
-        self.set(Inner::Hash(h));
+    fn class_count(&self) -> u64 {
+        self.classes.len()
     }
+}
 
-    fn set_binary(&mut self, s: &[u8]) {
-        self.set(Inner::String(s.to_owned()));
+impl ArcBuilder {
+    pub fn new() -> ArcBuilder {
+        ArcBuilder::default()
     }
 
-    fn set_string(&mut self, s: &[u8]) {
-        self.set(Inner::String(s.to_owned()));
+    /// A builder that takes arrays and hashes from `pools` before
+    /// allocating new ones. Class names are interned as by `new`.
+    pub fn with_pools<'a>(pools: &'a Pools) -> PooledBuilder<'a> {
+        PooledBuilder {
+            pools: pools,
+            classes: Classes::default(),
+        }
     }
+}
 
-    fn set_object(&mut self, class: Self, value: Self) -> Result<()> {
-        self.set(Inner::Object(class.to_string()?, value));
-        Ok(())
+/// Free lists of arrays and hashes for `PooledBuilder`. Containers come
+/// back through `recycle` once a decoded value is no longer needed.
+///
+/// Each list keeps at most `max_pooled` containers, and containers with
+/// room for more than `max_capacity` entries are freed instead of kept,
+/// so one large document does not pin its memory in the pools.
+#[derive(Debug)]
+pub struct Pools {
+    arrays: RefCell<Vec<Vec<Value>>>,
+    hashes: RefCell<Vec<Map>>,
+    /// Cells waiting to be taken apart by `recycle`, kept between calls.
+    stack: RefCell<Vec<Value>>,
+    max_pooled: usize,
+    max_capacity: usize,
+}
+
+impl Default for Pools {
+    fn default() -> Pools {
+        Pools::with_limits(1024, 4096)
     }
+}
 
-    fn set_object_freeze(&mut self, class: Self, value: Self) -> Result<()> {
-        self.set_object(class, value)
+impl Pools {
+    /// Pools keeping up to 1024 arrays and 1024 hashes of up to 4096
+    /// entries each.
+    pub fn new() -> Pools {
+        Pools::default()
     }
 
-    fn set_regexp(&mut self, pattern: Self, flags: Self) -> Result<()> {
-        self.set(Inner::Regexp(pattern.to_string()?, flags.to_string()?));
-        Ok(())
+    pub fn with_limits(max_pooled: usize, max_capacity: usize) -> Pools {
+        Pools {
+            arrays: RefCell::new(Vec::new()),
+            hashes: RefCell::new(Vec::new()),
+            stack: RefCell::new(Vec::new()),
+            max_pooled: max_pooled,
+            max_capacity: max_capacity,
+        }
+    }
+
+    /// Take `value` apart and keep its arrays and hashes, emptied, for
+    /// the next document. Cells still shared with other values are left
+    /// alone, and so is everything below them.
+    pub fn recycle(&self, value: Value) {
+        let mut stack = self.stack.borrow_mut();
+        stack.push(value);
+
+        while let Some(value) = stack.pop() {
+            let lock = match value {
+                Value::Strong(a) => match Arc::try_unwrap(a) {
+                    Ok(lock) => lock,
+                    Err(_) => continue,
+                },
+                Value::Weak(_) => continue,
+            };
+
+            match lock.into_inner().unwrap() {
+                Inner::Array(mut a) => {
+                    stack.extend(a.drain(..));
+                    let mut arrays = self.arrays.borrow_mut();
+                    if arrays.len() < self.max_pooled && a.capacity() <= self.max_capacity {
+                        arrays.push(a);
+                    }
+                }
+                Inner::Hash(mut h) => {
+                    stack.extend(h.values().cloned());
+                    h.clear();
+                    let mut hashes = self.hashes.borrow_mut();
+                    if hashes.len() < self.max_pooled && h.capacity() <= self.max_capacity {
+                        hashes.push(h);
+                    }
+                }
+                Inner::Ref(v) | Inner::Object(_, v) => stack.push(v),
+                _ => (),
+            }
+        }
     }
 }
 
-pub struct ArcBuilder;
+pub struct PooledBuilder<'a> {
+    pools: &'a Pools,
+    classes: Classes,
+}
 
-impl<'buf> parser::Builder<'buf> for ArcBuilder {
+impl<'a, 'buf> parser::Builder<'buf> for PooledBuilder<'a> {
     type Value = Value;
     type ArrayBuilder = Vec<Value>;
-    type HashBuilder = HashMap<Vec<u8>, Value>;
+    type HashBuilder = Map;
 
     fn new(&mut self) -> Value {
         Value::new(Inner::Undef)
     }
 
     fn build_array(&mut self, count: u64) -> Vec<Value> {
-        Vec::with_capacity(count as usize)
+        let mut a = self.pools.arrays.borrow_mut().pop().unwrap_or_default();
+        a.reserve(count as usize);
+        a
+    }
+
+    fn build_hash(&mut self, count: u64) -> Map {
+        let mut h = self.pools.hashes.borrow_mut().pop().unwrap_or_default();
+        h.reserve(count as usize);
+        h
     }
 
-    fn build_hash(&mut self, count: u64) -> HashMap<Vec<u8>, Value> {
-        HashMap::with_capacity(count as usize)
+    fn set_object(&mut self, value: &mut Value, class: Value, obj: Value) -> Result<()> {
+        self.classes.set_object(value, class, obj)
+    }
+
+    fn set_object_freeze(&mut self, value: &mut Value, class: Value, obj: Value) -> Result<()> {
+        self.classes.set_object(value, class, obj)
+    }
+
+    fn class_count(&self) -> u64 {
+        self.classes.len()
     }
 }
 
@@ -293,7 +596,7 @@ impl<'buf> parser::ArrayBuilder<'buf, Value> for Vec<Value> {
     }
 }
 
-impl<'buf> parser::HashBuilder<'buf, Value> for HashMap<Vec<u8>, Value> {
+impl<'buf> parser::HashBuilder<'buf, Value> for Map {
     fn insert(&mut self, key: &'buf [u8], value: Value) -> Result<()> {
         self.insert(key.to_vec(), value);
         Ok(())
@@ -305,15 +608,13 @@ impl<'buf> parser::HashBuilder<'buf, Value> for HashMap<Vec<u8>, Value> {
 }
 
 pub fn parse(s: &[u8]) -> Result<Value> {
-    parser::parse(s, ArcBuilder)
+    parser::parse(s, ArcBuilder::new())
 }
 
 #[cfg(test)]
 mod test {
-    use std::collections::HashMap;
-
     use arc::parse;
-    use arc::{Value, Inner};
+    use arc::{Value, Inner, Map};
     use arc::Inner::*;
     use arc::Error;
 
@@ -321,13 +622,77 @@ mod test {
         parse(s).unwrap().read()
     }
 
+    fn id(v: &Value) -> usize {
+        match *v {
+            Value::Strong(ref a) => a.as_ref() as *const _ as usize,
+            Value::Weak(_) => panic!("unexpected weak ref"),
+        }
+    }
+
+    fn deref(v: &Value) -> Value {
+        match v.read() {
+            Ref(a) => a,
+            _ => panic!("expecting reference"),
+        }
+    }
+
+    fn items(v: &Value) -> Vec<Value> {
+        match v.read() {
+            Array(items) => items,
+            _ => panic!("expecting array"),
+        }
+    }
+
     #[test]
     fn test_simple() {
         assert_eq!(p(b"\x01"), Inner::U64(1));
+        assert_eq!(p(b"\x1f"), Inner::I64(-1));
+        assert_eq!(p(b"\x10"), Inner::I64(-16));
         assert_eq!(p(b"\x60"), Inner::String(vec![]));
         assert_eq!(p(b"\x61\x00"), Inner::String(vec![0]));
     }
 
+    #[test]
+    fn test_bool() {
+        assert_eq!(p(b"\x3b"), Bool(true));
+        assert_eq!(p(b"\x3a"), Bool(false));
+        assert!(p(b"\x3a") != Inner::U64(0));
+        assert_eq!(
+            p(b"\x3c\x02\x3a"),
+            Array(vec![Value::new(Bool(false)), Value::new(Bool(false))])
+        );
+    }
+
+    #[test]
+    fn test_padding() {
+        use config::Config;
+        use parser::Parser;
+        use arc::ArcBuilder;
+
+        let config = Config::default();
+        let mut h = Map::new();
+        h.insert(b"a".to_vec(), Value::new(U64(1)));
+        let cases: &[(&[u8], Inner)] = &[
+            (b"\x3f\x3f\x01", U64(1)),
+            (
+                b"\x3f\x42\x3f\x01\x3f\x3f\x02",
+                Ref(Value::new(Array(vec![Value::new(U64(1)), Value::new(U64(2))]))),
+            ),
+            (b"\x51\x3f\x61a\x3f\x01", Ref(Value::new(Hash(h)))),
+            // the track bit on a PAD byte does not make it a value
+            (b"\xbf\x28\x3f\x81", Ref(Value::new(U64(1)))),
+        ];
+
+        for &(s, ref expected) in cases {
+            let v = Parser::new(ArcBuilder::new(), &config, s).parse().unwrap();
+            assert_eq!(v.read(), *expected, "{:?}", s);
+            let v = Parser::new(ArcBuilder::new(), &config, s).parse_iterative().unwrap();
+            assert_eq!(v.read(), *expected, "{:?}", s);
+        }
+
+        assert!(parse(b"\x3f\x3f").unwrap_err().is_eof());
+    }
+
     #[test]
     fn test_array() {
         assert_eq!(p(b"\x2b\x01\x00"), Array(vec![Value::from(0u64)]));
@@ -342,19 +707,34 @@ mod test {
     fn test_hash() {
         let r = p(b"\x2a\x02\x63foo\x63bar\x64ook\x00\x64eek\x00");
 
-        let mut m = HashMap::new();
+        let mut m = Map::new();
         m.insert(b"foo".to_vec(), Value::new(Inner::from(&b"bar"[..])));
         m.insert(b"ook\0".to_vec(), Value::new(Inner::from(&b"eek\0"[..])));
 
         assert_eq!(r, Inner::Hash(m));
     }
 
+    #[cfg(feature = "indexmap")]
+    #[test]
+    fn test_hash_order() {
+        let h = match p(b"\x54\x63zzz\x01\x61a\x02\x63mmm\x03\x61b\x04") {
+            Ref(h) => h.read(),
+            _ => panic!("expecting reference"),
+        };
+
+        let keys: Vec<&[u8]> = match h {
+            Hash(ref m) => m.keys().map(|k| &k[..]).collect(),
+            _ => panic!("expecting hash"),
+        };
+        assert_eq!(keys, vec![&b"zzz"[..], b"a", b"mmm", b"b"]);
+    }
+
     #[test]
     fn test_hash_nested() {
         let r = p(b"\x2a\x01\x63foo\x2a\x00");
 
-        let mut m = HashMap::new();
-        m.insert(b"foo".to_vec(), Value::new(Inner::Hash(HashMap::new())));
+        let mut m = Map::new();
+        m.insert(b"foo".to_vec(), Value::new(Inner::Hash(Map::new())));
 
         assert_eq!(r, Inner::Hash(m));
     }
@@ -381,12 +761,73 @@ mod test {
         assert_eq!(a_id, b_id);
     }
 
+    #[test]
+    fn test_refp_tracked_array() {
+        // [ \@a, \@a ] where @a = (1): the ARRAY tag sits at offset 3.
+        let v = p(b"\x42\x28\xab\x01\x01\x29\x03");
+        let items = match v {
+            Ref(ref a) => match a.read() {
+                Array(items) => items,
+                _ => panic!("expecting array"),
+            },
+            _ => panic!("expecting reference"),
+        };
+
+        let (a, b) = match (items[0].read(), items[1].read()) {
+            (Ref(a), Ref(b)) => (a, b),
+            _ => panic!("expecting references"),
+        };
+
+        assert_eq!(a.read(), Array(vec![Value::new(U64(1))]));
+        assert_eq!(id(&a), id(&b));
+
+        assert!(parse(b"\x41\x2f\x00").unwrap_err().is_invalid_copy());
+        assert!(parse(b"\x41\x2f\x05").unwrap_err().is_invalid_copy());
+    }
+
+    #[test]
+    fn test_weak() {
+        let strong = Value::new(U64(1));
+        let weak = strong.clone().downgrade();
+
+        assert!(!strong.is_weak());
+        assert!(weak.is_weak());
+        assert!(weak.is_alive());
+
+        let upgraded = weak.try_upgrade().unwrap();
+        assert!(!upgraded.is_weak());
+        assert_eq!(id(&upgraded), id(&strong));
+
+        drop(strong);
+        assert!(weak.is_alive());
+        drop(upgraded);
+        assert!(!weak.is_alive());
+        assert!(weak.try_upgrade().is_none());
+    }
+
     #[test]
     fn test_mutual_ref() {
-        let a = parse(b"\x28\xab\x01\x28\x2b\x01\x29\x02");
-        let b = parse(b"\xc1\x41\x2e\x01");
-        println!("{:?}", a);
-        println!("{:?}", b);
+        // \@a where @a = (\[ \@a ]): the REFP points back at the tracked
+        // array, so the innermost reference shares its target with the root.
+        let a = parse(b"\x28\xab\x01\x28\x2b\x01\x29\x02").unwrap();
+        let outer = deref(&a);
+        let inner = deref(&items(&outer)[0]);
+        let back = deref(&items(&inner)[0]);
+        assert_eq!(id(&back), id(&outer));
+        assert!(id(&inner) != id(&outer));
+
+        // $r = [ \[ $r ] ]: the ALIAS makes the inner slot the root cell
+        // itself rather than a new reference to the same array.
+        let b = parse(b"\xc1\x41\x2e\x01").unwrap();
+        let inner = deref(&items(&deref(&b))[0]);
+        let slot = &items(&inner)[0];
+        assert_eq!(id(slot), id(&b));
+
+        // the same alias without the track bit on its target
+        match parse(b"\x41\x2e\x01").unwrap_err() {
+            Error::InvalidRef(1) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
     }
 
     #[test]
@@ -403,25 +844,799 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_unknown_tag() {
+        use sereal_common::constants::*;
+
+        let err = parse(&[RESERVED_0]).unwrap_err();
+        assert!(err.is_unknown_tag());
+        assert_eq!(err.as_unknown_tag(), Some(RESERVED_0));
+
+        let tags = [
+            LONG_DOUBLE,
+            RESERVED_0,
+            RESERVED_1,
+            RESERVED_2,
+            RESERVED_3,
+            RESERVED_4,
+            PACKET_START,
+        ];
+        for &tag in &tags {
+            assert_eq!(parse(&[tag]).unwrap_err().as_unknown_tag(), Some(tag));
+        }
+    }
+
+    #[test]
+    fn test_allow_unknown_tags() {
+        use config::Config;
+        use parser::Parser;
+        use arc::ArcBuilder;
+
+        let config = Config::default().with_allow_unknown_tags(true);
+        let mut h = Map::new();
+        h.insert(b"a".to_vec(), Value::new(U64(1)));
+        let cases: &[(&[u8], Inner)] = &[
+            (b"\x34\x01", U64(1)),
+            (b"\x38\xb5\x3f\x01", U64(1)),
+            (
+                b"\x42\x36\x01\x37\x02",
+                Ref(Value::new(Array(vec![Value::new(U64(1)), Value::new(U64(2))]))),
+            ),
+            (b"\x51\x35\x61a\x01", Ref(Value::new(Hash(h)))),
+        ];
+
+        for &(s, ref expected) in cases {
+            let v = Parser::new(ArcBuilder::new(), &config, s).parse().unwrap();
+            assert_eq!(v.read(), *expected);
+            let v = Parser::new(ArcBuilder::new(), &config, s).parse_iterative().unwrap();
+            assert_eq!(v.read(), *expected);
+            assert!(Parser::new(ArcBuilder::new(), &Config::default(), s).parse().is_err());
+        }
+
+        let s = b"\x42\x36\x01\xb8\x34\x02";
+        let mut p = Parser::new(ArcBuilder::new(), &config, s);
+        p.parse().unwrap();
+        assert_eq!(p.skipped_tags(), b"\x36\x38\x34");
+        let mut p = Parser::new(ArcBuilder::new(), &config, b"\x01");
+        p.parse_iterative().unwrap();
+        assert!(p.skipped_tags().is_empty());
+
+        for s in &[&b"\x24\x01"[..], b"\x3d\x01", b"\x34"] {
+            assert!(Parser::new(ArcBuilder::new(), &config, s).parse().is_err());
+            assert!(Parser::new(ArcBuilder::new(), &config, s).parse_iterative().is_err());
+        }
+    }
+
+    #[test]
+    fn test_max_object_classes() {
+        use config::Config;
+        use parser::{Error, Parser};
+        use arc::ArcBuilder;
+
+        let config = Config::default().with_max_object_classes(2);
+
+        // Three objects, each declaring its own class.
+        let s = b"\x2b\x03\x2c\x61a\x01\x2c\x61b\x01\x2c\x61c\x01";
+        for &iterative in &[false, true] {
+            let mut p = Parser::new(ArcBuilder::new(), &config, s);
+            let res = if iterative { p.parse_iterative() } else { p.parse() };
+            match res {
+                Err(Error::TooManyClasses { limit: 2 }) => (),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+
+        // Three objects sharing one class through `OBJECTV`.
+        let s = b"\x2b\x03\x2c\x61a\x01\x2d\x04\x01\x2d\x04\x01";
+        let config = config.with_max_object_classes(1);
+        assert!(Parser::new(ArcBuilder::new(), &config, s).parse().is_ok());
+        assert!(Parser::new(ArcBuilder::new(), &config, s).parse_iterative().is_ok());
+
+        // Three objects repeating one class name, inline and through `COPY`.
+        let s = b"\x2b\x03\x2c\x61a\x01\x2c\x61a\x01\x2c\x2f\x04\x01";
+        assert!(Parser::new(ArcBuilder::new(), &config, s).parse().is_ok());
+        assert!(Parser::new(ArcBuilder::new(), &config, s).parse_iterative().is_ok());
+    }
+
+    #[test]
+    fn test_array_stream() {
+        use config::Config;
+        use parser::ArrayStream;
+        use arc::ArcBuilder;
+
+        let config = Config::default();
+
+        // [ \@a, "foo", \@a ] where @a = (1)
+        let s = b"\x2b\x03\x28\xab\x01\x01\x63foo\x29\x04";
+        let mut stream = ArrayStream::new(ArcBuilder::new(), &config, s).unwrap();
+        assert_eq!(stream.remaining(), 3);
+
+        let first = stream.next().unwrap().unwrap();
+        assert_eq!(items(&deref(&first)), vec![Value::new(U64(1))]);
+        assert_eq!(stream.remaining(), 2);
+        assert_eq!(stream.next().unwrap().unwrap().read(), String(b"foo".to_vec()));
+        let third = stream.next().unwrap().unwrap();
+        assert_eq!(id(&deref(&first)), id(&deref(&third)));
+        assert!(stream.next().is_none());
+
+        let forms: &[&[u8]] = &[
+            b"\x43\x01\x02\x03",
+            b"\x28\x2b\x03\x01\x02\x03",
+            b"\x3f\xab\x03\x01\x02\x03",
+        ];
+        for s in forms {
+            let stream = ArrayStream::new(ArcBuilder::new(), &config, s).unwrap();
+            let v: Vec<Value> = stream.map(|v| v.unwrap()).collect();
+            assert_eq!(v, vec![Value::new(U64(1)), Value::new(U64(2)), Value::new(U64(3))]);
+        }
+
+        let mut stream =
+            ArrayStream::new(ArcBuilder::new(), &config, b"\x2b\x03\x01\x29\x01\x02").unwrap();
+        assert!(stream.next().unwrap().is_ok());
+        assert!(stream.next().unwrap().is_err());
+        assert!(stream.next().is_none());
+
+        for s in &[&b"\x01"[..], b"\x28\x01", b"\x51\x61a\x01", b""] {
+            assert!(ArrayStream::new(ArcBuilder::new(), &config, s).is_err());
+        }
+
+        let strict = Config::default().with_max_array_size(2);
+        assert!(ArrayStream::new(ArcBuilder::new(), &strict, b"\x43\x01\x02\x03").is_err());
+    }
+
+    #[test]
+    fn test_many() {
+        assert_eq!(
+            p(b"\x3c\x03\x20\x01\x80\x01\x00"),
+            Array(vec![
+                Value::new(U64(1)),
+                Value::new(U64(128)),
+                Value::new(U64(0)),
+            ])
+        );
+        assert_eq!(
+            p(b"\x3c\x02\x1f"),
+            Array(vec![Value::new(I64(-1)), Value::new(I64(-1))])
+        );
+        assert_eq!(
+            p(b"\x3c\x02\x27\x01a\x00"),
+            Array(vec![
+                Value::new(String(b"a".to_vec())),
+                Value::new(String(vec![])),
+            ])
+        );
+        assert!(parse(b"\x3c\x02\x20\x01").unwrap_err().is_eof());
+
+        for &tag in &[0x2a, 0x2b, 0x28, 0x41, 0x51] {
+            match parse(&[0x3c, 0x01, tag, 0x00]).unwrap_err() {
+                Error::InvalidType => (),
+                e => panic!("unexpected error {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_extend() {
+        let err = parse(b"\x3e\x00").unwrap_err();
+        assert!(!err.is_unknown_tag());
+        assert_eq!(err.as_unsupported_extension(), Some(0));
+        assert!(parse(b"\x3e").unwrap_err().is_eof());
+    }
+
+    #[test]
+    fn test_extend_handlers() {
+        use config::Config;
+        use parser::{half_float, Extension, Parser};
+        use reader::{self, Reader};
+        use arc::ArcBuilder;
+
+        fn varint(r: &mut Reader) -> reader::Result<Extension> {
+            Ok(Extension::U64(r.read_varint()?))
+        }
+
+        let config = Config::default()
+            .with_extension(0x01, half_float)
+            .with_extension(0x02, varint);
+        let p = |s: &[u8]| Parser::new(ArcBuilder::new(), &config, s).parse();
+
+        let cases: &[(&[u8], f32)] = &[
+            (b"\x3e\x01\x00\x3c", 1.0),
+            (b"\x3e\x01\x00\xc0", -2.0),
+            (b"\x3e\x01\x00\x35", 0.3125),
+            (b"\x3e\x01\xff\x7b", 65504.0),
+            (b"\x3e\x01\x01\x00", 1.0 / (1 << 24) as f32),
+            (b"\x3e\x01\x00\x80", -0.0),
+            (b"\x3e\x01\x00\x7c", f32::INFINITY),
+        ];
+        for &(s, expected) in cases {
+            match p(s).unwrap().read() {
+                F32(v) => assert_eq!(v.to_bits(), expected.to_bits(), "{:?}", s),
+                other => panic!("expecting float, got {:?}", other),
+            }
+        }
+        match p(b"\x3e\x01\x01\x7e").unwrap().read() {
+            F32(v) => assert!(v.is_nan()),
+            other => panic!("expecting float, got {:?}", other),
+        }
+
+        let items = vec![Value::new(U64(128)), Value::new(U64(1))];
+        assert_eq!(p(b"\x42\x3e\x02\x80\x01\x01").unwrap().read(), Ref(Value::new(Array(items))));
+        assert!(p(b"\x3e\x01\x00").unwrap_err().is_eof());
+        assert_eq!(p(b"\x3e\x03").unwrap_err().as_unsupported_extension(), Some(3));
+        assert!(Parser::new(ArcBuilder::new(), &config, b"\x3e\x01\x00\x3c")
+            .parse_iterative()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_strict_utf8() {
+        use config::Config;
+        use parser::Parser;
+        use arc::ArcBuilder;
+
+        let s = b"\x42\x27\x01a\x27\x02\xc3\x28";
+
+        let config = Config::default();
+        let v = Parser::new(ArcBuilder::new(), &config, s).parse().unwrap();
+        assert_eq!(
+            v.read(),
+            Ref(Value::new(Array(vec![
+                Value::new(String(b"a".to_vec())),
+                Value::new(String(b"\xc3\x28".to_vec())),
+            ])))
+        );
+
+        let config = Config::default().with_strict_utf8(true);
+        match Parser::new(ArcBuilder::new(), &config, s).parse().unwrap_err() {
+            Error::InvalidUtf8 { pos } => assert_eq!(pos, 6),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_bytes_consumed() {
+        use config::Config;
+        use parser::Parser;
+        use reader::Reader;
+        use arc::ArcBuilder;
+
+        let config = Config::default();
+        // [1, 2], then "foo", then a truncated array.
+        let s = b"\x42\x01\x02\x63foo\x43\x01";
+
+        let mut p = Parser::new(ArcBuilder::new(), &config, s);
+        assert_eq!(p.bytes_consumed(), 0);
+        p.parse().unwrap();
+        assert_eq!(p.bytes_consumed(), 3);
+
+        let rest = &s[p.bytes_consumed()..];
+        let mut p = Parser::new(ArcBuilder::new(), &config, rest);
+        assert_eq!(p.parse().unwrap().read(), String(b"foo".to_vec()));
+        assert_eq!(p.bytes_consumed(), 4);
+
+        let rest = &rest[p.bytes_consumed()..];
+        assert!(Parser::new(ArcBuilder::new(), &config, rest).parse().unwrap_err().is_eof());
+
+        let mut r = Reader::new(b"\x80");
+        match r.read_varint() {
+            Err(::ReaderError::UnexpectedEof) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_error_position() {
+        use config::Config;
+        use parser::{Parser, PositionedError};
+        use arc::ArcBuilder;
+
+        let config = Config::default();
+        // ARRAYREF_3 with only two elements present.
+        let s = b"\x43\x01\x02";
+        match Parser::new(ArcBuilder::new(), &config, s).parse_positioned().unwrap_err() {
+            PositionedError { error: Error::UnexpectedEof, pos } => assert_eq!(pos, 3),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // Unknown tag in the second element.
+        let s = b"\x43\x01\x35\x02";
+        let err = Parser::new(ArcBuilder::new(), &config, s).parse_positioned().unwrap_err();
+        assert_eq!(err.pos, 3);
+        assert_eq!(format!("{}", err), "unknown tag 0x35 at offset 3");
+
+        assert!(Parser::new(ArcBuilder::new(), &config, b"\x42\x01\x02")
+            .parse_positioned()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_utf8_keys() {
+        let mut h = Map::new();
+        h.insert("é".as_bytes().to_vec(), Value::new(U64(1)));
+        assert_eq!(p(b"\x51\x27\x02\xc3\xa9\x01"), Ref(Value::new(Hash(h))));
+
+        // binary keys are not checked
+        let mut h = Map::new();
+        h.insert(b"\xc3\x28".to_vec(), Value::new(U64(1)));
+        assert_eq!(p(b"\x51\x62\xc3\x28\x01"), Ref(Value::new(Hash(h))));
+
+        // keys declared as utf-8 are, even without strict_utf8
+        match parse(b"\x51\x27\x02\xc3\x28\x01").unwrap_err() {
+            Error::InvalidUtf8 { pos } => assert_eq!(pos, 3),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_strict_config() {
+        use config::Config;
+        use parser::Parser;
+        use arc::ArcBuilder;
+
+        let mut s = b"\x2b\xa0\x8d\x06".to_vec();
+        s.extend(vec![0x01; 100_000]);
+
+        let config = Config::default();
+        assert!(Parser::new(ArcBuilder::new(), &config, &s).parse().is_ok());
+
+        let config = Config::strict();
+        match Parser::new(ArcBuilder::new(), &config, &s).parse().unwrap_err() {
+            Error::ArrayTooLarge { count, limit } => {
+                assert_eq!(count, 100_000);
+                assert_eq!(limit, 10_000);
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let config = Config::strict().with_max_array_size(100_000);
+        assert!(Parser::new(ArcBuilder::new(), &config, &s).parse().is_ok());
+    }
+
+    #[test]
+    fn test_untrusted_config() {
+        use config::Config;
+        use parser::Parser;
+        use arc::ArcBuilder;
+
+        let config = Config::for_untrusted();
+        let p = |s: &[u8]| Parser::new(ArcBuilder::new(), &config, s).parse();
+
+        // 200 nested references.
+        let mut deep = vec![0x28; 200];
+        deep.push(0x01);
+        assert!(parse(&deep).is_ok());
+        match p(&deep).unwrap_err() {
+            Error::DepthLimitExceeded { limit: 128 } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // An array claiming 2^32 elements, with one present.
+        let huge = b"\x2b\x80\x80\x80\x80\x10\x01";
+        match p(huge).unwrap_err() {
+            Error::ArrayTooLarge { count: 0x1_0000_0000, limit: 10_000 } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        match p(b"\x2a\x02\x61a\x01\x61a\x02").unwrap_err() {
+            Error::DuplicateKey(ref k) if k == b"a" => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+        match p(b"\x01\x02").unwrap_err() {
+            Error::TrailingData { pos: 1 } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert!(p(b"\x34\x01").unwrap_err().is_unknown_tag());
+        assert!(p(b"\x27\x01\xff").is_err());
+        assert!(p(b"\x2a\x01\x61a\x01").is_ok());
+    }
+
+    #[test]
+    fn test_limits() {
+        use config::Config;
+        use parser::Parser;
+        use arc::ArcBuilder;
+
+        let config = Config::default().with_max_string_len(2);
+        assert!(Parser::new(ArcBuilder::new(), &config, b"\x26\x02ab").parse().is_ok());
+        match Parser::new(ArcBuilder::new(), &config, b"\x27\x03abc").parse().unwrap_err() {
+            Error::StringTooLarge { len, limit } => assert_eq!((len, limit), (3, 2)),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // keys follow max_string_len unless limited separately
+        let s = b"\x51\x26\x03abc\x01";
+        assert!(Parser::new(ArcBuilder::new(), &config, s).parse().is_err());
+        let config = Config::default().with_max_string_len(2).with_max_key_len(3);
+        assert!(Parser::new(ArcBuilder::new(), &config, s).parse().is_ok());
+
+        // { abcd => 1 } with the key as SHORT_BINARY, BINARY and STR_UTF8
+        let config = Config::default().with_max_key_len(3);
+        let inputs: &[&[u8]] = &[
+            b"\x51\x64abcd\x01",
+            b"\x51\x26\x04abcd\x01",
+            b"\x51\x27\x04abcd\x01",
+        ];
+        for s in inputs {
+            for res in &[
+                Parser::new(ArcBuilder::new(), &config, s).parse(),
+                Parser::new(ArcBuilder::new(), &config, s).parse_iterative(),
+            ] {
+                match *res {
+                    Err(Error::StringTooLarge { len: 4, limit: 3 }) => (),
+                    ref r => panic!("unexpected result {:?}", r),
+                }
+            }
+        }
+
+        // { a => "abcd" }: values are not affected
+        let s = b"\x51\x61a\x26\x04abcd";
+        assert!(Parser::new(ArcBuilder::new(), &config, s).parse().is_ok());
+
+        let s = b"\x41\x41\x01";
+        let config = Config::default().with_max_depth(3);
+        assert!(Parser::new(ArcBuilder::new(), &config, s).parse().is_ok());
+        assert!(Parser::new(ArcBuilder::new(), &config, s).parse_iterative().is_ok());
+
+        let config = Config::default().with_max_depth(2);
+        let results = [
+            Parser::new(ArcBuilder::new(), &config, s).parse(),
+            Parser::new(ArcBuilder::new(), &config, s).parse_iterative(),
+        ];
+        for res in &results {
+            match *res {
+                Err(Error::DepthLimitExceeded { limit }) => assert_eq!(limit, 2),
+                ref r => panic!("unexpected result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_pools() {
+        use config::Config;
+        use parser::Parser;
+        use arc::{ArcBuilder, Pools};
+
+        let config = Config::default();
+        let pools = Pools::new();
+
+        // \[ \[1], { a => \[2] }, \[3] ], with [3] kept alive by the test
+        let s = b"\x43\x41\x01\x51\x61a\x41\x02\x41\x03";
+        let expected = parse(s).unwrap();
+
+        let v = Parser::new(ArcBuilder::with_pools(&pools), &config, s).parse().unwrap();
+        assert_eq!(v, expected);
+
+        let shared = deref(&items(&deref(&v))[2]);
+        pools.recycle(v);
+        assert_eq!(pools.arrays.borrow().len(), 3);
+        assert_eq!(pools.hashes.borrow().len(), 1);
+        assert!(pools.arrays.borrow().iter().all(|a| a.is_empty()));
+        assert_eq!(items(&shared), vec![Value::new(U64(3))]);
+
+        let v = Parser::new(ArcBuilder::with_pools(&pools), &config, s).parse().unwrap();
+        assert_eq!(v, expected);
+        assert_eq!(pools.arrays.borrow().len(), 0);
+        assert_eq!(pools.hashes.borrow().len(), 0);
+
+        // Only two of the three arrays are kept, and a large one is freed.
+        let pools = Pools::with_limits(2, 4);
+        pools.recycle(parse(s).unwrap());
+        assert_eq!(pools.arrays.borrow().len(), 2);
+        pools.arrays.borrow_mut().clear();
+        pools.recycle(Value::new(Array(Vec::with_capacity(5))));
+        assert_eq!(pools.arrays.borrow().len(), 0);
+
+        // [ OBJECT foo 1, OBJECT COPY(foo) 2 ] share the class name.
+        let s = b"\x42\x2c\x63foo\x01\x2c\x2f\x03\x02";
+        let v = Parser::new(ArcBuilder::with_pools(&pools), &config, s).parse().unwrap();
+        match (items(&deref(&v))[0].read(), items(&deref(&v))[1].read()) {
+            (Object(a, _), Object(b, _)) => assert!(::std::sync::Arc::ptr_eq(&a, &b)),
+            other => panic!("unexpected values {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_total_bytes() {
+        use config::Config;
+        use parser::Parser;
+        use arc::ArcBuilder;
+
+        // 100 eight-byte strings: 16 for the array, 16 for each of its slots
+        // and elements, and 8 for the contents of each string
+        let mut s = b"\x2b\x64".to_vec();
+        for _ in 0..100 {
+            s.extend_from_slice(b"\x68abcdefgh");
+        }
+
+        let config = Config::default().with_max_total_bytes(4016);
+        assert!(Parser::new(ArcBuilder::new(), &config, &s).parse().is_ok());
+        assert!(Parser::new(ArcBuilder::new(), &config, &s).parse_iterative().is_ok());
+
+        let config = Config::default().with_max_total_bytes(4000);
+        for res in &[
+            Parser::new(ArcBuilder::new(), &config, &s).parse(),
+            Parser::new(ArcBuilder::new(), &config, &s).parse_iterative(),
+        ] {
+            match *res {
+                Err(Error::TotalSizeExceeded { limit: 4000 }) => (),
+                ref r => panic!("unexpected result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_tracked_refs() {
+        use config::Config;
+        use parser::Parser;
+        use arc::ArcBuilder;
+
+        // [ 1, 1, 1, ... ], every element with the track bit set
+        let mut s = b"\x2b\x90\x4e".to_vec();
+        s.extend(vec![0x81; 10_000]);
+
+        let config = Config::default().with_max_tracked_refs(10_000);
+        assert!(Parser::new(ArcBuilder::new(), &config, &s).parse().is_ok());
+
+        let config = Config::default().with_max_tracked_refs(9_999);
+        for res in &[
+            Parser::new(ArcBuilder::new(), &config, &s).parse(),
+            Parser::new(ArcBuilder::new(), &config, &s).parse_iterative(),
+        ] {
+            match *res {
+                Err(Error::TooManyRefs { limit }) => assert_eq!(limit, 9_999),
+                ref r => panic!("unexpected result {:?}", r),
+            }
+        }
+
+        // Copies of a tracked value are not tracked a second time.
+        let s = b"\x42\x81\x2f\x02";
+        let config = Config::default().with_max_tracked_refs(1);
+        assert!(Parser::new(ArcBuilder::new(), &config, s).parse().is_ok());
+    }
+
+    #[test]
+    fn test_parse_exact() {
+        use config::Config;
+        use parser::Parser;
+        use arc::ArcBuilder;
+
+        let config = Config::default();
+        let s = b"\x42\x01\x02\x00\x63foo";
+
+        assert!(Parser::new(ArcBuilder::new(), &config, s).parse().is_ok());
+        match Parser::new(ArcBuilder::new(), &config, s).parse_exact().unwrap_err() {
+            Error::TrailingData { pos: 3 } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        assert!(Parser::new(ArcBuilder::new(), &config, &s[..3]).parse_exact().is_ok());
+
+        let config = Config::default().with_require_eof(true);
+        for res in &[
+            Parser::new(ArcBuilder::new(), &config, s).parse(),
+            Parser::new(ArcBuilder::new(), &config, s).parse_iterative(),
+        ] {
+            match *res {
+                Err(Error::TrailingData { pos: 3 }) => (),
+                ref r => panic!("unexpected result {:?}", r),
+            }
+        }
+
+        assert!(Parser::new(ArcBuilder::new(), &config, &s[..3]).parse().is_ok());
+        assert!(Parser::new(ArcBuilder::new(), &config, &s[..3]).parse_iterative().is_ok());
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        use config::Config;
+        use parser::Parser;
+        use arc::ArcBuilder;
+
+        // { foo => 1, foo => 2 }, the second key as a COPY of the first
+        let inputs: &[&[u8]] = &[b"\x52\x63foo\x01\x63foo\x02", b"\x52\x63foo\x01\x2f\x02\x02"];
+
+        for s in inputs {
+            let config = Config::default();
+            let mut h = Map::new();
+            h.insert(b"foo".to_vec(), Value::new(U64(2)));
+            assert_eq!(
+                Parser::new(ArcBuilder::new(), &config, s).parse().unwrap().read(),
+                Ref(Value::new(Hash(h)))
+            );
+
+            let config = Config::default().with_reject_duplicate_keys(true);
+            for res in &[
+                Parser::new(ArcBuilder::new(), &config, s).parse(),
+                Parser::new(ArcBuilder::new(), &config, s).parse_iterative(),
+            ] {
+                match *res {
+                    Err(Error::DuplicateKey(ref key)) => assert_eq!(key, b"foo"),
+                    ref r => panic!("unexpected result {:?}", r),
+                }
+            }
+        }
+
+        let config = Config::default().with_reject_duplicate_keys(true);
+        let s = b"\x52\x63foo\x51\x63foo\x01\x63bar\x02";
+        assert!(Parser::new(ArcBuilder::new(), &config, s).parse().is_ok());
+        assert!(Parser::new(ArcBuilder::new(), &config, s).parse_iterative().is_ok());
+    }
+
     #[test]
     fn test_objects() {
         let parsed = p(b"\x42\x2c\x63foo\x28\x2a\x00\x2d\x03\x28\x2a\x00");
+        use std::sync::Arc;
         use arc::Inner::{Ref, Object, Array, Hash};
 
         let value = Ref(Value::new(Array(vec![
             Value::new(Object(
-                b"foo".to_vec(),
-                Value::new(Ref(Value::new(Hash(HashMap::new())))),
+                Arc::new(b"foo".to_vec()),
+                Value::new(Ref(Value::new(Hash(Map::new())))),
             )),
             Value::new(Object(
-                b"foo".to_vec(),
-                Value::new(Ref(Value::new(Hash(HashMap::new())))),
+                Arc::new(b"foo".to_vec()),
+                Value::new(Ref(Value::new(Hash(Map::new())))),
             )),
         ])));
 
         assert_eq!(parsed, value);
     }
 
+    #[test]
+    fn test_shared_class() {
+        use std::sync::Arc;
+
+        fn class(v: &Value) -> Arc<Vec<u8>> {
+            match v.read() {
+                Object(class, _) => class,
+                _ => panic!("expecting object"),
+            }
+        }
+
+        // [ OBJECT foo {}, OBJECTV 3 {}, OBJECT_FREEZE foo {}, OBJECT bar {} ]
+        let v = parse(b"\x44\x2c\x63foo\x50\x2d\x03\x50\x32\x63foo\x50\x2c\x63bar\x50")
+            .unwrap();
+        let objects = items(&deref(&v));
+
+        assert!(Arc::ptr_eq(&class(&objects[0]), &class(&objects[1])));
+        assert!(Arc::ptr_eq(&class(&objects[0]), &class(&objects[2])));
+        assert!(!Arc::ptr_eq(&class(&objects[0]), &class(&objects[3])));
+        assert_eq!(*class(&objects[2]), b"foo".to_vec());
+        assert_eq!(*class(&objects[3]), b"bar".to_vec());
+
+        match parse(b"\x2c\x01\x50").unwrap_err() {
+            Error::InvalidType => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_walk() {
+        use arc::Visitor;
+
+        #[derive(Default)]
+        struct Counter {
+            nodes: usize,
+            seen: usize,
+            depth: usize,
+            max_depth: usize,
+            classes: Vec<Vec<u8>>,
+        }
+
+        impl Counter {
+            fn enter(&mut self) {
+                self.nodes += 1;
+                self.depth += 1;
+                self.max_depth = ::std::cmp::max(self.depth, self.max_depth);
+            }
+        }
+
+        impl Visitor for Counter {
+            fn visit_scalar(&mut self, _: &Inner) {
+                self.nodes += 1;
+            }
+            fn visit_seen(&mut self) {
+                self.seen += 1;
+            }
+            fn enter_ref(&mut self, _: bool) {
+                self.enter();
+            }
+            fn leave_ref(&mut self, _: bool) {
+                self.depth -= 1;
+            }
+            fn enter_array(&mut self, _: usize) {
+                self.enter();
+            }
+            fn leave_array(&mut self) {
+                self.depth -= 1;
+            }
+            fn enter_hash(&mut self, _: usize) {
+                self.enter();
+            }
+            fn leave_hash(&mut self) {
+                self.depth -= 1;
+            }
+            fn enter_object(&mut self, class: &[u8]) {
+                self.classes.push(class.to_vec());
+                self.enter();
+            }
+            fn leave_object(&mut self) {
+                self.depth -= 1;
+            }
+        }
+
+        // [ bless({}, 'foo'), bless({}, 'foo') ]
+        let v = parse(b"\x42\x2c\x63foo\x28\x2a\x00\x2d\x03\x28\x2a\x00").unwrap();
+        let mut c = Counter::default();
+        v.walk(&mut c);
+
+        assert_eq!(c.nodes, 8);
+        assert_eq!(c.seen, 0);
+        assert_eq!(c.depth, 0);
+        assert_eq!(c.max_depth, 5);
+        assert_eq!(c.classes, vec![b"foo".to_vec(), b"foo".to_vec()]);
+
+        // $a = \$a
+        let v = parse(b"\xa9\x01").unwrap();
+        let mut c = Counter::default();
+        v.walk(&mut c);
+
+        assert_eq!((c.nodes, c.seen), (1, 1));
+    }
+
+    #[test]
+    fn test_display() {
+        let v = parse(b"\x42\x2c\x63foo\x28\x2a\x02\x61b\x41\x61x\x61a\x25\x2d\x03\x28\x05").unwrap();
+        assert_eq!(
+            format!("{}", v),
+            "[bless( {'a' => undef,'b' => ['x']}, 'foo' ),bless( \\5, 'foo' )]"
+        );
+
+        assert_eq!(format!("{}", parse(b"\x64it's").unwrap()), "'it\\'s'");
+        assert_eq!(format!("{}", parse(b"\xa9\x01").unwrap()), "\\<loop>");
+    }
+
+    #[test]
+    fn test_iterative() {
+        use config::Config;
+        use parser::Parser;
+        use arc::ArcBuilder;
+
+        let config = Config::default();
+        let inputs: &[&[u8]] = &[
+            b"\x01",
+            b"\x2a\x02\x63foo\x63bar\x64ook\x00\x64eek\x00",
+            b"\x2a\x01\x63foo\x2a\x00",
+            b"\x42\x2c\x63foo\x28\x2a\x00\x2d\x03\x28\x2a\x00",
+            b"\x43\x41\x01\x2f\x02\x2f\x02",
+            b"\x43\x61a\x51\x2f\x02\x01\x2f\x04",
+            b"\x42\x28\xab\x01\x01\x29\x03",
+            b"\x42\x31\x63a.b\x61i\x30\x28\x01",
+            b"\x3c\x03\x20\x01\x80\x01\x00",
+        ];
+
+        for s in inputs {
+            let expected = Parser::new(ArcBuilder::new(), &config, s).parse().unwrap();
+            let actual = Parser::new(ArcBuilder::new(), &config, s).parse_iterative().unwrap();
+            assert_eq!(actual, expected);
+        }
+
+        let errors: &[&[u8]] = &[
+            b"\x2b\x02\x00",
+            b"\x2a\x01\x63foo\x2a\x01\x63bar",
+            b"\x2a\x01\x00\x63foo",
+            b"\x42\x01\x2f\x01",
+            b"\x43\x61b\x2f\x02\x51\x2f\x04\x01",
+            b"\x34",
+        ];
+
+        for s in errors {
+            let expected = Parser::new(ArcBuilder::new(), &config, s).parse().unwrap_err();
+            let actual = Parser::new(ArcBuilder::new(), &config, s).parse_iterative().unwrap_err();
+            assert_eq!(format!("{:?}", actual), format!("{:?}", expected));
+        }
+    }
+
     #[test]
     fn test_copy() {
         let parsed = parse(b"\x2f\x01");
@@ -434,10 +1649,13 @@ mod test {
         assert!(parsed.unwrap_err().is_invalid_copy());
     }
 
+    // [[1], COPY of [1], COPY of [1]]
+    const COPY_COMPLEX_VALUE: &[u8] = b"\x43\x41\x01\x2f\x02\x2f\x02";
+
     #[test]
     fn test_copy_complex_value() {
         assert_eq!(
-            p(b"\x43\x41\x01\x2f\x02\x2f\x02"),
+            p(COPY_COMPLEX_VALUE),
             Ref(Value::new(Array(vec![
                 Value::new(
                     Ref(Value::new(Array(vec![Value::new(U64(1))])))
@@ -452,9 +1670,66 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_track_hooks() {
+        use parser::{self, Builder};
+
+        struct Counting<'a> {
+            tracked: &'a mut Vec<usize>,
+            referenced: &'a mut Vec<usize>,
+        }
+
+        impl<'a, 'buf> Builder<'buf> for Counting<'a> {
+            type Value = Value;
+            type ArrayBuilder = Vec<Value>;
+            type HashBuilder = Map;
+
+            fn new(&mut self) -> Value {
+                Value::new(Inner::Undef)
+            }
+
+            fn build_array(&mut self, count: u64) -> Vec<Value> {
+                Vec::with_capacity(count as usize)
+            }
+
+            fn build_hash(&mut self, count: u64) -> Map {
+                Map::with_capacity(count as usize)
+            }
+
+            fn on_track(&mut self, pos: usize) {
+                self.tracked.push(pos);
+            }
+
+            fn on_reference(&mut self, target: usize) {
+                self.referenced.push(target);
+            }
+        }
+
+        fn count(s: &[u8]) -> (Vec<usize>, Vec<usize>) {
+            let mut tracked = Vec::new();
+            let mut referenced = Vec::new();
+            parser::parse(
+                s,
+                Counting {
+                    tracked: &mut tracked,
+                    referenced: &mut referenced,
+                },
+            ).unwrap();
+            (tracked, referenced)
+        }
+
+        // Copies are not references.
+        assert_eq!(count(COPY_COMPLEX_VALUE), (vec![], vec![]));
+        assert_eq!(count(b"\x42\xc1\x01\x29\x02"), (vec![2], vec![2]));
+        assert_eq!(
+            count(b"\x43\x81\x2e\x02\x2e\x02"),
+            (vec![2], vec![2, 2])
+        );
+    }
+
     #[test]
     fn test_copy_hash_key() {
-        let mut map = HashMap::new();
+        let mut map = Map::new();
         map.insert(vec![b'a'], Value::new(U64(1)));
 
         assert_eq!(
@@ -467,6 +1742,55 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_refp_hash_key() {
+        use config::Config;
+        use parser::Parser;
+        use arc::ArcBuilder;
+
+        let mut map = Map::new();
+        map.insert(vec![b'a'], Value::new(U64(1)));
+        let hash = Value::new(Ref(Value::new(Hash(map.clone()))));
+
+        // A key referring to a key of an earlier hash.
+        assert_eq!(
+            p(b"\x42\x51\xe1a\x01\x51\x29\x03\x01"),
+            Ref(Value::new(Array(vec![hash.clone(), hash.clone()])))
+        );
+        // A key referring to a string value.
+        assert_eq!(
+            p(b"\x42\xe1a\x51\x29\x02\x01"),
+            Ref(Value::new(Array(vec![Value::new(String(vec![b'a'])), hash.clone()])))
+        );
+
+        // The second key refers to the first one of the same hash.
+        let s = b"\x52\xe1a\x02\x29\x02\x01";
+        assert_eq!(p(s), Ref(Value::new(Hash(map.clone()))));
+        let config = Config::default().with_reject_duplicate_keys(true);
+        match Parser::new(ArcBuilder::new(), &config, s).parse().unwrap_err() {
+            Error::DuplicateKey(ref key) => assert_eq!(key, b"a"),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let config = Config::default();
+        let err = |s: &[u8]| Parser::new(ArcBuilder::new(), &config, s).parse().unwrap_err();
+        // Untracked target.
+        match err(b"\x42\x61a\x51\x29\x02\x01") {
+            Error::InvalidRef(2) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+        // Tracked target that is not a string.
+        match err(b"\x42\x81\x51\x29\x02\x01") {
+            Error::InvalidType => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+        // Forward reference.
+        match err(b"\x51\x29\x05\x01") {
+            Error::InvalidRef(5) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
     #[test]
     fn test_f32() {
         assert_eq!(
@@ -1,17 +1,43 @@
 use std;
 use std::cell::Cell;
+use std::fmt;
+use std::iter;
+use std::slice;
 use std::collections::HashMap;
+use std::collections::HashSet;
+
+#[cfg(feature = "indexmap")]
+use indexmap::IndexMap;
 
 use typed_arena;
 
+#[cfg(feature = "smallvec")]
+use smallvec::SmallVec;
+
+use arc;
 use parser;
 pub use parser::Error;
 pub use parser::Result;
 
+/// Map behind `Inner::Hash`, insertion-ordered with the `indexmap` feature.
+#[cfg(not(feature = "indexmap"))]
+pub type Map<'a> = HashMap<&'a str, Value<'a>>;
+#[cfg(feature = "indexmap")]
+pub type Map<'a> = IndexMap<&'a str, Value<'a>>;
+
 pub struct Arena<'a> {
     values: typed_arena::Arena<Cell<Inner<'a>>>,
     arrays: typed_arena::Arena<Vec<Value<'a>>>,
-    hashes: typed_arena::Arena<HashMap<&'a str, Value<'a>>>,
+    hashes: typed_arena::Arena<Map<'a>>,
+    bytes: typed_arena::Arena<u8>,
+    #[cfg(feature = "smallvec")]
+    slices: typed_arena::Arena<Value<'a>>,
+}
+
+impl<'a> Default for Arena<'a> {
+    fn default() -> Self {
+        Arena::new()
+    }
 }
 
 impl<'a> Arena<'a> {
@@ -20,6 +46,9 @@ impl<'a> Arena<'a> {
             values: typed_arena::Arena::new(),
             arrays: typed_arena::Arena::new(),
             hashes: typed_arena::Arena::new(),
+            bytes: typed_arena::Arena::new(),
+            #[cfg(feature = "smallvec")]
+            slices: typed_arena::Arena::new(),
         }
     }
 }
@@ -35,18 +64,26 @@ pub enum Inner<'a> {
     Ref(Value<'a>),
     WeakRef(Value<'a>),
     Array(&'a [Value<'a>]),
-    Hash(&'a HashMap<&'a str, Value<'a>>),
+    Hash(&'a Map<'a>),
     Object(Value<'a>, Value<'a>),
     Bool(bool),
     Regexp(Value<'a>, Value<'a>),
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, PartialEq)]
 pub struct Value<'a: 'a>(pub &'a Cell<Inner<'a>>);
 
+/// Prints each cell once, so shared and self-referencing values do not
+/// recurse forever.
+impl<'a> fmt::Debug for Value<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.debug_fmt(f, &mut HashSet::new())
+    }
+}
+
 impl<'a> parser::Value<'a> for Value<'a> {
     type Array = &'a [Value<'a>];
-    type Hash = &'a HashMap<&'a str, Value<'a>>;
+    type Hash = &'a Map<'a>;
 
     fn set_undef(&mut self) {
         self.set(Inner::Undef);
@@ -57,7 +94,7 @@ impl<'a> parser::Value<'a> for Value<'a> {
     }
 
     fn set_false(&mut self) {
-        self.set(Inner::Bool(true));
+        self.set(Inner::Bool(false));
     }
 
     fn set_i64(&mut self, v: i64) {
@@ -123,6 +160,236 @@ impl<'a> Value<'a> {
     fn set(&self, inner: Inner<'a>) {
         self.0.set(inner)
     }
+
+    fn debug_fmt(&self, f: &mut fmt::Formatter, seen: &mut HashSet<usize>) -> fmt::Result {
+        let obj_id = self.0 as *const _ as usize;
+        if seen.contains(&obj_id) {
+            return write!(f, "<loop>");
+        }
+        seen.insert(obj_id);
+
+        match self.0.get() {
+            Inner::Ref(v) => {
+                write!(f, "\\")?;
+                v.debug_fmt(f, seen)
+            }
+
+            Inner::WeakRef(v) => {
+                write!(f, "\\?")?;
+                v.debug_fmt(f, seen)
+            }
+
+            Inner::Array(a) => {
+                write!(f, "Array(")?;
+                for (i, v) in a.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    v.debug_fmt(f, seen)?;
+                }
+                write!(f, ")")
+            }
+
+            Inner::Hash(h) => {
+                write!(f, "Hash(")?;
+                for (i, (k, v)) in h.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?} => ", k)?;
+                    v.debug_fmt(f, seen)?;
+                }
+                write!(f, ")")
+            }
+
+            Inner::Object(class, obj) => {
+                write!(f, "<")?;
+                class.debug_fmt(f, seen)?;
+                write!(f, "=")?;
+                obj.debug_fmt(f, seen)?;
+                write!(f, ">")
+            }
+
+            Inner::String(s) => write!(f, "{:?}", String::from_utf8_lossy(s)),
+
+            Inner::Regexp(pattern, flags) => {
+                write!(f, "qr(")?;
+                pattern.debug_fmt(f, seen)?;
+                write!(f, ", ")?;
+                flags.debug_fmt(f, seen)?;
+                write!(f, ")")
+            }
+
+            other => write!(f, "{:?}", other),
+        }
+    }
+
+    /// Elements of an array, or of an array behind a reference.
+    pub fn as_array(&self) -> Option<&'a [Value<'a>]> {
+        match self.0.get() {
+            Inner::Array(a) => Some(a),
+            Inner::Ref(v) => {
+                match v.0.get() {
+                    Inner::Array(a) => Some(a),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Entries of a hash, or of a hash behind a reference.
+    pub fn as_hash(&self) -> Option<&'a Map<'a>> {
+        match self.0.get() {
+            Inner::Hash(h) => Some(h),
+            Inner::Ref(v) => {
+                match v.0.get() {
+                    Inner::Hash(h) => Some(h),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Integers that fit in `u64`.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.0.get() {
+            Inner::U64(v) => Some(v),
+            Inner::I64(v) if v >= 0 => Some(v as u64),
+            _ => None,
+        }
+    }
+
+    /// Integers that fit in `i64`.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self.0.get() {
+            Inner::I64(v) => Some(v),
+            Inner::U64(v) if v <= i64::MAX as u64 => Some(v as i64),
+            _ => None,
+        }
+    }
+
+    /// Any number, converted to `f64` with possible loss of precision.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self.0.get() {
+            Inner::F64(v) => Some(v),
+            Inner::F32(v) => Some(v as f64),
+            Inner::I64(v) => Some(v as f64),
+            Inner::U64(v) => Some(v as f64),
+            _ => None,
+        }
+    }
+
+    /// Contents of a string, binary or UTF-8.
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self.0.get() {
+            Inner::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn iter_array(&self) -> Option<iter::Cloned<slice::Iter<'a, Value<'a>>>> {
+        self.as_array().map(|a| a.iter().cloned())
+    }
+
+    /// Number of elements if this is an array or a reference to one.
+    pub fn len(&self) -> Option<usize> {
+        self.as_array().map(|a| a.len())
+    }
+
+    pub fn is_empty(&self) -> Option<bool> {
+        self.as_array().map(|a| a.is_empty())
+    }
+
+    /// Deep-copy an `arc::Value` tree into `arena`, so code written
+    /// against arena values can read it.
+    ///
+    /// Cells shared in the source, including those forming cycles, are
+    /// shared the same way in the copy, and expired weak references become
+    /// `Undef`. Fails with `InvalidType` on hash keys that are not valid
+    /// UTF-8, which arena hashes cannot hold.
+    pub fn from_arc(value: &arc::Value, arena: &'a Arena<'a>) -> Result<Value<'a>> {
+        let mut copier = FromArc {
+            arena: arena,
+            seen: HashMap::new(),
+        };
+        copier.copy(value)
+    }
+}
+
+struct FromArc<'a: 'a> {
+    arena: &'a Arena<'a>,
+    seen: HashMap<usize, Value<'a>>,
+}
+
+impl<'a> FromArc<'a> {
+    fn alloc(&self, inner: Inner<'a>) -> Value<'a> {
+        Value(self.arena.values.alloc(Cell::new(inner)))
+    }
+
+    fn bytes(&self, s: &[u8]) -> &'a [u8] {
+        self.arena.bytes.alloc_extend(s.iter().cloned())
+    }
+
+    fn copy(&mut self, value: &arc::Value) -> Result<Value<'a>> {
+        let cell = match value.try_upgrade() {
+            Some(arc::Value::Strong(a)) => a,
+            _ => return Ok(self.alloc(Inner::Undef)),
+        };
+
+        let cell_id = &*cell as *const _ as usize;
+        if let Some(&v) = self.seen.get(&cell_id) {
+            return Ok(v);
+        }
+
+        // Register the cell before copying its contents, so references
+        // back to it resolve to the same arena cell.
+        let v = self.alloc(Inner::Undef);
+        self.seen.insert(cell_id, v);
+
+        let inner = cell.read().unwrap().clone();
+        let inner = match inner {
+            arc::Inner::Undef => Inner::Undef,
+            arc::Inner::I64(n) => Inner::I64(n),
+            arc::Inner::U64(n) => Inner::U64(n),
+            arc::Inner::F32(n) => Inner::F32(n),
+            arc::Inner::F64(n) => Inner::F64(n),
+            arc::Inner::Bool(b) => Inner::Bool(b),
+            arc::Inner::String(ref s) => Inner::String(self.bytes(s)),
+            arc::Inner::Ref(ref o) => Inner::Ref(self.copy(o)?),
+            arc::Inner::WeakRef(ref o) => Inner::WeakRef(self.copy(o)?),
+            arc::Inner::Array(ref a) => {
+                let mut items = Vec::with_capacity(a.len());
+                for item in a {
+                    items.push(self.copy(item)?);
+                }
+                Inner::Array(self.arena.arrays.alloc(items))
+            }
+            arc::Inner::Hash(ref h) => {
+                let mut map = Map::with_capacity(h.len());
+                for (key, item) in h {
+                    let key = match std::str::from_utf8(self.bytes(key)) {
+                        Ok(key) => key,
+                        Err(_) => return Err(Error::InvalidType),
+                    };
+                    map.insert(key, self.copy(item)?);
+                }
+                Inner::Hash(self.arena.hashes.alloc(map))
+            }
+            arc::Inner::Object(ref class, ref obj) => {
+                let class = self.alloc(Inner::String(self.bytes(class)));
+                Inner::Object(class, self.copy(obj)?)
+            }
+            arc::Inner::Regexp(ref pattern, ref flags) => {
+                let pattern = self.alloc(Inner::String(self.bytes(pattern)));
+                let flags = self.alloc(Inner::String(self.bytes(flags)));
+                Inner::Regexp(pattern, flags)
+            }
+        };
+        v.set(inner);
+        Ok(v)
+    }
 }
 
 pub struct ArenaBuilder<'a: 'a> {
@@ -138,7 +405,7 @@ impl<'a> ArenaBuilder<'a> {
 impl<'a> parser::Builder<'a> for ArenaBuilder<'a> {
     type Value = Value<'a>;
     type ArrayBuilder = &'a mut Vec<Value<'a>>;
-    type HashBuilder = &'a mut HashMap<&'a str, Value<'a>>;
+    type HashBuilder = &'a mut Map<'a>;
 
     fn new(&mut self) -> Value<'a> {
         Value(self.arena.values.alloc(Cell::new(Inner::Undef)))
@@ -148,9 +415,9 @@ impl<'a> parser::Builder<'a> for ArenaBuilder<'a> {
         self.arena.arrays.alloc(Vec::with_capacity(count as usize))
     }
 
-    fn build_hash(&mut self, count: u64) -> &'a mut HashMap<&'a str, Value<'a>> {
+    fn build_hash(&mut self, count: u64) -> &'a mut Map<'a> {
         self.arena.hashes.alloc(
-            HashMap::with_capacity(count as usize),
+            Map::with_capacity(count as usize),
         )
     }
 }
@@ -166,7 +433,7 @@ impl<'a> parser::ArrayBuilder<'a, Value<'a>> for &'a mut Vec<Value<'a>> {
     }
 }
 
-impl<'a> parser::HashBuilder<'a, Value<'a>> for &'a mut HashMap<&'a str, Value<'a>> {
+impl<'a> parser::HashBuilder<'a, Value<'a>> for &'a mut Map<'a> {
     fn insert(&mut self, key: &'a [u8], value: Value<'a>) -> Result<()> {
         let s = match std::str::from_utf8(key) {
             Ok(s) => s,
@@ -178,11 +445,69 @@ impl<'a> parser::HashBuilder<'a, Value<'a>> for &'a mut HashMap<&'a str, Value<'
         Ok(())
     }
 
-    fn finalize(self) -> &'a HashMap<&'a str, Value<'a>> {
+    fn finalize(self) -> &'a Map<'a> {
         self
     }
 }
 
+/// Same as `ArenaBuilder`, but collects array elements on the stack while
+/// parsing and then copies them into one contiguous arena slice, instead
+/// of allocating a `Vec` per array. Arrays of up to 8 elements never touch
+/// the heap outside the arena.
+#[cfg(feature = "smallvec")]
+pub struct SmallArenaBuilder<'a: 'a> {
+    arena: &'a Arena<'a>,
+}
+
+#[cfg(feature = "smallvec")]
+impl<'a> SmallArenaBuilder<'a> {
+    pub fn new(arena: &'a Arena<'a>) -> SmallArenaBuilder<'a> {
+        SmallArenaBuilder { arena: arena }
+    }
+}
+
+#[cfg(feature = "smallvec")]
+pub struct SmallArray<'a: 'a> {
+    arena: &'a Arena<'a>,
+    items: SmallVec<[Value<'a>; 8]>,
+}
+
+#[cfg(feature = "smallvec")]
+impl<'a> parser::Builder<'a> for SmallArenaBuilder<'a> {
+    type Value = Value<'a>;
+    type ArrayBuilder = SmallArray<'a>;
+    type HashBuilder = &'a mut Map<'a>;
+
+    fn new(&mut self) -> Value<'a> {
+        Value(self.arena.values.alloc(Cell::new(Inner::Undef)))
+    }
+
+    fn build_array(&mut self, count: u64) -> SmallArray<'a> {
+        SmallArray {
+            arena: self.arena,
+            items: SmallVec::with_capacity(count as usize),
+        }
+    }
+
+    fn build_hash(&mut self, count: u64) -> &'a mut Map<'a> {
+        self.arena.hashes.alloc(
+            Map::with_capacity(count as usize),
+        )
+    }
+}
+
+#[cfg(feature = "smallvec")]
+impl<'a> parser::ArrayBuilder<'a, Value<'a>> for SmallArray<'a> {
+    fn insert(&mut self, v: Value<'a>) -> Result<()> {
+        self.items.push(v);
+        Ok(())
+    }
+
+    fn finalize(self) -> &'a [Value<'a>] {
+        self.arena.slices.alloc_extend(self.items)
+    }
+}
+
 pub fn parse<'a>(s: &'a [u8], arena: &'a Arena<'a>) -> Result<Value<'a>> {
     let builder = ArenaBuilder { arena: arena };
     parser::parse(s, builder)
@@ -194,7 +519,8 @@ mod test {
     use arena::Value;
     use arena::Inner;
     use arena::parse;
-
+    use arena::Error;
+    use arc;
 
     #[test]
     fn test_self_ref() {
@@ -207,4 +533,164 @@ mod test {
         };
         assert_eq!(a_id, b_id);
     }
+
+    #[test]
+    fn test_debug() {
+        let arena = Arena::new();
+        assert_eq!(format!("{:?}", parse(b"\xa9\x01", &arena).unwrap()), "\\<loop>");
+
+        let v = parse(b"\x43\x01\x1f\x51\x63foo\x62ok", &arena).unwrap();
+        assert_eq!(
+            format!("{:?}", v),
+            "\\Array(U64(1), I64(-1), \\Hash(\"foo\" => \"ok\"))"
+        );
+
+        // [ $x, $x ] prints the shared cell only once.
+        let v = parse(b"\x42\xc1\x01\x2e\x02", &arena).unwrap();
+        assert_eq!(format!("{:?}", v), "\\Array(\\Array(U64(1)), <loop>)");
+    }
+
+    #[test]
+    fn test_bool() {
+        let arena = Arena::new();
+        let a = parse(b"\x42\x3b\x3a", &arena).unwrap();
+
+        let items: Vec<Inner> = a.iter_array().unwrap().map(|v| v.0.get()).collect();
+        assert_eq!(items, vec![Inner::Bool(true), Inner::Bool(false)]);
+    }
+
+    #[test]
+    fn test_utf8_keys() {
+        let arena = Arena::new();
+        assert!(parse(b"\x51\x27\x02\xc3\xa9\x01", &arena).is_ok());
+
+        match parse(b"\x51\x27\x02\xc3\x28\x01", &arena).unwrap_err() {
+            Error::InvalidUtf8 { pos } => assert_eq!(pos, 3),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[cfg(feature = "indexmap")]
+    #[test]
+    fn test_hash_order() {
+        let arena = Arena::new();
+        let v = parse(b"\x53\x63zzz\x01\x61a\x02\x63mmm\x03", &arena).unwrap();
+
+        let keys: Vec<&str> = match v.0.get() {
+            Inner::Ref(Value(h)) => match h.get() {
+                Inner::Hash(m) => m.keys().cloned().collect(),
+                _ => panic!("expecting hash"),
+            },
+            _ => panic!("expecting reference"),
+        };
+        assert_eq!(keys, vec!["zzz", "a", "mmm"]);
+    }
+
+    #[test]
+    fn test_iter_array() {
+        let arena = Arena::new();
+        let a = parse(b"\x43\x01\x02\x63foo", &arena).unwrap();
+
+        assert_eq!(a.len(), Some(3));
+        assert_eq!(a.is_empty(), Some(false));
+
+        let items: Vec<Inner> = a.iter_array().unwrap().map(|v| v.0.get()).collect();
+        assert_eq!(
+            items,
+            vec![Inner::U64(1), Inner::U64(2), Inner::String(b"foo")]
+        );
+
+        let s = parse(b"\x01", &arena).unwrap();
+        assert_eq!(s.len(), None);
+        assert!(s.iter_array().is_none());
+    }
+
+    #[test]
+    fn test_accessors() {
+        let arena = Arena::new();
+        // [ 1, -1, 1.5, "foo", { a => 2 }, [] ]
+        let v = parse(
+            b"\x46\x01\x1f\x22\x00\x00\xc0\x3f\x63foo\x51\x61a\x02\x40",
+            &arena,
+        ).unwrap();
+        let items = v.as_array().unwrap();
+
+        assert_eq!(items[0].as_u64(), Some(1));
+        assert_eq!(items[0].as_i64(), Some(1));
+        assert_eq!(items[0].as_f64(), Some(1.0));
+        assert_eq!(items[1].as_u64(), None);
+        assert_eq!(items[1].as_i64(), Some(-1));
+        assert_eq!(items[2].as_f64(), Some(1.5));
+        assert_eq!(items[2].as_i64(), None);
+        assert_eq!(items[3].as_bytes(), Some(&b"foo"[..]));
+        assert_eq!(items[3].as_u64(), None);
+
+        let h = items[4].as_hash().unwrap();
+        assert_eq!(h.len(), 1);
+        assert_eq!(h["a"].as_u64(), Some(2));
+        assert!(items[4].as_array().is_none());
+
+        assert_eq!(items[5].as_array().map(|a| a.len()), Some(0));
+        assert!(items[5].as_hash().is_none());
+        assert!(items[0].as_bytes().is_none());
+
+        let big = parse(b"\x20\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01", &arena).unwrap();
+        assert_eq!(big.as_u64(), Some(u64::MAX));
+        assert_eq!(big.as_i64(), None);
+    }
+
+    #[test]
+    fn test_from_arc() {
+        let arena = Arena::new();
+        let input = b"\x43\x01\x1f\x51\x63foo\x62ok";
+        let src = arc::parse(input).unwrap();
+        let copy = Value::from_arc(&src, &arena).unwrap();
+        let expected = parse(input, &arena).unwrap();
+
+        assert_eq!(format!("{:?}", copy), format!("{:?}", expected));
+        let items = copy.as_array().unwrap();
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].as_u64(), Some(1));
+        assert_eq!(items[1].as_i64(), Some(-1));
+        let hash = items[2].as_hash().unwrap();
+        assert_eq!(hash.len(), 1);
+        assert_eq!(hash["foo"].as_bytes(), Some(&b"ok"[..]));
+
+        let src = arc::parse(b"\xa9\x01").unwrap();
+        let copy = Value::from_arc(&src, &arena).unwrap();
+        match copy.0.get() {
+            Inner::Ref(v) => assert_eq!(v.0 as *const _, copy.0 as *const _),
+            _ => panic!("expecting reference"),
+        }
+    }
+
+    #[cfg(feature = "smallvec")]
+    #[test]
+    fn test_small_arrays() {
+        use config::Config;
+        use parser::Parser;
+        use arena::{ArenaBuilder, SmallArenaBuilder};
+
+        let config = Config::default();
+        let inputs: &[&[u8]] = &[
+            b"\x40",
+            b"\x43\x01\x02\x63foo",
+            b"\x42\x41\x01\x4f\x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e",
+            b"\x2b\x02\x2b\x00\x51\x61k\x41\x25",
+        ];
+
+        for s in inputs {
+            let a1 = Arena::new();
+            let a2 = Arena::new();
+            let expected = format!("{:?}", Parser::new(ArenaBuilder::new(&a1), &config, s).parse());
+            let actual = format!("{:?}", Parser::new(SmallArenaBuilder::new(&a2), &config, s).parse());
+            assert_eq!(actual, expected);
+        }
+
+        let arena = Arena::new();
+        let v = Parser::new(SmallArenaBuilder::new(&arena), &config, b"\x43\x01\x02\x03")
+            .parse()
+            .unwrap();
+        assert_eq!(v.len(), Some(3));
+    }
 }
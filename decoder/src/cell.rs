@@ -0,0 +1,200 @@
+//! What `arc::Value` and `rc::Value` have in common. Both keep each
+//! decoded value in a shared cell and differ only in the kind of cell, so
+//! `Debug` formatting and `parser::Value` are written once here against
+//! `Cell`.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use parser::{self, Error, Result};
+
+/// Contents for a cell, as produced by the parser.
+pub enum Item<V: Cell> {
+    Undef,
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    String(Vec<u8>),
+    Ref(V),
+    WeakRef(V),
+    Array(V::Array),
+    Hash(V::Hash),
+    Object(Vec<u8>, V),
+    Regexp(Vec<u8>, Vec<u8>),
+}
+
+/// The parts of a cell's contents that `debug_fmt` descends into.
+pub enum View<'a, V: 'a> {
+    String(&'a [u8]),
+    Ref(&'a V),
+    WeakRef(&'a V),
+    Array(&'a [V]),
+    Hash(Vec<(&'a [u8], &'a V)>),
+    Object(&'a [u8], &'a V),
+    /// Anything else, formatted with its own `Debug`.
+    Scalar,
+}
+
+pub trait Cell: Clone {
+    type Inner: fmt::Debug;
+    type Array;
+    type Hash;
+
+    /// Call `f` with the address and contents of the cell, unless it was
+    /// held through a weak reference that has expired.
+    fn with_inner<R, F: FnOnce(usize, &Self::Inner) -> R>(&self, f: F) -> Option<R>;
+
+    fn view(inner: &Self::Inner) -> View<'_, Self>;
+
+    /// Replace the contents of the cell, for every slot that shares it.
+    fn store(&self, item: Item<Self>);
+
+    /// A weak handle to the same cell.
+    fn weaken(self) -> Self;
+}
+
+pub fn debug_fmt<V: Cell>(v: &V, f: &mut fmt::Formatter, seen: &mut HashSet<usize>) -> fmt::Result {
+    let res = v.with_inner(|obj_id, inner| {
+        if seen.contains(&obj_id) {
+            write!(f, "<loop>")
+        } else {
+            seen.insert(obj_id);
+            debug_fmt_inner::<V>(inner, f, seen)
+        }
+    });
+
+    match res {
+        Some(res) => res,
+        None => write!(f, "<dead weak ref>"),
+    }
+}
+
+fn debug_fmt_inner<V: Cell>(
+    inner: &V::Inner,
+    f: &mut fmt::Formatter,
+    seen: &mut HashSet<usize>,
+) -> fmt::Result {
+    match V::view(inner) {
+        View::Ref(v) => {
+            write!(f, "\\")?;
+            debug_fmt(v, f, seen)?;
+        }
+
+        View::WeakRef(v) => {
+            write!(f, "\\?")?;
+            debug_fmt(v, f, seen)?;
+        }
+
+        View::Array(a) => {
+            write!(f, "Array(")?;
+            for v in a {
+                debug_fmt(v, f, seen)?;
+            }
+            write!(f, ")")?;
+        }
+
+        View::Hash(h) => {
+            write!(f, "Hash(")?;
+            for (k, v) in h {
+                write!(f, "{:?} => ", k)?;
+                debug_fmt(v, f, seen)?;
+            }
+            write!(f, ")")?;
+        }
+
+        View::Object(class, obj) => {
+            write!(f, "<{:?}=", class)?;
+            debug_fmt(obj, f, seen)?;
+            write!(f, ">")?;
+        }
+
+        View::String(_) | View::Scalar => write!(f, "{:?}", inner)?,
+    }
+
+    Ok(())
+}
+
+fn to_string<V: Cell>(v: &V) -> Result<Vec<u8>> {
+    let s = v.with_inner(|_, inner| match V::view(inner) {
+        View::String(s) => Some(s.to_vec()),
+        _ => None,
+    });
+    s.and_then(|s| s).ok_or(Error::InvalidType)
+}
+
+impl<'buf, V: Cell> parser::Value<'buf> for V {
+    type Array = V::Array;
+    type Hash = V::Hash;
+
+    fn set_undef(&mut self) {
+        self.store(Item::Undef);
+    }
+
+    fn set_true(&mut self) {
+        self.store(Item::Bool(true));
+    }
+
+    fn set_false(&mut self) {
+        self.store(Item::Bool(false));
+    }
+
+    fn set_i64(&mut self, v: i64) {
+        self.store(Item::I64(v))
+    }
+
+    fn set_u64(&mut self, v: u64) {
+        self.store(Item::U64(v))
+    }
+
+    fn set_f32(&mut self, v: f32) {
+        self.store(Item::F32(v))
+    }
+
+    fn set_f64(&mut self, v: f64) {
+        self.store(Item::F64(v))
+    }
+
+    fn set_ref(&mut self, o: Self) {
+        self.store(Item::Ref(o));
+    }
+
+    fn set_weak_ref(&mut self, o: Self) {
+        self.store(Item::WeakRef(o.weaken()));
+    }
+
+    fn set_alias(&mut self, o: Self) {
+        *self = o;
+    }
+
+    fn set_array(&mut self, a: Self::Array) {
+        self.store(Item::Array(a));
+    }
+
+    fn set_hash(&mut self, h: Self::Hash) {
+        self.store(Item::Hash(h));
+    }
+
+    fn set_binary(&mut self, s: &[u8]) {
+        self.store(Item::String(s.to_owned()));
+    }
+
+    fn set_string(&mut self, s: &[u8]) {
+        self.store(Item::String(s.to_owned()));
+    }
+
+    fn set_object(&mut self, class: Self, value: Self) -> Result<()> {
+        self.store(Item::Object(to_string(&class)?, value));
+        Ok(())
+    }
+
+    fn set_object_freeze(&mut self, class: Self, value: Self) -> Result<()> {
+        self.set_object(class, value)
+    }
+
+    fn set_regexp(&mut self, pattern: Self, flags: Self) -> Result<()> {
+        self.store(Item::Regexp(to_string(&pattern)?, to_string(&flags)?));
+        Ok(())
+    }
+}
@@ -1,28 +1,122 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+
+use parser::ExtensionHandler;
+
 #[derive(Clone, Debug)]
 pub struct Config {
     max_suffix_len: u64,
     max_string_len: u64,
+    max_key_len: Option<u64>,
     max_compressed_size: u64,
     max_uncompressed_size: u64,
     max_array_size: u64,
     max_hash_size: u64,
+    max_depth: u64,
+    max_tracked_refs: u64,
+    max_object_classes: u64,
+    max_total_bytes: u64,
+    max_leaves: u64,
+    expose_object_class: bool,
+    strict_utf8: bool,
+    verify_checksum: bool,
+    reject_lossy_float: bool,
+    reject_undef_in_required: bool,
+    reject_duplicate_keys: bool,
+    require_eof: bool,
+    allow_unknown_tags: bool,
+    recursive_decompress: bool,
+    extensions: HashMap<u8, ExtensionHandler>,
 }
 
 impl Default for Config {
     fn default() -> Config {
-        Config::default()
-    }
-}
-
-impl Config {
-    pub fn default() -> Config {
         Config {
             max_suffix_len: 1_000_000,
-            max_string_len: 1_000_000,
+            max_string_len: u64::MAX,
+            max_key_len: None,
             max_compressed_size: 100_000_000,
             max_uncompressed_size: 100_000_000,
             max_array_size: 1_000_000,
             max_hash_size: 1_000_000,
+            max_depth: u64::MAX,
+            max_tracked_refs: 1_000_000,
+            max_object_classes: 1_000_000,
+            max_total_bytes: u64::MAX,
+            max_leaves: 1_000_000,
+            expose_object_class: false,
+            strict_utf8: false,
+            verify_checksum: false,
+            reject_lossy_float: false,
+            reject_undef_in_required: false,
+            reject_duplicate_keys: false,
+            require_eof: false,
+            allow_unknown_tags: false,
+            recursive_decompress: false,
+            extensions: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Conservative limits for documents from untrusted sources:
+    ///
+    /// - header suffix: 64 KiB
+    /// - strings and binary values: 64 KiB
+    /// - compressed body: 16 MiB
+    /// - uncompressed body: 16 MiB
+    /// - array and hash entries: 10 000
+    /// - nesting depth: 128
+    /// - tracked values: 10 000
+    /// - object classes: 1 000
+    /// - estimated size of the decoded values: 64 MiB
+    ///
+    /// Other settings are as in `default()`, and any of them can be
+    /// adjusted further with the `with_*` methods.
+    pub fn strict() -> Config {
+        Config {
+            max_suffix_len: 64 * 1024,
+            max_string_len: 64 * 1024,
+            max_compressed_size: 16 * 1024 * 1024,
+            max_uncompressed_size: 16 * 1024 * 1024,
+            max_array_size: 10_000,
+            max_hash_size: 10_000,
+            max_depth: 128,
+            max_tracked_refs: 10_000,
+            max_object_classes: 1_000,
+            max_total_bytes: 64 * 1024 * 1024,
+            ..Config::default()
+        }
+    }
+
+    /// The `strict()` limits, plus the checks that matter when the sender
+    /// may be hostile:
+    ///
+    /// - hashes with duplicate keys are rejected, so that a value checked
+    ///   under one key cannot be replaced by a later entry
+    /// - input after the root value is rejected, so that nothing can be
+    ///   smuggled past a validator that only looks at the decoded value
+    /// - `STR_UTF8` strings must be valid UTF-8
+    /// - reserved tags are errors, and no `EXTEND` handlers are registered
+    ///
+    /// The size limits also bound decompression: compressed bodies over
+    /// 16 MiB are refused before reading, and decompression stops as soon
+    /// as the output passes 16 MiB.
+    ///
+    /// `de::Deserializer` applies the depth, array, hash and string limits
+    /// and rejects duplicate keys too, but checks for trailing input only
+    /// when its `end` is called, as `FromSereal::from_sereal_with_config`
+    /// does. `max_total_bytes`, `max_tracked_refs` and `max_object_classes`
+    /// apply to `Parser`-based decoding only.
+    pub fn for_untrusted() -> Config {
+        Config {
+            reject_duplicate_keys: true,
+            require_eof: true,
+            strict_utf8: true,
+            allow_unknown_tags: false,
+            extensions: HashMap::new(),
+            ..Config::strict()
         }
     }
 
@@ -37,6 +131,7 @@ impl Config {
         }
     }
 
+    /// Longest `BINARY` or `STR_UTF8` payload. Unlimited by default.
     pub fn max_string_len(&self) -> u64 {
         self.max_string_len
     }
@@ -48,6 +143,18 @@ impl Config {
         }
     }
 
+    /// Longest hash key accepted. Same as `max_string_len` unless set.
+    pub fn max_key_len(&self) -> u64 {
+        self.max_key_len.unwrap_or(self.max_string_len)
+    }
+
+    pub fn with_max_key_len(self, new_max: u64) -> Config {
+        Config {
+            max_key_len: Some(new_max),
+            ..self
+        }
+    }
+
     pub fn max_compressed_size(&self) -> u64 {
         self.max_compressed_size
     }
@@ -91,4 +198,405 @@ impl Config {
             ..self
         }
     }
+
+    /// How deeply values may nest. Each reference, container, object or
+    /// copy adds one level; a lone scalar is at depth 1. Unlimited by
+    /// default.
+    pub fn max_depth(&self) -> u64 {
+        self.max_depth
+    }
+
+    pub fn with_max_depth(self, new_max: u64) -> Config {
+        Config {
+            max_depth: new_max,
+            ..self
+        }
+    }
+
+    /// How many values with the track bit set (or class names, which are
+    /// always tracked) the parser remembers as reference targets.
+    pub fn max_tracked_refs(&self) -> u64 {
+        self.max_tracked_refs
+    }
+
+    pub fn with_max_tracked_refs(self, new_max: u64) -> Config {
+        Config {
+            max_tracked_refs: new_max,
+            ..self
+        }
+    }
+
+    /// How many distinct class names a builder that interns them, such as
+    /// `ArcBuilder`, may keep. A name counts once, however many objects use
+    /// it. Builders that do not intern class names are not limited.
+    pub fn max_object_classes(&self) -> u64 {
+        self.max_object_classes
+    }
+
+    pub fn with_max_object_classes(self, new_max: u64) -> Config {
+        Config {
+            max_object_classes: new_max,
+            ..self
+        }
+    }
+
+    /// Cap on the estimated memory taken by the decoded values, summed over
+    /// the whole document. Unlimited by default.
+    pub fn max_total_bytes(&self) -> u64 {
+        self.max_total_bytes
+    }
+
+    pub fn with_max_total_bytes(self, new_max: u64) -> Config {
+        Config {
+            max_total_bytes: new_max,
+            ..self
+        }
+    }
+
+    /// Most `(path, scalar)` pairs `flat::parse_flat_with_config` produces
+    /// for one document, counting a value once for every reference to it.
+    pub fn max_leaves(&self) -> u64 {
+        self.max_leaves
+    }
+
+    pub fn with_max_leaves(self, new_max: u64) -> Config {
+        Config {
+            max_leaves: new_max,
+            ..self
+        }
+    }
+
+    /// Whether the serde deserializer presents blessed objects as enums
+    /// with the class name as the variant, or discards the class.
+    pub fn expose_object_class(&self) -> bool {
+        self.expose_object_class
+    }
+
+    pub fn with_expose_object_class(self, expose: bool) -> Config {
+        Config {
+            expose_object_class: expose,
+            ..self
+        }
+    }
+
+    /// Whether the parser rejects `STR_UTF8` values that are not valid UTF-8.
+    pub fn strict_utf8(&self) -> bool {
+        self.strict_utf8
+    }
+
+    pub fn with_strict_utf8(self, strict: bool) -> Config {
+        Config {
+            strict_utf8: strict,
+            ..self
+        }
+    }
+
+    /// Whether `parse` checks the body against the `crc32` entry of the
+    /// user metadata.
+    pub fn verify_checksum(&self) -> bool {
+        self.verify_checksum
+    }
+
+    pub fn with_verify_checksum(self, verify: bool) -> Config {
+        Config {
+            verify_checksum: verify,
+            ..self
+        }
+    }
+
+    /// Whether the serde deserializer fails when a `DOUBLE` is read into
+    /// an `f32` and does not survive the conversion unchanged.
+    pub fn reject_lossy_float(&self) -> bool {
+        self.reject_lossy_float
+    }
+
+    pub fn with_reject_lossy_float(self, reject: bool) -> Config {
+        Config {
+            reject_lossy_float: reject,
+            ..self
+        }
+    }
+
+    /// Whether the serde deserializer fails with `UnexpectedUndef` when an
+    /// `UNDEF` is read into anything other than an `Option` or a unit.
+    /// Otherwise the visitor gets `visit_none` and reports its own error.
+    pub fn reject_undef_in_required(&self) -> bool {
+        self.reject_undef_in_required
+    }
+
+    pub fn with_reject_undef_in_required(self, reject: bool) -> Config {
+        Config {
+            reject_undef_in_required: reject,
+            ..self
+        }
+    }
+
+    /// Whether the parser fails on a hash that contains the same key twice,
+    /// instead of letting the builder keep the last value.
+    pub fn reject_duplicate_keys(&self) -> bool {
+        self.reject_duplicate_keys
+    }
+
+    pub fn with_reject_duplicate_keys(self, reject: bool) -> Config {
+        Config {
+            reject_duplicate_keys: reject,
+            ..self
+        }
+    }
+
+    /// Whether `Parser::parse` fails on input left after the root value.
+    pub fn require_eof(&self) -> bool {
+        self.require_eof
+    }
+
+    pub fn with_require_eof(self, require: bool) -> Config {
+        Config {
+            require_eof: require,
+            ..self
+        }
+    }
+
+    /// Whether the reserved tags `0x34` to `0x38` are skipped like `PAD`
+    /// instead of failing with `UnknownTag`, for documents from encoders
+    /// newer than this decoder. Only these are assumed to be single bytes:
+    /// `LONG_DOUBLE`, `PACKET_START` and `EXTEND` may carry a payload of
+    /// unknown length and are still errors, unless `EXTEND` has a handler
+    /// registered with `with_extension`.
+    pub fn allow_unknown_tags(&self) -> bool {
+        self.allow_unknown_tags
+    }
+
+    pub fn with_allow_unknown_tags(self, allow: bool) -> Config {
+        Config {
+            allow_unknown_tags: allow,
+            ..self
+        }
+    }
+
+    /// Whether a compressed body that is itself a complete document, as
+    /// written by pipelines that wrap Sereal output in another Sereal
+    /// frame, is decoded in turn. At most `MAX_NESTED_DOCUMENTS` frames
+    /// are unwrapped below the outer one.
+    pub fn recursive_decompress(&self) -> bool {
+        self.recursive_decompress
+    }
+
+    pub fn with_recursive_decompress(self, recursive: bool) -> Config {
+        Config {
+            recursive_decompress: recursive,
+            ..self
+        }
+    }
+
+    /// Handler for `EXTEND` tags with sub-tag `sel`. Sub-tags without one
+    /// fail with `UnsupportedExtension`; none are registered by default.
+    pub fn extension(&self, sel: u8) -> Option<ExtensionHandler> {
+        self.extensions.get(&sel).cloned()
+    }
+
+    pub fn with_extension(mut self, sel: u8, handler: ExtensionHandler) -> Config {
+        self.extensions.insert(sel, handler);
+        self
+    }
+}
+
+/// Every `Config` setting as a public field, for building a configuration
+/// in one expression and checking it before use:
+///
+/// ```
+/// # use sereal_decoder::config::ConfigBuilder;
+/// let config = ConfigBuilder {
+///     max_compressed_size: 1024,
+///     max_uncompressed_size: 4096,
+///     ..ConfigBuilder::default()
+/// }.build().unwrap();
+/// # let _ = config;
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConfigBuilder {
+    pub max_suffix_len: u64,
+    pub max_string_len: u64,
+    pub max_key_len: Option<u64>,
+    pub max_compressed_size: u64,
+    pub max_uncompressed_size: u64,
+    pub max_array_size: u64,
+    pub max_hash_size: u64,
+    pub max_depth: u64,
+    pub max_tracked_refs: u64,
+    pub max_object_classes: u64,
+    pub max_total_bytes: u64,
+    pub max_leaves: u64,
+    pub expose_object_class: bool,
+    pub strict_utf8: bool,
+    pub verify_checksum: bool,
+    pub reject_lossy_float: bool,
+    pub reject_undef_in_required: bool,
+    pub reject_duplicate_keys: bool,
+    pub require_eof: bool,
+    pub allow_unknown_tags: bool,
+    pub recursive_decompress: bool,
+    pub extensions: HashMap<u8, ExtensionHandler>,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> ConfigBuilder {
+        ConfigBuilder::from(Config::default())
+    }
+}
+
+impl From<Config> for ConfigBuilder {
+    fn from(c: Config) -> ConfigBuilder {
+        ConfigBuilder {
+            max_suffix_len: c.max_suffix_len,
+            max_string_len: c.max_string_len,
+            max_key_len: c.max_key_len,
+            max_compressed_size: c.max_compressed_size,
+            max_uncompressed_size: c.max_uncompressed_size,
+            max_array_size: c.max_array_size,
+            max_hash_size: c.max_hash_size,
+            max_depth: c.max_depth,
+            max_tracked_refs: c.max_tracked_refs,
+            max_object_classes: c.max_object_classes,
+            max_total_bytes: c.max_total_bytes,
+            max_leaves: c.max_leaves,
+            expose_object_class: c.expose_object_class,
+            strict_utf8: c.strict_utf8,
+            verify_checksum: c.verify_checksum,
+            reject_lossy_float: c.reject_lossy_float,
+            reject_undef_in_required: c.reject_undef_in_required,
+            reject_duplicate_keys: c.reject_duplicate_keys,
+            require_eof: c.require_eof,
+            allow_unknown_tags: c.allow_unknown_tags,
+            recursive_decompress: c.recursive_decompress,
+            extensions: c.extensions,
+        }
+    }
+}
+
+impl ConfigBuilder {
+    /// Check the settings against each other and turn them into a `Config`.
+    pub fn build(self) -> Result<Config, ConfigError> {
+        if self.max_uncompressed_size < self.max_compressed_size {
+            return Err(ConfigError::UncompressedBelowCompressed {
+                max_compressed_size: self.max_compressed_size,
+                max_uncompressed_size: self.max_uncompressed_size,
+            });
+        }
+        if self.max_depth == 0 {
+            return Err(ConfigError::ZeroDepth);
+        }
+
+        Ok(Config {
+            max_suffix_len: self.max_suffix_len,
+            max_string_len: self.max_string_len,
+            max_key_len: self.max_key_len,
+            max_compressed_size: self.max_compressed_size,
+            max_uncompressed_size: self.max_uncompressed_size,
+            max_array_size: self.max_array_size,
+            max_hash_size: self.max_hash_size,
+            max_depth: self.max_depth,
+            max_tracked_refs: self.max_tracked_refs,
+            max_object_classes: self.max_object_classes,
+            max_total_bytes: self.max_total_bytes,
+            max_leaves: self.max_leaves,
+            expose_object_class: self.expose_object_class,
+            strict_utf8: self.strict_utf8,
+            verify_checksum: self.verify_checksum,
+            reject_lossy_float: self.reject_lossy_float,
+            reject_undef_in_required: self.reject_undef_in_required,
+            reject_duplicate_keys: self.reject_duplicate_keys,
+            require_eof: self.require_eof,
+            allow_unknown_tags: self.allow_unknown_tags,
+            recursive_decompress: self.recursive_decompress,
+            extensions: self.extensions,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Bodies could be accepted for decompression that can never be
+    /// decompressed within the limit.
+    UncompressedBelowCompressed {
+        max_compressed_size: u64,
+        max_uncompressed_size: u64,
+    },
+    /// No document fits: even a lone scalar is at depth 1.
+    ZeroDepth,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::UncompressedBelowCompressed {
+                max_compressed_size,
+                max_uncompressed_size,
+            } => write!(
+                f,
+                "max_uncompressed_size ({}) is below max_compressed_size ({})",
+                max_uncompressed_size, max_compressed_size
+            ),
+            ConfigError::ZeroDepth => write!(f, "max_depth must be at least 1"),
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn description(&self) -> &str {
+        match *self {
+            ConfigError::UncompressedBelowCompressed { .. } => {
+                "max_uncompressed_size is below max_compressed_size"
+            }
+            ConfigError::ZeroDepth => "max_depth is zero",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use config::{Config, ConfigBuilder, ConfigError};
+
+    #[test]
+    fn builder() {
+        let config = ConfigBuilder {
+            max_compressed_size: 10,
+            max_uncompressed_size: 20,
+            strict_utf8: true,
+            ..ConfigBuilder::default()
+        }.build().unwrap();
+        assert_eq!(config.max_compressed_size(), 10);
+        assert_eq!(config.max_uncompressed_size(), 20);
+        assert!(config.strict_utf8());
+        assert_eq!(config.max_depth(), Config::default().max_depth());
+
+        let config = ConfigBuilder::from(Config::strict()).build().unwrap();
+        assert_eq!(config.max_depth(), 128);
+    }
+
+    #[test]
+    fn builder_rejects_small_uncompressed() {
+        let res = ConfigBuilder {
+            max_compressed_size: 20,
+            max_uncompressed_size: 10,
+            ..ConfigBuilder::default()
+        }.build();
+        let err = res.unwrap_err();
+        assert_eq!(
+            err,
+            ConfigError::UncompressedBelowCompressed {
+                max_compressed_size: 20,
+                max_uncompressed_size: 10,
+            }
+        );
+        assert_eq!(
+            format!("{}", err),
+            "max_uncompressed_size (10) is below max_compressed_size (20)"
+        );
+    }
+
+    #[test]
+    fn builder_rejects_zero_depth() {
+        let res = ConfigBuilder { max_depth: 0, ..ConfigBuilder::default() }.build();
+        assert_eq!(res.unwrap_err(), ConfigError::ZeroDepth);
+    }
 }
@@ -0,0 +1,129 @@
+use std::cell::Cell;
+
+use parser;
+use null::Value;
+pub use parser::Error;
+pub use parser::Result;
+
+/// Number of values seen by a `CountingBuilder`.
+#[derive(Debug, Default)]
+pub struct Counts {
+    nodes: Cell<u64>,
+    elements: Cell<u64>,
+}
+
+impl Counts {
+    pub fn new() -> Counts {
+        Counts::default()
+    }
+
+    /// Every value the parser asked the builder for: slots, reference
+    /// targets, and values materialized again by `COPY`.
+    pub fn nodes(&self) -> u64 {
+        self.nodes.get()
+    }
+
+    /// Values inserted into arrays and hashes.
+    pub fn elements(&self) -> u64 {
+        self.elements.get()
+    }
+}
+
+fn bump(c: &Cell<u64>) {
+    c.set(c.get() + 1);
+}
+
+/// Builder that only counts values. Its values are zero-sized, so parsing
+/// with it checks the structure of a document without allocating for it.
+pub struct CountingBuilder<'a> {
+    counts: &'a Counts,
+}
+
+impl<'a> CountingBuilder<'a> {
+    pub fn new(counts: &'a Counts) -> CountingBuilder<'a> {
+        CountingBuilder { counts: counts }
+    }
+}
+
+impl<'a, 'buf> parser::Builder<'buf> for CountingBuilder<'a> {
+    type Value = Value;
+    type ArrayBuilder = Tally<'a>;
+    type HashBuilder = Tally<'a>;
+
+    fn new(&mut self) -> Value {
+        bump(&self.counts.nodes);
+        Value
+    }
+
+    fn build_array(&mut self, _: u64) -> Tally<'a> {
+        Tally(&self.counts.elements)
+    }
+
+    fn build_hash(&mut self, _: u64) -> Tally<'a> {
+        Tally(&self.counts.elements)
+    }
+}
+
+/// Adds every inserted value to `Counts::elements`.
+pub struct Tally<'a>(&'a Cell<u64>);
+
+impl<'a, 'buf> parser::ArrayBuilder<'buf, Value> for Tally<'a> {
+    fn insert(&mut self, _: Value) -> Result<()> {
+        bump(self.0);
+        Ok(())
+    }
+
+    fn finalize(self) {}
+}
+
+impl<'a, 'buf> parser::HashBuilder<'buf, Value> for Tally<'a> {
+    fn insert(&mut self, _: &'buf [u8], _: Value) -> Result<()> {
+        bump(self.0);
+        Ok(())
+    }
+
+    fn finalize(self) {}
+}
+
+pub fn count(s: &[u8]) -> Result<Counts> {
+    let counts = Counts::new();
+    parser::parse(s, CountingBuilder::new(&counts))?;
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod test {
+    use count::{count, Counts, CountingBuilder};
+
+    #[test]
+    fn test_copy_complex_value() {
+        // [ \[1], COPY, COPY ]: each copy builds its own reference and
+        // array, in addition to the slot it is aliased into.
+        let counts = count(b"\x43\x41\x01\x2f\x02\x2f\x02").unwrap();
+        assert_eq!(counts.nodes(), 13);
+        assert_eq!(counts.elements(), 6);
+    }
+
+    #[test]
+    fn test_document() {
+        use std::io::Cursor;
+        use parse;
+
+        // { foo => 1, bar => [2, 3] }
+        let counts = Counts::new();
+        let mut buf = Vec::new();
+        parse(
+            Cursor::new(b"=\xf3rl\x04\x00\x52\x63foo\x01\x63bar\x42\x02\x03"),
+            CountingBuilder::new(&counts),
+            &mut buf,
+        ).unwrap();
+
+        assert_eq!(counts.nodes(), 7);
+        assert_eq!(counts.elements(), 4);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(count(b"\x2b\x02\x00").unwrap_err().is_eof());
+    }
+}
@@ -0,0 +1,29 @@
+// CRC-32 (IEEE 802.3, as used by zlib), computed bytewise.
+
+const POLY: u32 = 0xedb8_8320;
+
+pub fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &b in buf {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (POLY & mask);
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::crc32;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"\x41\x01"), 0xdfbc_5c2d);
+    }
+}
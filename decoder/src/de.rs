@@ -1,39 +1,130 @@
 use std::error;
 use std::fmt;
-use std::collections::BTreeSet;
+use std::str;
+use std::collections::{BTreeSet, HashSet};
 
 use serde::de;
+use serde::de::value::BorrowedStrDeserializer;
 use sereal_common::constants::*;
 
 use config::Config;
+use parser::Extension;
 use reader::{self, Reader};
 
 pub enum Error {
     UnexpectedEof,
     OffsetOverflow,
     VarintOverflow,
-    InvalidRef(usize),
+    InvalidRef { target: usize, at: usize },
+    InvalidCopy { target: usize, at: usize },
+    UnexpectedUndef { at: usize },
+    InvalidType,
+    UnknownTag(u8),
+    UnsupportedExtension(u8),
+    DepthLimitExceeded { limit: u64 },
+    ArrayTooLarge { count: u64, limit: u64 },
+    HashTooLarge { count: u64, limit: u64 },
+    StringTooLarge { len: usize, limit: u64 },
+    DuplicateKey(Vec<u8>),
+    TrailingData { pos: usize },
     Custom(String),
+    /// `error` occurred with the reader at `pos`. Added to errors that
+    /// do not record their position themselves as they leave
+    /// `deserialize_any` or a sequence or map.
+    Positioned { pos: usize, error: Box<Error> },
 }
 
 impl Error {
-    pub fn as_invalid_ref(&self) -> Option<usize> {
+    /// Position in the body where deserialization failed, if known.
+    pub fn position(&self) -> Option<usize> {
+        match *self {
+            Error::InvalidRef { at, .. } => Some(at),
+            Error::InvalidCopy { at, .. } => Some(at),
+            Error::UnexpectedUndef { at } => Some(at),
+            Error::TrailingData { pos } => Some(pos),
+            Error::Positioned { pos, .. } => Some(pos),
+            _ => None,
+        }
+    }
+
+    /// The error without the position added by `Positioned`.
+    pub fn inner(&self) -> &Error {
+        match *self {
+            Error::Positioned { ref error, .. } => error,
+            ref e => e,
+        }
+    }
+
+    pub fn into_inner(self) -> Error {
         match self {
-            &Error::InvalidRef(p) => Some(p),
+            Error::Positioned { error, .. } => *error,
+            e => e,
+        }
+    }
+
+    fn at(self, pos: usize) -> Error {
+        if self.position().is_some() {
+            return self;
+        }
+
+        Error::Positioned {
+            pos: pos,
+            error: Box::new(self),
+        }
+    }
+
+    pub fn as_invalid_ref(&self) -> Option<usize> {
+        match self.inner() {
+            &Error::InvalidRef { target, .. } => Some(target),
+            _ => None,
+        }
+    }
+
+    pub fn as_unknown_tag(&self) -> Option<u8> {
+        match self.inner() {
+            &Error::UnknownTag(tag) => Some(tag),
+            _ => None,
+        }
+    }
+
+    pub fn as_unsupported_extension(&self) -> Option<u8> {
+        match self.inner() {
+            &Error::UnsupportedExtension(sel) => Some(sel),
             _ => None,
         }
     }
 }
 
 impl fmt::Display for Error {
+    #[allow(deprecated)]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::Error::*;
         match *self {
             UnexpectedEof | OffsetOverflow | VarintOverflow => {
                 write!(f, "{}", error::Error::description(self))
             }
-            InvalidRef(p) => write!(f, "invalid reference {}", p),
+            InvalidRef { target, at } => write!(f, "invalid reference {} at {}", target, at),
+            InvalidCopy { target, at } => write!(f, "invalid copy of {} at {}", target, at),
+            UnexpectedUndef { at } => write!(f, "unexpected undef at {}", at),
+            InvalidType => write!(f, "{}", error::Error::description(self)),
+            UnknownTag(tag) => write!(f, "unknown tag 0x{:02x}", tag),
+            UnsupportedExtension(sel) => write!(f, "unsupported extension 0x{:02x}", sel),
+            DepthLimitExceeded { limit } => write!(f, "nesting deeper than {} levels", limit),
+            ArrayTooLarge { count, limit } => {
+                write!(f, "array of {} elements exceeds the limit of {}", count, limit)
+            }
+            HashTooLarge { count, limit } => {
+                write!(f, "hash of {} entries exceeds the limit of {}", count, limit)
+            }
+            StringTooLarge { len, limit } => {
+                write!(f, "string of {} bytes exceeds the limit of {}", len, limit)
+            }
+            DuplicateKey(ref key) => {
+                write!(f, "duplicate hash key {:?}", String::from_utf8_lossy(key))
+            }
+            TrailingData { pos } => write!(f, "trailing data at {}", pos),
             Custom(ref b) => write!(f, "{}", b),
+            Positioned { pos, ref error } => write!(f, "{} at {}", error, pos),
         }
     }
 }
@@ -45,14 +136,34 @@ impl fmt::Debug for Error {
 }
 
 impl error::Error for Error {
+    #[allow(deprecated)]
     fn description(&self) -> &str {
         use self::Error::*;
         match *self {
             UnexpectedEof => "unexpected eof",
             OffsetOverflow => "offset overflow",
             VarintOverflow => "varint overflow",
-            InvalidRef(_) => "invalid reference",
+            InvalidRef { .. } => "invalid reference",
+            InvalidCopy { .. } => "invalid copy",
+            UnexpectedUndef { .. } => "unexpected undef",
+            InvalidType => "invalid type",
+            UnknownTag(_) => "unknown tag",
+            UnsupportedExtension(_) => "unsupported extension",
+            DepthLimitExceeded { .. } => "nesting too deep",
+            ArrayTooLarge { .. } => "array too large",
+            HashTooLarge { .. } => "hash too large",
+            StringTooLarge { .. } => "string too large",
+            DuplicateKey(_) => "duplicate hash key",
+            TrailingData { .. } => "trailing data",
             Custom(_) => "custom error",
+            Positioned { error: ref e, .. } => error::Error::description(&**e),
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Positioned { ref error, .. } => Some(&**error),
+            _ => None,
         }
     }
 }
@@ -68,42 +179,478 @@ impl From<reader::Error> for Error {
         match e {
             reader::Error::UnexpectedEof => Error::UnexpectedEof,
             reader::Error::OffsetOverflow => Error::OffsetOverflow,
-            reader::Error::VarintOverflow => Error::OffsetOverflow,
+            reader::Error::VarintOverflow => Error::VarintOverflow,
         }
     }
 }
 
+/// Prepended to the class name of `OBJECT_FREEZE` and `OBJECTV_FREEZE`
+/// values when they are exposed as enum variants, so that frozen objects
+/// can be told apart from regular ones of the same class.
+pub const FREEZE_PREFIX: &str = "FREEZE:";
+
+/// Serde deserializer reading directly from a Sereal document body.
+///
+/// Valid `STR_UTF8` strings are handed to visitors via `visit_borrowed_str`,
+/// other string and binary tags via `visit_borrowed_bytes`, so `&[u8]`,
+/// `&str` and borrowed `Cow<str>` targets borrow from the input without
+/// copying. This includes strings reached through `COPY`.
+///
+/// `REFP` and `ALIAS` are resolved by re-reading the value they point to,
+/// which must have been marked with the track bit.
+///
+/// The config's depth, array, hash and string limits apply, as does
+/// `reject_duplicate_keys`. Input left after the value is only checked
+/// by `end`. `max_total_bytes`, `max_tracked_refs` and
+/// `max_object_classes` bound what a `Parser` builds and have no effect
+/// here: the deserialized type does its own allocation.
 pub struct Deserializer<'cfg, 'b> {
     config: &'cfg Config,
     reader: Reader<'b>,
     seen: BTreeSet<usize>,
+    tracked: BTreeSet<usize>,
+    copy: bool,
+    depth: u64,
 }
 
 impl<'cfg, 'b> Deserializer<'cfg, 'b> {
+    /// Deserialize a document body, with the header already removed.
+    pub fn from_body(config: &'cfg Config, body: &'b [u8]) -> Self {
+        Deserializer::new(config, body)
+    }
+
+    /// Deserialize a complete document. The header is read and the body
+    /// decompressed into `buffer`, which the deserializer then borrows
+    /// from. Compressed documents fail with `CompressionUnsupported` if
+    /// the codec's feature is not enabled.
+    pub fn from_document(
+        config: &'cfg Config,
+        bytes: &[u8],
+        buffer: &'b mut Vec<u8>,
+    ) -> Result<Self, ::Error> {
+        ::read_body(bytes, buffer, config)?;
+        Ok(Deserializer::new(config, buffer))
+    }
+
     pub fn new(config: &'cfg Config, input: &'b [u8]) -> Self {
         Deserializer {
             config: config,
             reader: Reader::new(input),
             seen: BTreeSet::new(),
+            tracked: BTreeSet::new(),
+            copy: false,
+            depth: 0,
         }
     }
-}
 
-impl<'cfg, 'a, 'de> de::Deserializer<'de> for &'a mut Deserializer<'cfg, 'de> {
-    type Error = Error;
+    /// Fail if any input is left after the values deserialized so far.
+    /// `FromSereal::from_sereal_with_config` calls this when the config
+    /// sets `require_eof`.
+    pub fn end(&self) -> Result<(), Error> {
+        if !self.reader.is_empty() {
+            return Err(Error::TrailingData { pos: self.reader.pos() });
+        }
+        Ok(())
+    }
+
+    /// Run `f` one nesting level deeper, failing past `max_depth`.
+    fn nested<T, F>(&mut self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Self) -> Result<T, Error>,
+    {
+        if self.depth >= self.config.max_depth() {
+            return Err(Error::DepthLimitExceeded { limit: self.config.max_depth() });
+        }
+
+        self.depth += 1;
+        let res = f(self);
+        self.depth -= 1;
+
+        res
+    }
+
+    /// Read the length of a `BINARY` or `STR_UTF8` payload.
+    fn read_len(&mut self) -> Result<usize, Error> {
+        let len = self.reader.read_len()?;
+        if len as u64 > self.config.max_string_len() {
+            return Err(Error::StringTooLarge {
+                len: len,
+                limit: self.config.max_string_len(),
+            });
+        }
+        Ok(len)
+    }
+
+    fn check_array_size(&self, count: u64) -> Result<(), Error> {
+        if count > self.config.max_array_size() {
+            return Err(Error::ArrayTooLarge {
+                count: count,
+                limit: self.config.max_array_size(),
+            });
+        }
+        Ok(())
+    }
+
+    fn check_hash_size(&self, count: u64) -> Result<(), Error> {
+        if count > self.config.max_hash_size() {
+            return Err(Error::HashTooLarge {
+                count: count,
+                limit: self.config.max_hash_size(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Read the next tag and return it without the track bit, remembering
+    /// the offset of tracked values as targets for `REFP` and `ALIAS`.
+    /// Reserved tags are skipped if the config allows them.
+    fn read_tag(&mut self) -> Result<u8, Error> {
+        loop {
+            let tag = self.reader.read_tag()?;
+            match tag & TYPE_MASK {
+                RESERVED_0...RESERVED_4 if self.config.allow_unknown_tags() => continue,
+                _ => (),
+            }
+
+            if tag & TRACK_BIT != 0 {
+                self.tracked.insert(self.reader.pos());
+            }
+            return Ok(tag & TYPE_MASK);
+        }
+    }
+
+    /// Read the sub-tag and payload following `EXTEND` with the handler the
+    /// config registers for it.
+    fn read_extension(&mut self) -> Result<Extension, Error> {
+        let sel = self.reader.read_u8()?;
+        match self.config.extension(sel) {
+            Some(handler) => Ok(handler(&mut self.reader)?),
+            None => Err(Error::UnsupportedExtension(sel)),
+        }
+    }
+
+    /// Run `f` with the reader positioned at the tracked value at offset
+    /// `p`, then return to the current position.
+    fn with_target<T, F>(&mut self, p: usize, at: usize, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Self) -> Result<T, Error>,
+    {
+        if !self.tracked.contains(&p) || self.seen.contains(&p) || p >= self.reader.pos() {
+            return Err(Error::InvalidRef { target: p, at: at });
+        }
+
+        self.seen.insert(p);
+        let prev = self.reader.set_pos(p - 1);
+
+        let res = f(self);
+
+        self.reader.set_pos(prev);
+        self.seen.remove(&p);
+
+        res
+    }
+
+    /// Read the next value as a 128-bit integer if it is an integer tag.
+    /// Anything else is left unread.
+    fn read_wide(&mut self) -> Result<Option<i128>, Error> {
+        let pos = self.reader.pos();
+        let n = match self.read_tag()? {
+            tag @ POS_0...POS_15 => tag as i128,
+            tag @ NEG_16...NEG_1 => (tag | 0xf0) as i8 as i128,
+            VARINT => self.reader.read_varint()? as i128,
+            ZIGZAG => self.reader.read_zigzag()? as i128,
+            _ => {
+                self.reader.set_pos(pos);
+                return Ok(None);
+            }
+        };
+        Ok(Some(n))
+    }
+
+    fn read_str(&mut self) -> Result<&'b [u8], Error> {
+        let tag = self.read_tag()?;
+        let at = self.reader.pos();
+
+        match tag {
+            BINARY | STR_UTF8 => {
+                let len = self.read_len()?;
+                Ok(self.reader.read_bytes(len)?)
+            }
+
+            SHORT_BINARY_0...SHORT_BINARY_31 => {
+                let len = tag - SHORT_BINARY_0;
+                Ok(self.reader.read_bytes(len as usize)?)
+            }
+
+            COPY => {
+                let p = self.reader.read_varlen()?;
+
+                if self.copy {
+                    return Err(Error::InvalidCopy { target: p, at: at });
+                }
+
+                self.copy = true;
+                let res = self.read_str_at(p, at);
+                self.copy = false;
+
+                res
+            }
+
+            _ => Err(Error::InvalidType),
+        }
+    }
+
+    fn visit_str<V: de::Visitor<'b>>(&self, tag: u8, s: &'b [u8], visitor: V) -> Result<V::Value, Error> {
+        match str::from_utf8(s) {
+            Ok(s) if tag == STR_UTF8 => visitor.visit_borrowed_str(s),
+            _ => visitor.visit_borrowed_bytes(s),
+        }
+    }
+
+    fn read_str_at(&mut self, p: usize, at: usize) -> Result<&'b [u8], Error> {
+        if p == 0 || p >= self.reader.pos() {
+            return Err(Error::InvalidRef { target: p, at: at });
+        }
+
+        let prev = self.reader.set_pos(p - 1);
+        let res = self.read_str();
+        self.reader.set_pos(prev);
+
+        res
+    }
+
+    fn visit_object<V: de::Visitor<'b>>(
+        &mut self,
+        class: &'b [u8],
+        frozen: bool,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        if self.config.expose_object_class() {
+            let class = str::from_utf8(class).map_err(|_| Error::InvalidType)?;
+            visitor.visit_enum(Object::new(self, class, frozen))
+        } else {
+            de::Deserializer::deserialize_any(self, visitor)
+        }
+    }
+
+    /// Deserialize a value the visitor cannot take as undef. With
+    /// `reject_undef_in_required` set, an `UNDEF` here is an error instead
+    /// of a call to `visit_none`.
+    fn deserialize_defined<V: de::Visitor<'b>>(&mut self, visitor: V) -> Result<V::Value, Error> {
+        if self.config.reject_undef_in_required() {
+            let pos = self.reader.pos();
+            let undef = self.is_undef()?;
+            self.reader.set_pos(pos);
+
+            if undef {
+                self.read_tag()?;
+                return Err(Error::UnexpectedUndef { at: self.reader.pos() });
+            }
+        }
+
+        de::Deserializer::deserialize_any(self, visitor)
+    }
+
+    /// Read the next value far enough to tell whether it is undef, following
+    /// `REFP`, `ALIAS` and `COPY` to their targets. Targets always precede
+    /// the tag pointing at them, so this ends; invalid ones are left for the
+    /// caller to report.
+    fn is_undef(&mut self) -> Result<bool, Error> {
+        loop {
+            match self.read_tag()? {
+                UNDEF | CANONICAL_UNDEF => return Ok(true),
+
+                REFP | ALIAS | COPY => {
+                    let p = self.reader.read_varlen()?;
+                    if p == 0 || p >= self.reader.pos() {
+                        return Ok(false);
+                    }
+                    self.reader.set_pos(p - 1);
+                }
+
+                _ => return Ok(false),
+            }
+        }
+    }
+
+    /// Deserialize an integer target. Untracked `POS`, `NEG`, `VARINT` and
+    /// `ZIGZAG` tags, which make up most numeric data, are decoded here
+    /// without going through `read_tag`; anything else, including `PAD`
+    /// and tagged values, takes the general path.
+    #[inline]
+    fn deserialize_int<V: de::Visitor<'b>>(&mut self, visitor: V) -> Result<V::Value, Error> {
+        let res: Result<V::Value, Error> = match self.reader.next_if(|tag| tag <= ZIGZAG) {
+            Some(tag @ POS_0...POS_15) => visitor.visit_u8(tag),
+            Some(tag @ NEG_16...NEG_1) => visitor.visit_i8((tag | 0xf0) as i8),
+            Some(VARINT) => match self.reader.read_varint() {
+                Ok(v) => visitor.visit_u64(v),
+                Err(e) => Err(e.into()),
+            },
+            Some(ZIGZAG) => match self.reader.read_zigzag() {
+                Ok(v) => visitor.visit_i64(v),
+                Err(e) => Err(e.into()),
+            },
+            _ => return self.deserialize_defined(visitor),
+        };
+        res.map_err(|e| e.at(self.reader.pos()))
+    }
+
+    /// Read the next value as the text of a decimal integer if it is a
+    /// string, for targets wider than the 64 bits `VARINT` can hold.
+    /// Anything else is left unread.
+    fn read_decimal(&mut self) -> Result<Option<&'b str>, Error> {
+        let pos = self.reader.pos();
+        match self.read_tag()? {
+            BINARY | STR_UTF8 | SHORT_BINARY_0...SHORT_BINARY_31 | COPY => (),
+            _ => {
+                self.reader.set_pos(pos);
+                return Ok(None);
+            }
+        }
+
+        self.reader.set_pos(pos);
+        let s = self.read_str()?;
+        str::from_utf8(s).map(Some).map_err(|_| Error::InvalidType)
+    }
+
+    /// Visit an array as a sequence of exactly `len` elements. Other
+    /// values are passed to `deserialize_defined` unchanged.
+    fn visit_tuple<V: de::Visitor<'b>>(&mut self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        let pos = self.reader.pos();
+        let tag = self.read_tag()?;
+
+        let count = match tag {
+            ARRAY => self.reader.read_varint()?,
+            ARRAYREF_0...ARRAYREF_15 => (tag - ARRAYREF_0) as u64,
+            _ => {
+                self.reader.set_pos(pos);
+                return self.deserialize_defined(visitor);
+            }
+        };
+
+        if count != len as u64 {
+            return Err(de::Error::invalid_length(count as usize, &visitor));
+        }
+        self.check_array_size(count)?;
+
+        self.nested(|de| visitor.visit_seq(Seq::new(de, count)))
+    }
+
+    /// Advance past the next value without building it. Nested values are
+    /// counted rather than recursed into, and back-references are not
+    /// followed, so their targets are not validated either.
+    fn skip(&mut self) -> Result<(), Error> {
+        let mut pending: u64 = 1;
+
+        while pending > 0 {
+            pending -= 1;
+
+            let tag = self.read_tag()?;
+            let children = self.skip_body(tag)?;
+            pending = pending.saturating_add(children);
+        }
+
+        Ok(())
+    }
+
+    /// Skip the payload following `tag` and return how many values nested
+    /// inside it remain to be skipped.
+    fn skip_body(&mut self, tag: u8) -> Result<u64, Error> {
+        match tag {
+            POS_0...NEG_1 | UNDEF | CANONICAL_UNDEF | TRUE | FALSE => (),
+
+            VARINT | ZIGZAG => {
+                self.reader.read_varint()?;
+            }
+
+            FLOAT => {
+                self.reader.read_bytes(4)?;
+            }
+
+            DOUBLE => {
+                self.reader.read_bytes(8)?;
+            }
+
+            LONG_DOUBLE => {
+                self.reader.read_bytes(16)?;
+            }
+
+            BINARY | STR_UTF8 => {
+                let len = self.reader.read_len()?;
+                self.reader.read_bytes(len)?;
+            }
+
+            SHORT_BINARY_0...SHORT_BINARY_31 => {
+                self.reader.read_bytes((tag - SHORT_BINARY_0) as usize)?;
+            }
+
+            REFP | ALIAS | COPY => {
+                self.reader.read_varlen()?;
+            }
+
+            REFN | WEAKEN => return Ok(1),
+
+            OBJECT | OBJECT_FREEZE | REGEXP => return Ok(2),
+
+            OBJECTV | OBJECTV_FREEZE => {
+                self.reader.read_varlen()?;
+                return Ok(1);
+            }
+
+            ARRAY => return Ok(self.reader.read_varint()?),
+            ARRAYREF_0...ARRAYREF_15 => return Ok((tag - ARRAYREF_0) as u64),
+
+            HASH => return Ok(self.reader.read_varint()?.saturating_mul(2)),
+            HASHREF_0...HASHREF_15 => return Ok((tag - HASHREF_0) as u64 * 2),
+
+            MANY => {
+                let count = self.reader.read_varint()?;
+                match self.reader.read_u8()? & TYPE_MASK {
+                    // Elements without a payload take no bytes to skip.
+                    POS_0...NEG_1 | UNDEF | CANONICAL_UNDEF | TRUE | FALSE => (),
 
-    fn deserialize_any<V: de::Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, Error> {
-        let tag = self.reader.read_tag()? & TYPE_MASK;
+                    // Every other element takes at least a byte, so a count
+                    // past the end of the input is cut short before looping.
+                    elem @ VARINT...DOUBLE | elem @ BINARY...STR_UTF8 => {
+                        if count > self.reader.remaining() as u64 {
+                            return Err(Error::UnexpectedEof);
+                        }
+                        for _ in 0..count {
+                            self.skip_body(elem)?;
+                        }
+                    }
+
+                    _ => return Err(Error::InvalidType),
+                }
+            }
+
+            EXTEND => {
+                self.read_extension()?;
+            }
+
+            _ => return Err(Error::UnknownTag(tag)),
+        }
+
+        Ok(0)
+    }
+
+    /// Body of `deserialize_any`, which adds the position to its errors.
+    fn read_any<V: de::Visitor<'b>>(&mut self, visitor: V) -> Result<V::Value, Error> {
+        let tag = self.read_tag()?;
+        let at = self.reader.pos();
 
         match tag {
             POS_0...POS_15 => visitor.visit_u8(tag),
             NEG_16...NEG_1 => visitor.visit_i8((tag | 0xf0) as i8),
+            VARINT => visitor.visit_u64(self.reader.read_varint()?),
+            ZIGZAG => visitor.visit_i64(self.reader.read_zigzag()?),
             FLOAT => visitor.visit_f32(self.reader.read_f32()?),
             DOUBLE => visitor.visit_f64(self.reader.read_f64()?),
 
             BINARY | STR_UTF8 => {
-                let len = self.reader.read_varlen()?;
-                visitor.visit_borrowed_bytes(self.reader.read_bytes(len)?)
+                let len = self.read_len()?;
+                let s = self.reader.read_bytes(len)?;
+                self.visit_str(tag, s, visitor)
             }
 
             SHORT_BINARY_0...SHORT_BINARY_31 => {
@@ -111,14 +658,34 @@ impl<'cfg, 'a, 'de> de::Deserializer<'de> for &'a mut Deserializer<'cfg, 'de> {
                 visitor.visit_borrowed_bytes(self.reader.read_bytes(len as usize)?)
             }
 
+            COPY => {
+                let p = self.reader.read_varlen()?;
+
+                if self.copy || p == 0 || p >= self.reader.pos() {
+                    return Err(Error::InvalidCopy { target: p, at: at });
+                }
+
+                self.copy = true;
+                let prev = self.reader.set_pos(p - 1);
+
+                let res = de::Deserializer::deserialize_any(&mut *self, visitor);
+
+                self.reader.set_pos(prev);
+                self.copy = false;
+
+                res
+            }
+
             ARRAY => {
                 let len = self.reader.read_varint()?;
+                self.check_array_size(len)?;
                 visitor.visit_seq(Seq::new(self, len))
             }
 
             ARRAYREF_0...ARRAYREF_15 => {
-                let len = tag - ARRAYREF_0;
-                visitor.visit_seq(Seq::new(self, len as u64))
+                let len = (tag - ARRAYREF_0) as u64;
+                self.check_array_size(len)?;
+                visitor.visit_seq(Seq::new(self, len))
             }
 
             UNDEF | CANONICAL_UNDEF => visitor.visit_none(),
@@ -127,120 +694,189 @@ impl<'cfg, 'a, 'de> de::Deserializer<'de> for &'a mut Deserializer<'cfg, 'de> {
 
             REFP => {
                 let p = self.reader.read_varlen()?;
+                self.with_target(p, at, |de| visitor.visit_some(de))
+            }
 
-                if self.seen.contains(&p) || p >= self.reader.pos() {
-                    return Err(Error::InvalidRef(p));
-                }
-
-                self.seen.insert(p);
-                let prev = self.reader.set_pos(p - 1);
-
-                let res = visitor.visit_some(&mut *self);
-
-                self.reader.set_pos(prev);
-                self.seen.remove(&p);
+            ALIAS => {
+                let p = self.reader.read_varlen()?;
+                self.with_target(p, at, |de| de::Deserializer::deserialize_any(de, visitor))
+            }
 
-                res
+            HASH => {
+                let len = self.reader.read_varint()?;
+                self.check_hash_size(len)?;
+                visitor.visit_map(Map::new(self, len))
             }
 
             HASHREF_0...HASHREF_15 => {
-                let len = tag - HASHREF_0;
-                visitor.visit_map(Map::new(self, len as u64))
+                let len = (tag - HASHREF_0) as u64;
+                self.check_hash_size(len)?;
+                visitor.visit_map(Map::new(self, len))
             }
 
-            _ => {
-                panic!(
-                    "tag type {tag} (0x{tag:02x}) not implemented yet",
-                    tag = tag
-                )
+            OBJECT | OBJECT_FREEZE => {
+                let class = self.read_str()?;
+                self.visit_object(class, tag == OBJECT_FREEZE, visitor)
+            }
+
+            OBJECTV | OBJECTV_FREEZE => {
+                let p = self.reader.read_varlen()?;
+                let class = self.read_str_at(p, at)?;
+                self.visit_object(class, tag == OBJECTV_FREEZE, visitor)
             }
+
+            EXTEND => match self.read_extension()? {
+                Extension::I64(v) => visitor.visit_i64(v),
+                Extension::U64(v) => visitor.visit_u64(v),
+                Extension::F32(v) => visitor.visit_f32(v),
+                Extension::F64(v) => visitor.visit_f64(v),
+            },
+
+            _ => Err(Error::UnknownTag(tag)),
         }
     }
+}
+
+impl<'cfg, 'a, 'de> de::Deserializer<'de> for &'a mut Deserializer<'cfg, 'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let res = self.nested(|de| de.read_any(visitor));
+        res.map_err(|e| e.at(self.reader.pos()))
+    }
 
     fn deserialize_bool<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_defined(v)
     }
     fn deserialize_u8<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_int(v)
     }
     fn deserialize_u16<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_int(v)
     }
     fn deserialize_u32<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_int(v)
     }
     fn deserialize_u64<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_int(v)
     }
     fn deserialize_i8<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_int(v)
     }
     fn deserialize_i16<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_int(v)
     }
     fn deserialize_i32<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_int(v)
     }
     fn deserialize_i64<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_int(v)
+    }
+    fn deserialize_i128<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
+        if let Some(s) = self.read_decimal()? {
+            return match s.parse() {
+                Ok(n) => v.visit_i128(n),
+                Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(s), &v)),
+            };
+        }
+        match self.read_wide()? {
+            Some(n) => v.visit_i128(n),
+            None => self.deserialize_defined(v),
+        }
+    }
+    fn deserialize_u128<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
+        if let Some(s) = self.read_decimal()? {
+            return match s.parse() {
+                Ok(n) => v.visit_u128(n),
+                Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(s), &v)),
+            };
+        }
+        match self.read_wide()? {
+            Some(n) if n >= 0 => v.visit_u128(n as u128),
+            Some(n) => Err(de::Error::invalid_value(de::Unexpected::Signed(n as i64), &v)),
+            None => self.deserialize_defined(v),
+        }
     }
     fn deserialize_f32<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        if !self.config.reject_lossy_float() {
+            return self.deserialize_defined(v);
+        }
+
+        let pos = self.reader.pos();
+        if self.read_tag()? != DOUBLE {
+            self.reader.set_pos(pos);
+            return self.deserialize_defined(v);
+        }
+
+        let f = self.reader.read_f64()?;
+        let narrow = f as f32;
+        if narrow as f64 != f && !f.is_nan() {
+            return Err(de::Error::invalid_value(de::Unexpected::Float(f), &v));
+        }
+
+        v.visit_f32(narrow)
     }
     fn deserialize_f64<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_defined(v)
     }
     fn deserialize_char<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_defined(v)
     }
     fn deserialize_str<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_defined(v)
     }
     fn deserialize_string<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_defined(v)
     }
     fn deserialize_bytes<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_defined(v)
     }
     fn deserialize_byte_buf<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_defined(v)
     }
     fn deserialize_option<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
         self.deserialize_any(v)
     }
     fn deserialize_unit<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        let pos = self.reader.pos();
+        match self.read_tag()? {
+            UNDEF | CANONICAL_UNDEF => v.visit_unit(),
+            _ => {
+                self.reader.set_pos(pos);
+                self.deserialize_any(v)
+            }
+        }
     }
     fn deserialize_unit_struct<V: de::Visitor<'de>>(
         self,
         _: &'static str,
         v: V,
     ) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_unit(v)
     }
     fn deserialize_newtype_struct<V: de::Visitor<'de>>(
         self,
         _: &'static str,
         v: V,
     ) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_defined(v)
     }
     fn deserialize_seq<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_defined(v)
     }
-    fn deserialize_tuple<V: de::Visitor<'de>>(self, _: usize, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+    fn deserialize_tuple<V: de::Visitor<'de>>(self, len: usize, v: V) -> Result<V::Value, Error> {
+        self.visit_tuple(len, v)
     }
     fn deserialize_tuple_struct<V: de::Visitor<'de>>(
         self,
         _: &'static str,
-        _: usize,
+        len: usize,
         v: V,
     ) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.visit_tuple(len, v)
     }
     fn deserialize_map<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_defined(v)
     }
     fn deserialize_struct<V: de::Visitor<'de>>(
         self,
@@ -248,7 +884,7 @@ impl<'cfg, 'a, 'de> de::Deserializer<'de> for &'a mut Deserializer<'cfg, 'de> {
         _: &'static [&'static str],
         v: V,
     ) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_defined(v)
     }
     fn deserialize_enum<V: de::Visitor<'de>>(
         self,
@@ -256,13 +892,14 @@ impl<'cfg, 'a, 'de> de::Deserializer<'de> for &'a mut Deserializer<'cfg, 'de> {
         _: &'static [&'static str],
         v: V,
     ) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_defined(v)
     }
     fn deserialize_identifier<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.deserialize_defined(v)
     }
     fn deserialize_ignored_any<V: de::Visitor<'de>>(self, v: V) -> Result<V::Value, Error> {
-        self.deserialize_any(v)
+        self.skip()?;
+        v.visit_unit()
     }
 }
 
@@ -293,22 +930,51 @@ impl<'de, 'a, 'cfg> de::SeqAccess<'de> for Seq<'a, 'cfg, 'de> {
 
         self.count -= 1;
 
-        Ok(Some(seed.deserialize(&mut *self.de)?))
+        let res = seed.deserialize(&mut *self.de);
+        Ok(Some(res.map_err(|e| e.at(self.de.reader.pos()))?))
     }
 }
 
+/// Hands out keys and values strictly in the order they appear in the
+/// document, so order-preserving maps such as `IndexMap` keep that order.
 struct Map<'a, 'cfg: 'a, 'de: 'a> {
     de: &'a mut Deserializer<'cfg, 'de>,
     count: u64,
+    // Keys seen so far, if duplicates are to be rejected.
+    keys: Option<HashSet<&'de [u8]>>,
 }
 
 impl<'a, 'cfg, 'de> Map<'a, 'cfg, 'de> {
     fn new(de: &'a mut Deserializer<'cfg, 'de>, count: u64) -> Map<'a, 'cfg, 'de> {
+        let keys = if de.config.reject_duplicate_keys() {
+            Some(HashSet::new())
+        } else {
+            None
+        };
+
         Map {
             de: de,
             count: count,
+            keys: keys,
         }
     }
+
+    /// Read the next key ahead of the visitor and fail if it was seen
+    /// before. Keys that are not strings are left for the visitor.
+    fn check_key(&mut self) -> Result<(), Error> {
+        if let Some(ref mut keys) = self.keys {
+            let pos = self.de.reader.pos();
+            let key = self.de.read_str();
+            self.de.reader.set_pos(pos);
+
+            if let Ok(key) = key {
+                if !keys.insert(key) {
+                    return Err(Error::DuplicateKey(key.to_vec()));
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<'de, 'a, 'cfg> de::MapAccess<'de> for Map<'a, 'cfg, 'de> {
@@ -324,20 +990,108 @@ impl<'de, 'a, 'cfg> de::MapAccess<'de> for Map<'a, 'cfg, 'de> {
 
         self.count -= 1;
 
-        Ok(Some(seed.deserialize(&mut *self.de)?))
+        let res = self.check_key().and_then(|_| seed.deserialize(&mut *self.de));
+        Ok(Some(res.map_err(|e| e.at(self.de.reader.pos()))?))
     }
 
     fn next_value_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, Error> {
-        Ok(seed.deserialize(&mut *self.de)?)
+        let res = seed.deserialize(&mut *self.de);
+        res.map_err(|e| e.at(self.de.reader.pos()))
+    }
+}
+
+struct Object<'a, 'cfg: 'a, 'de: 'a> {
+    de: &'a mut Deserializer<'cfg, 'de>,
+    class: &'de str,
+    frozen: bool,
+}
+
+impl<'a, 'cfg, 'de> Object<'a, 'cfg, 'de> {
+    fn new(
+        de: &'a mut Deserializer<'cfg, 'de>,
+        class: &'de str,
+        frozen: bool,
+    ) -> Object<'a, 'cfg, 'de> {
+        Object {
+            de: de,
+            class: class,
+            frozen: frozen,
+        }
+    }
+}
+
+impl<'de, 'a, 'cfg> de::EnumAccess<'de> for Object<'a, 'cfg, 'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<(T::Value, Self), Error> {
+        let variant = if self.frozen {
+            let name = format!("{}{}", FREEZE_PREFIX, self.class);
+            seed.deserialize(de::IntoDeserializer::<Error>::into_deserializer(name))?
+        } else {
+            seed.deserialize(BorrowedStrDeserializer::<Error>::new(self.class))?
+        };
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'a, 'cfg> de::VariantAccess<'de> for Object<'a, 'cfg, 'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        <de::IgnoredAny as de::Deserialize>::deserialize(self.de)?;
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self.de)
+    }
+
+    fn tuple_variant<V: de::Visitor<'de>>(self, _: usize, v: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_any(self.de, v)
+    }
+
+    fn struct_variant<V: de::Visitor<'de>>(
+        self,
+        _: &'static [&'static str],
+        v: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_any(self.de, v)
+    }
+}
+
+/// Decoding of complete documents, as in `Point::from_sereal(&bytes)`,
+/// without setting up a `Deserializer`. Implemented for every type that
+/// deserializes without borrowing from the input.
+pub trait FromSereal: for<'de> de::Deserialize<'de> {
+    /// Decode a document, header included, with the default config.
+    fn from_sereal(bytes: &[u8]) -> Result<Self, ::Error> {
+        Self::from_sereal_with_config(bytes, &Config::default())
+    }
+
+    fn from_sereal_with_config(bytes: &[u8], config: &Config) -> Result<Self, ::Error> {
+        let mut body = Vec::new();
+        let mut de = Deserializer::from_document(config, bytes, &mut body)?;
+        let value = Self::deserialize(&mut de)?;
+        if config.require_eof() {
+            de.end()?;
+        }
+        Ok(value)
     }
 }
 
+impl<T> FromSereal for T where T: for<'de> de::Deserialize<'de> {}
+
 #[cfg(test)]
 mod test {
     use std::collections::HashMap;
     use std::fmt::Debug;
 
     use serde::de::Deserialize;
+    use sereal_common::constants::{RESERVED_0, RESERVED_4};
 
     use config::Config;
     use super::Deserializer;
@@ -351,7 +1105,7 @@ mod test {
         }
 
         fn err(s: &'de [u8]) -> Error {
-            Self::de_res(s).unwrap_err()
+            Self::de_res(s).unwrap_err().into_inner()
         }
     }
 
@@ -366,6 +1120,120 @@ mod test {
     #[test]
     fn ints() {
         assert_eq!(u64::de(b"\x01"), 1);
+        assert_eq!(i8::de(b"\x10"), -16);
+        assert_eq!(
+            u64::de(b"\x20\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01"),
+            u64::MAX
+        );
+        assert_eq!(
+            i64::de(b"\x21\xfe\xff\xff\xff\xff\xff\xff\xff\xff\x01"),
+            i64::MAX
+        );
+        assert_eq!(
+            i64::de(b"\x21\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01"),
+            i64::MIN
+        );
+        match u64::err(b"\x20\x80\x80\x80\x80\x80\x80\x80\x80\x80\x80\x01") {
+            Error::VarintOverflow => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert!(u8::de_res(b"\x20\x80\x02").is_err());
+
+        assert_eq!(i64::de(b"\x21\x03"), -2);
+        assert_eq!(i32::de(b"\x21\x03"), -2);
+        // -2^40 does not fit in i32.
+        match i32::err(b"\x21\xff\xff\xff\xff\xff\x3f") {
+            Error::Custom(ref msg) => assert_eq!(
+                msg,
+                "invalid value: integer `-1099511627776`, expected i32"
+            ),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn ints_128() {
+        assert_eq!(u128::de(b"\x00"), 0);
+        assert_eq!(u128::de(b"\x0f"), 15);
+        assert_eq!(
+            u128::de(b"\x20\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01"),
+            u64::MAX as u128
+        );
+        assert_eq!(i128::de(b"\x10"), -16);
+        assert_eq!(
+            i128::de(b"\x20\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01"),
+            u64::MAX as i128
+        );
+        assert_eq!(
+            i128::de(b"\x21\xfe\xff\xff\xff\xff\xff\xff\xff\xff\x01"),
+            i64::MAX as i128
+        );
+        assert_eq!(
+            i128::de(b"\x21\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01"),
+            i64::MIN as i128
+        );
+        assert_eq!(Option::<u128>::de(b"\x28\x01"), Some(1));
+
+        assert!(u128::err(b"\x1f").to_string().contains("-1"));
+        match u128::err(b"\x20\x80\x80\x80\x80\x80\x80\x80\x80\x80\x80\x01") {
+            Error::VarintOverflow => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn padding() {
+        assert_eq!(u64::de(b"\x3f\x3f\x01"), 1);
+        assert_eq!(Vec::<u32>::de(b"\x3f\x42\x3f\x01\x3f\x02"), vec![1, 2]);
+    }
+
+    #[test]
+    fn error_position() {
+        use std::error::Error as StdError;
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct S {
+            foo: u32,
+            bar: String,
+        }
+
+        // "bar" is SHORT_BINARY_3 with only two bytes left.
+        let err = S::de_res(b"\x52\x63foo\x01\x63bar\x63ba").unwrap_err();
+        assert_eq!(err.position(), Some(11));
+        match err.inner() {
+            &Error::UnexpectedEof => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(format!("{}", err), "unexpected eof at 11");
+        match err.source().and_then(|e| e.downcast_ref::<Error>()) {
+            Some(&Error::UnexpectedEof) => (),
+            e => panic!("unexpected source {:?}", e),
+        }
+
+        // Errors from visitors get the position too.
+        let err = Vec::<u8>::de_res(b"\x42\x01\x20\xac\x02").unwrap_err();
+        assert_eq!(err.position(), Some(5));
+
+        // Positions recorded by the error itself are kept.
+        let err = Vec::<u32>::de_res(b"\x42\x07\x2f\x04").unwrap_err();
+        assert_eq!(err.position(), Some(3));
+    }
+
+    #[test]
+    fn int_fast_path() {
+        // PAD between the elements and before varints, which sends them
+        // through the general path. `benches/scalar_ints.rs` times the two
+        // paths against each other; its header lists the recorded timings.
+        assert_eq!(
+            Vec::<i64>::de(b"\x44\x01\x3f\x1f\x3f\x20\x80\x01\x21\x03"),
+            vec![1, -1, 128, -2]
+        );
+        // Tracked integers, and an ALIAS of one of them.
+        assert_eq!(Vec::<u8>::de(b"\x43\x81\xa0\x02\x2e\x02"), vec![1, 2, 1]);
+        // Non-integer tags still reach the visitor.
+        assert_eq!(Vec::<Option<u8>>::de(b"\x42\x25\x28\x01"), vec![None, Some(1)]);
+        assert!(u8::de_res(b"\x20").is_err());
+        assert!(u8::de_res(b"").is_err());
     }
 
     #[test]
@@ -386,6 +1254,51 @@ mod test {
         assert_eq!(HashMap::<u32, u32>::de(b"\x52\x01\x02\x03\x04\x05"), map);
     }
 
+    #[test]
+    fn ordered_maps() {
+        use indexmap::IndexMap;
+
+        fn items(map: &IndexMap<String, u32>) -> Vec<(&str, u32)> {
+            map.iter().map(|(k, &v)| (k.as_str(), v)).collect()
+        }
+
+        let expected = vec![("c", 1), ("a", 2), ("b", 3)];
+
+        let map = IndexMap::<String, u32>::de(b"\x53\x61c\x01\x61a\x02\x61b\x03");
+        assert_eq!(items(&map), expected);
+
+        let map = IndexMap::<String, u32>::de(b"\x2a\x03\x61c\x01\x61a\x02\x61b\x03");
+        assert_eq!(items(&map), expected);
+
+        let map = Option::<IndexMap<String, u32>>::de(b"\x28\x2a\x03\x61c\x01\x61a\x02\x61b\x03");
+        assert_eq!(items(&map.unwrap()), expected);
+    }
+
+    #[test]
+    fn floats() {
+        let d = b"\x23\x00\x00\x00\x00\x00\x00\xf8\x3f"; // 1.5
+        let lossy = b"\x23\x9a\x99\x99\x99\x99\x99\xb9\x3f"; // 0.1
+
+        assert_eq!(f32::de(d), 1.5);
+        assert_eq!(f32::de(lossy), 0.1);
+        assert_eq!(f64::de(b"\x22\x00\x00\xc0\x3f"), 1.5);
+
+        let config = Config::default().with_reject_lossy_float(true);
+        let strict = |s: &[u8]| f32::deserialize(&mut Deserializer::new(&config, s));
+
+        assert_eq!(strict(d).unwrap(), 1.5);
+        assert_eq!(strict(b"\x22\x00\x00\xc0\x3f").unwrap(), 1.5);
+        assert_eq!(strict(b"\x01").unwrap(), 1.0);
+        assert!(strict(b"\x23\x00\x00\x00\x00\x00\x00\xf8\x7f").unwrap().is_nan());
+        match strict(lossy).unwrap_err() {
+            Error::Custom(ref msg) => assert!(msg.starts_with("invalid value"), "{}", msg),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let v = f64::deserialize(&mut Deserializer::new(&config, lossy)).unwrap();
+        assert_eq!(v, 0.1);
+    }
+
     #[test]
     fn tuples() {
         #[derive(Deserialize, PartialEq, Debug)]
@@ -395,6 +1308,24 @@ mod test {
             S::de(b"\x43\x01\x02\x43\x03\x04\x05"),
             S(1, 2, vec![3, 4, 5])
         );
+        assert!(S::de_res(b"\x42\x01\x02").is_err());
+
+        assert_eq!(<(u32, u32)>::de(b"\x42\x01\x02"), (1, 2));
+        assert_eq!(<(u32, u32)>::de(b"\x2b\x02\x01\x02"), (1, 2));
+
+        for s in &[&b"\x43\x01\x02\x03"[..], b"\x41\x01", b"\x2b\x00"] {
+            match <(u32, u32)>::err(s) {
+                Error::Custom(ref msg) => assert!(msg.starts_with("invalid length"), "{}", msg),
+                e => panic!("unexpected error {:?}", e),
+            }
+        }
+
+        match <(u32, u32, u32)>::err(b"\x42\x01\x02") {
+            Error::Custom(ref msg) => {
+                assert_eq!(msg, "invalid length 2, expected a tuple of size 3")
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
     }
 
     #[test]
@@ -416,13 +1347,54 @@ mod test {
         );
     }
 
+    #[test]
+    fn undef_in_required() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct S {
+            foo: u32,
+            bar: Option<u32>,
+        }
+
+        let config = Config::default().with_reject_undef_in_required(true);
+        let strict = |s: &[u8]| S::deserialize(&mut Deserializer::new(&config, s));
+
+        let s = b"\x52\x63foo\x25\x63bar\x25";
+        match S::err(s) {
+            Error::Custom(ref msg) => assert!(msg.starts_with("invalid type"), "{}", msg),
+            e => panic!("unexpected error {:?}", e),
+        }
+        match strict(s).unwrap_err() {
+            Error::UnexpectedUndef { at } => assert_eq!(at, 6),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let s = b"\x52\x63foo\x01\x63bar\x25";
+        assert_eq!(strict(s).unwrap(), S { foo: 1, bar: None });
+
+        // Undef reached through ALIAS, COPY and REFP.
+        for &s in &[
+            &b"\x52\x63bar\xa5\x63foo\x2e\x06"[..],
+            &b"\x52\x63bar\x25\x63foo\x2f\x06"[..],
+            &b"\x52\x63bar\xa5\x63foo\x29\x06"[..],
+        ] {
+            match strict(s).unwrap_err() {
+                Error::UnexpectedUndef { at } => assert_eq!(at, 11),
+                e => panic!("unexpected error {:?}", e),
+            }
+        }
+
+        assert_eq!(<()>::de(b"\x25"), ());
+        assert!(<()>::deserialize(&mut Deserializer::new(&config, b"\x25")).is_ok());
+        assert!(<Vec<u32>>::deserialize(&mut Deserializer::new(&config, b"\x25")).is_err());
+    }
+
     #[test]
     fn refs() {
         #[derive(Deserialize, Debug, PartialEq, Clone)]
         struct S {
             f: Option<Box<S>>,
             g: Option<Box<S>>,
-        };
+        }
 
         let s = Some(Box::new(S { f: None, g: None }));
 
@@ -443,6 +1415,306 @@ mod test {
         );
         assert_eq!(S::err(b"\x42\x29\x01\x28\x50").as_invalid_ref(), Some(1));
         assert_eq!(S::err(b"\x42\x28\x50\x29\x01").as_invalid_ref(), Some(1));
+        assert_eq!(S::err(b"\x42\x28\x50\x29\x00").as_invalid_ref(), Some(0));
+
+        match S::err(b"\x42\x28\x50\x29\x05") {
+            Error::InvalidRef { target: 5, at: 4 } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn copies() {
+        use std::borrow::Cow;
+
+        #[derive(Deserialize, Debug)]
+        struct S<'a> {
+            #[serde(borrow)]
+            a: Cow<'a, str>,
+            #[serde(borrow)]
+            b: Cow<'a, str>,
+        }
+
+        // { a => "foo", b => COPY of "foo" }
+        let s = b"\x52\x61a\x27\x03foo\x61b\x2f\x04";
+        let v = S::de(s);
+
+        match (&v.a, &v.b) {
+            (&Cow::Borrowed(a), &Cow::Borrowed(b)) => {
+                assert_eq!(b, "foo");
+                assert_eq!(a.as_ptr(), s[5..].as_ptr());
+                assert_eq!(b.as_ptr(), s[5..].as_ptr());
+            }
+            _ => panic!("unexpected allocation: {:?}", v),
+        }
+
+        assert_eq!(Vec::<u32>::de(b"\x43\x07\x2f\x02\x2f\x02"), vec![7, 7, 7]);
+
+        match Vec::<u32>::de_res(b"\x42\x07\x2f\x04") {
+            Err(Error::InvalidCopy { target: 4, at: 3 }) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match Vec::<u32>::de_res(b"\x43\x07\x2f\x02\x2f\x03") {
+            Err(Error::InvalidCopy { target: 2, at: 3 }) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // Copies of themselves where a string is expected.
+        match u128::err(b"\x2f\x01") {
+            Error::InvalidCopy { target: 1, at: 1 } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+        match u32::err(b"\x2c\x2f\x02\x01") {
+            Error::InvalidCopy { target: 2, at: 2 } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn tracked() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct S {
+            a: u32,
+            b: Option<u32>,
+            c: u32,
+        }
+
+        // { a => $x, b => \$x, c => $x } with $x = 1 tracked at offset 4
+        let s = b"\x53\x61a\x81\x61b\x29\x04\x61c\x2e\x04";
+        assert_eq!(S::de(s), S { a: 1, b: Some(1), c: 1 });
+
+        // the same without the track bit
+        match S::err(b"\x53\x61a\x01\x61b\x29\x04\x61c\x2e\x04") {
+            Error::InvalidRef { target: 4, at: 7 } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // targets inside skipped values are recorded too
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct T {
+            c: u32,
+        }
+        assert_eq!(T::de(b"\x52\x61a\x41\x81\x61c\x2e\x05"), T { c: 1 });
+    }
+
+    #[test]
+    fn ignored_fields() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct S {
+            foo: u32,
+        }
+
+        // { big => [ "x" x 100_000 ], foo => 1 }
+        let mut s = b"\x52\x63big\x2b\xa0\x8d\x06".to_vec();
+        for _ in 0..100_000 {
+            s.extend_from_slice(b"\x61x");
+        }
+        s.extend_from_slice(b"\x63foo\x01");
+        assert_eq!(S::de(&s), S { foo: 1 });
+
+        // Ignored values may use tags the deserializer itself does not
+        // support yet.
+        assert_eq!(
+            S::de(b"\x52\x63bar\x2a\x01\x61k\x3c\x02\x3b\x63foo\x02"),
+            S { foo: 2 }
+        );
+        assert_eq!(
+            S::de(b"\x52\x63bar\x2c\x61C\x30\x28\x23\x00\x00\x00\x00\x00\x00\xf8\x3f\x63foo\x03"),
+            S { foo: 3 }
+        );
+
+        match S::err(b"\x51\x63bar\x43\x01\x02") {
+            Error::UnexpectedEof => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+        match S::err(b"\x51\x63bar\x3c\x01\x28") {
+            Error::InvalidType => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+        match S::err(b"\x51\x63bar\x3c\x01\x29\x01") {
+            Error::InvalidType => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // MANY with a huge count does not loop over it.
+        assert_eq!(
+            S::de(b"\x52\x63bar\x3c\xff\xff\xff\xff\x0f\x25\x63foo\x04"),
+            S { foo: 4 }
+        );
+        match S::err(b"\x51\x63bar\x3c\xff\xff\xff\xff\x0f\x20\x01") {
+            Error::UnexpectedEof => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(S::err(b"\x51\x63bar\x41\x34").as_unknown_tag(), Some(RESERVED_0));
+    }
+
+    #[test]
+    fn unknown_tags() {
+        for tag in RESERVED_0..RESERVED_4 + 1 {
+            assert_eq!(u64::err(&[tag]).as_unknown_tag(), Some(tag));
+        }
+
+        let config = Config::default().with_allow_unknown_tags(true);
+        let de = |s| Vec::<u64>::deserialize(&mut Deserializer::new(&config, s));
+        assert_eq!(de(b"\x34\x42\x01\xb8\x02").unwrap(), vec![1, 2]);
+        assert_eq!(de(b"\x42\x01\x24").unwrap_err().as_unknown_tag(), Some(0x24));
+    }
+
+    #[test]
+    fn extend() {
+        assert_eq!(u64::err(b"\x3e\x01").as_unsupported_extension(), Some(1));
+        match u64::err(b"\x3e") {
+            Error::UnexpectedEof => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct S {
+            a: f32,
+        }
+
+        let config = Config::default().with_extension(0x01, ::parser::half_float);
+        let f = f32::deserialize(&mut Deserializer::new(&config, b"\x3e\x01\x00\x3c"));
+        assert_eq!(f.unwrap(), 1.0);
+
+        // The extension payload is skipped for the unknown field `b`.
+        let s = b"\x52\x61b\x3e\x01\x00\x3c\x61a\x3e\x01\x00\xc0";
+        assert_eq!(S::deserialize(&mut Deserializer::new(&config, s)).unwrap(), S { a: -2.0 });
+    }
+
+    #[test]
+    fn wide_integers() {
+        assert_eq!(
+            u128::de(b"\x27\x27340282366920938463463374607431768211455"),
+            u128::MAX
+        );
+        assert_eq!(u128::de(b"\x6a1234567890"), 1234567890);
+        assert_eq!(u128::de(b"\x0a"), 10);
+        assert_eq!(
+            u128::de(b"\x20\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01"),
+            u64::MAX as u128
+        );
+        assert_eq!(i128::de(b"\x6b-1234567890"), -1234567890);
+        assert_eq!(i128::de(b"\x1f"), -1);
+        assert_eq!(<(u128, u128)>::de(b"\x42\x6212\x2f\x02"), (12, 12));
+
+        assert!(u128::err(b"\x63foo").to_string().contains("foo"));
+        assert!(u128::err(b"\x62-1").to_string().contains("-1"));
+        match u128::err(b"\x62\xff\xff") {
+            Error::InvalidType => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn borrow_bytes() {
+        let d = b"\x66foobar";
+        let s = <&[u8]>::de(&d[..]);
+
+        assert_eq!(s, b"foobar");
+        assert_eq!(s.as_ptr(), d[1..].as_ptr());
+    }
+
+    #[test]
+    fn byte_buf() {
+        use std::fmt;
+        use serde::de::{Deserializer as SerdeDeserializer, Visitor};
+
+        #[derive(Debug, PartialEq)]
+        struct ByteBuf(Vec<u8>);
+
+        struct ByteBufVisitor;
+
+        impl<'de> Visitor<'de> for ByteBufVisitor {
+            type Value = ByteBuf;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "byte array")
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<ByteBuf, E> {
+                Ok(ByteBuf(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<ByteBuf, E> {
+                Ok(ByteBuf(v))
+            }
+        }
+
+        impl<'de> Deserialize<'de> for ByteBuf {
+            fn deserialize<D: SerdeDeserializer<'de>>(d: D) -> Result<ByteBuf, D::Error> {
+                d.deserialize_byte_buf(ByteBufVisitor)
+            }
+        }
+
+        assert_eq!(ByteBuf::de(b"\x63foo"), ByteBuf(b"foo".to_vec()));
+        assert_eq!(ByteBuf::de(b"\x26\x03bar"), ByteBuf(b"bar".to_vec()));
+    }
+
+    #[test]
+    fn objects() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct S {
+            a: u32,
+        }
+
+        assert_eq!(S::de(b"\x2c\x63Foo\x51\x61a\x01"), S { a: 1 });
+        assert_eq!(
+            Vec::<S>::de(b"\x42\x2c\x63Foo\x51\x61a\x01\x2d\x03\x51\x61a\x02"),
+            vec![S { a: 1 }, S { a: 2 }]
+        );
+        assert_eq!(
+            Vec::<S>::err(b"\x42\x2d\x03\x51\x61a\x01").as_invalid_ref(),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn objects_with_class() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        enum E {
+            Foo { a: u32 },
+            Bar(Vec<u32>),
+        }
+
+        let config = Config::default().with_expose_object_class(true);
+        let de = |s| Vec::<E>::deserialize(&mut Deserializer::new(&config, s));
+
+        assert_eq!(
+            de(b"\x42\x2c\x63Foo\x51\x61a\x01\x2c\x63Bar\x41\x02").unwrap(),
+            vec![E::Foo { a: 1 }, E::Bar(vec![2])]
+        );
+        assert_eq!(
+            de(b"\x42\x2c\x63Foo\x51\x61a\x01\x2d\x03\x51\x61a\x02").unwrap(),
+            vec![E::Foo { a: 1 }, E::Foo { a: 2 }]
+        );
+        assert!(de(b"\x41\x2c\x63Baz\x50").is_err());
+    }
+
+    #[test]
+    fn frozen_objects() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        enum E {
+            Foo(Vec<u32>),
+            #[serde(rename = "FREEZE:Foo")]
+            FrozenFoo(Vec<u32>),
+        }
+
+        // [ OBJECT_FREEZE Foo [1], OBJECTV_FREEZE 3 [2] ]
+        let s = b"\x42\x32\x63Foo\x41\x01\x33\x03\x41\x02";
+        assert_eq!(Vec::<Vec<u32>>::de(s), vec![vec![1], vec![2]]);
+
+        let config = Config::default().with_expose_object_class(true);
+        let de = |s| Vec::<E>::deserialize(&mut Deserializer::new(&config, s));
+
+        assert_eq!(
+            de(s).unwrap(),
+            vec![E::FrozenFoo(vec![1]), E::FrozenFoo(vec![2])]
+        );
+        assert_eq!(
+            de(b"\x42\x2c\x63Foo\x41\x01\x33\x03\x41\x02").unwrap(),
+            vec![E::Foo(vec![1]), E::FrozenFoo(vec![2])]
+        );
     }
 
     #[test]
@@ -465,5 +1737,132 @@ mod test {
                 c: "baz",
             }
         );
+        assert_eq!(s.b.as_ptr(), d[6..].as_ptr());
+
+        // Bytes borrow too, and a string that cannot be borrowed as &str
+        // is an error rather than a silent copy.
+        let d = b"\x42\x63foo\x26\x03bar";
+        let v = <Vec<&[u8]>>::de(&d[..]);
+        assert_eq!(v, vec![&b"foo"[..], &b"bar"[..]]);
+        assert_eq!(v[1].as_ptr(), d[7..].as_ptr());
+
+        match <&str>::err(b"\x62\xc3\x28") {
+            Error::Custom(ref msg) => assert!(msg.starts_with("invalid value"), "{}", msg),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn constructors() {
+        let config = Config::default();
+        let mut de = Deserializer::from_body(&config, b"\x42\x01\x02");
+        assert_eq!(Vec::<u8>::deserialize(&mut de).unwrap(), vec![1, 2]);
+
+        let mut buf = Vec::new();
+        let doc = b"=\xf3rl\x04\x00\x42\x01\x63foo";
+        let mut de = Deserializer::from_document(&config, doc, &mut buf).unwrap();
+        assert_eq!(<(u8, &str)>::deserialize(&mut de).unwrap(), (1, "foo"));
+
+        let mut buf = Vec::new();
+        match Deserializer::from_document(&config, b"\x42\x01\x02", &mut buf) {
+            Err(::Error::Header(_)) => (),
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+
+        let body = b"\x42\x01\x63foo";
+        let mut doc = b"=\xf3rl\x23\x00\x08".to_vec();
+        doc.extend(&[0x06, 0x14]);
+        doc.extend(&body[..]);
+
+        let mut buf = Vec::new();
+        let res = Deserializer::from_document(&config, &doc, &mut buf);
+        #[cfg(feature = "comp-snappy")]
+        assert_eq!(<(u8, &str)>::deserialize(&mut res.unwrap()).unwrap(), (1, "foo"));
+        #[cfg(not(feature = "comp-snappy"))]
+        match res {
+            Err(::Error::CompressionUnsupported { .. }) => (),
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    #[test]
+    fn from_sereal() {
+        use de::FromSereal;
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Point {
+            x: u32,
+            y: u32,
+        }
+
+        let doc = b"=\xf3rl\x04\x00\x52\x61x\x01\x61y\x02";
+        assert_eq!(Point::from_sereal(doc).unwrap(), Point { x: 1, y: 2 });
+        assert_eq!(<Vec<Point>>::from_sereal(b"=\xf3rl\x04\x00\x40").unwrap(), vec![]);
+
+        match Point::from_sereal(b"=\xf3rl\x04\x00\x01") {
+            Err(::Error::Deserialize(_)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match Point::from_sereal(b"\x52\x61x\x01\x61y\x02") {
+            Err(::Error::Header(_)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // Trailing input is only an error if the config requires EOF.
+        let doc = b"=\xf3rl\x04\x00\x01\x02";
+        assert_eq!(u8::from_sereal(doc).unwrap(), 1);
+        match u8::from_sereal_with_config(doc, &Config::for_untrusted()) {
+            Err(::Error::Deserialize(ref e)) if e.position() == Some(1) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn limits() {
+        fn de_with<'a, T: Deserialize<'a>>(config: &Config, s: &'a [u8]) -> Result<T, Error> {
+            T::deserialize(&mut Deserializer::new(config, s)).map_err(Error::into_inner)
+        }
+
+        let config = Config::default().with_max_depth(2);
+        assert_eq!(de_with::<Vec<Vec<u8>>>(&config, b"\x41\x41\x01").unwrap(), vec![vec![1]]);
+        match de_with::<Vec<Vec<Vec<u8>>>>(&config, b"\x41\x41\x41\x01") {
+            Err(Error::DepthLimitExceeded { limit: 2 }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let config = Config::default().with_max_array_size(2);
+        match de_with::<Vec<u8>>(&config, b"\x43\x01\x02\x03") {
+            Err(Error::ArrayTooLarge { count: 3, limit: 2 }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let config = Config::default().with_max_hash_size(1);
+        match de_with::<HashMap<String, u8>>(&config, b"\x52\x61a\x01\x61b\x02") {
+            Err(Error::HashTooLarge { count: 2, limit: 1 }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let config = Config::default().with_max_string_len(2);
+        match de_with::<String>(&config, b"\x27\x03abc") {
+            Err(Error::StringTooLarge { len: 3, limit: 2 }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        // { a => 1, a => 2 }, the second key a COPY of the first
+        let s = b"\x52\x61a\x01\x2f\x02\x02";
+        let map = HashMap::<String, u8>::de(s);
+        assert_eq!(map.get("a"), Some(&2));
+
+        let config = Config::default().with_reject_duplicate_keys(true);
+        let res = HashMap::<String, u8>::deserialize(&mut Deserializer::new(&config, s));
+        match res.map_err(Error::into_inner) {
+            Err(Error::DuplicateKey(ref key)) if key == b"a" => (),
+            other => panic!("unexpected result {:?}", other),
+        }
     }
 }
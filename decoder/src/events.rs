@@ -0,0 +1,576 @@
+//! Decoding a body into a flat list of events instead of a value tree.
+
+use std::str;
+
+use sereal_common::constants::*;
+
+use config::Config;
+use parser::{Error, Extension, Result, VALUE_COST};
+use reader::Reader;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Container {
+    Array,
+    Hash,
+}
+
+/// One step of a depth-first walk over a body.
+///
+/// A container is a `Begin` with its entry count, the entries and an `End`;
+/// each hash entry is a `Key` followed by the value. `Ref`, `WeakRef` and
+/// `Object` apply to the single value that follows them, so `ARRAYREF_N`
+/// and `HASHREF_N` appear as a `Ref` followed by the container. `COPY` is
+/// replaced by the events of the value it copies.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event<'buf> {
+    /// The next value has the track bit set, and `RefTo` and `Alias` can
+    /// refer to it by this offset.
+    Track(usize),
+    Undef,
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    Binary(&'buf [u8]),
+    String(&'buf [u8]),
+    Begin(Container, u64),
+    Key(&'buf [u8]),
+    End,
+    Ref,
+    WeakRef,
+    /// A `REFP` to the tracked value at an offset.
+    RefTo(usize),
+    /// An `ALIAS` of the tracked value at an offset.
+    Alias(usize),
+    Object { class: &'buf [u8], freeze: bool },
+    Regexp { pattern: &'buf [u8], flags: &'buf [u8] },
+}
+
+/// Decode `body` into events without building any values.
+///
+/// The config's depth, array, hash, string and total size limits apply,
+/// the last estimated as the parser does. So do
+/// `strict_utf8`, `allow_unknown_tags`, its extension handlers and
+/// `require_eof`. References are reported by offset and not checked.
+pub fn decode_events<'buf>(body: &'buf [u8], config: &Config) -> Result<Vec<Event<'buf>>> {
+    let mut dec = Decoder {
+        config: config,
+        reader: Reader::new(body),
+        events: Vec::new(),
+        depth: 0,
+        copy_pos: 0,
+        total_bytes: 0,
+    };
+
+    dec.value()?;
+    if config.require_eof() && !dec.reader.is_empty() {
+        return Err(Error::TrailingData { pos: dec.reader.pos() });
+    }
+
+    Ok(dec.events)
+}
+
+struct Decoder<'a, 'buf> {
+    config: &'a Config,
+    reader: Reader<'buf>,
+    events: Vec<Event<'buf>>,
+    depth: u64,
+    // Position to return to after a `COPY`, or 0 outside of one.
+    copy_pos: usize,
+    total_bytes: u64,
+}
+
+impl<'a, 'buf> Decoder<'a, 'buf> {
+    fn read_tag(&mut self) -> Result<u8> {
+        loop {
+            let tag = self.reader.read_tag()?;
+            match tag & TYPE_MASK {
+                RESERVED_0...RESERVED_4 if self.config.allow_unknown_tags() => (),
+                _ => return Ok(tag),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > self.config.max_depth() {
+            return Err(Error::DepthLimitExceeded { limit: self.config.max_depth() });
+        }
+
+        let tag = self.read_tag()?;
+        self.charge(VALUE_COST)?;
+        if tag & TRACK_BIT != 0 {
+            self.events.push(Event::Track(self.reader.pos()));
+        }
+
+        let tag = tag & TYPE_MASK;
+        match tag {
+            REFN => {
+                self.events.push(Event::Ref);
+                self.value()?;
+            }
+
+            WEAKEN => {
+                self.events.push(Event::WeakRef);
+                self.value()?;
+            }
+
+            REFP => {
+                let p = self.reader.read_varlen()?;
+                self.events.push(Event::RefTo(p));
+            }
+
+            ALIAS => {
+                let p = self.reader.read_varlen()?;
+                self.events.push(Event::Alias(p));
+            }
+
+            COPY => self.do_copy(|d| d.value())?,
+
+            ARRAY => {
+                let len = self.reader.read_varint()?;
+                self.array(len)?;
+            }
+
+            ARRAYREF_0...ARRAYREF_15 => {
+                self.events.push(Event::Ref);
+                self.array((tag - ARRAYREF_0) as u64)?;
+            }
+
+            HASH => {
+                let len = self.reader.read_varint()?;
+                self.hash(len)?;
+            }
+
+            HASHREF_0...HASHREF_15 => {
+                self.events.push(Event::Ref);
+                self.hash((tag - HASHREF_0) as u64)?;
+            }
+
+            MANY => {
+                let len = self.reader.read_varint()?;
+                self.check_array_size(len)?;
+
+                let elem = self.reader.read_u8()? & TYPE_MASK;
+                match elem {
+                    UNDEF | CANONICAL_UNDEF | TRUE | FALSE => (),
+                    POS_0...NEG_1 | VARINT | ZIGZAG | FLOAT | DOUBLE => (),
+                    BINARY | STR_UTF8 => (),
+                    _ => return Err(Error::InvalidType),
+                }
+
+                self.events.push(Event::Begin(Container::Array, len));
+                for _ in 0..len {
+                    self.charge(VALUE_COST)?;
+                    let event = self.scalar(elem)?;
+                    self.events.push(event);
+                }
+                self.events.push(Event::End);
+            }
+
+            OBJECT | OBJECT_FREEZE => {
+                let class = self.read_str()?;
+                self.object(class, tag == OBJECT_FREEZE)?;
+            }
+
+            OBJECTV | OBJECTV_FREEZE => {
+                let p = self.reader.read_varlen()?;
+                if p == 0 || p >= self.reader.pos() {
+                    return Err(Error::InvalidRef(p));
+                }
+                let prev = self.reader.set_pos(p - 1);
+                let class = self.read_str();
+                self.reader.set_pos(prev);
+                self.object(class?, tag == OBJECTV_FREEZE)?;
+            }
+
+            REGEXP => {
+                let pattern = self.read_str()?;
+                let flags = self.read_str()?;
+                self.events.push(Event::Regexp {
+                    pattern: pattern,
+                    flags: flags,
+                });
+            }
+
+            _ => {
+                let event = self.scalar(tag)?;
+                self.events.push(event);
+            }
+        }
+
+        self.depth -= 1;
+        Ok(())
+    }
+
+    /// Decode a value that has no children, given its tag.
+    fn scalar(&mut self, tag: u8) -> Result<Event<'buf>> {
+        let event = match tag {
+            UNDEF | CANONICAL_UNDEF => Event::Undef,
+
+            POS_0...POS_15 => Event::U64(tag as u64),
+            NEG_16...NEG_1 => Event::I64((tag | 0xf0) as i8 as i64),
+
+            VARINT => Event::U64(self.reader.read_varint()?),
+            ZIGZAG => Event::I64(self.reader.read_zigzag()?),
+            FLOAT => Event::F32(self.reader.read_f32()?),
+            DOUBLE => Event::F64(self.reader.read_f64()?),
+
+            TRUE => Event::Bool(true),
+            FALSE => Event::Bool(false),
+
+            BINARY => {
+                let len = self.read_len(self.config.max_string_len())?;
+                Event::Binary(self.reader.read_bytes(len)?)
+            }
+
+            SHORT_BINARY_0...SHORT_BINARY_31 => {
+                let len = tag - SHORT_BINARY_0;
+                self.charge(len.into())?;
+                Event::Binary(self.reader.read_bytes(len as usize)?)
+            }
+
+            STR_UTF8 => {
+                let limit = self.config.max_string_len();
+                Event::String(self.read_utf8(limit, self.config.strict_utf8())?)
+            }
+
+            EXTEND => {
+                let sel = self.reader.read_u8()?;
+                let handler = self.config.extension(sel).ok_or(Error::UnsupportedExtension(sel))?;
+                match handler(&mut self.reader)? {
+                    Extension::I64(v) => Event::I64(v),
+                    Extension::U64(v) => Event::U64(v),
+                    Extension::F32(v) => Event::F32(v),
+                    Extension::F64(v) => Event::F64(v),
+                }
+            }
+
+            _ => return Err(Error::UnknownTag(tag)),
+        };
+
+        Ok(event)
+    }
+
+    fn array(&mut self, len: u64) -> Result<()> {
+        self.check_array_size(len)?;
+
+        self.events.push(Event::Begin(Container::Array, len));
+        for _ in 0..len {
+            self.value()?;
+        }
+        self.events.push(Event::End);
+        Ok(())
+    }
+
+    fn hash(&mut self, len: u64) -> Result<()> {
+        self.check_hash_size(len)?;
+
+        self.events.push(Event::Begin(Container::Hash, len));
+        for _ in 0..len {
+            let key = self.read_key()?;
+            self.events.push(Event::Key(key));
+            self.value()?;
+        }
+        self.events.push(Event::End);
+        Ok(())
+    }
+
+    fn object(&mut self, class: &'buf [u8], freeze: bool) -> Result<()> {
+        self.events.push(Event::Object {
+            class: class,
+            freeze: freeze,
+        });
+        self.value()
+    }
+
+    fn check_array_size(&mut self, len: u64) -> Result<()> {
+        if len > self.config.max_array_size() {
+            return Err(Error::ArrayTooLarge {
+                count: len,
+                limit: self.config.max_array_size(),
+            });
+        }
+        self.charge(len.saturating_mul(VALUE_COST))
+    }
+
+    fn check_hash_size(&mut self, len: u64) -> Result<()> {
+        if len > self.config.max_hash_size() {
+            return Err(Error::HashTooLarge {
+                count: len,
+                limit: self.config.max_hash_size(),
+            });
+        }
+        self.charge(len.saturating_mul(VALUE_COST))
+    }
+
+    /// Add `bytes` to the estimated size of the decoded document.
+    fn charge(&mut self, bytes: u64) -> Result<()> {
+        self.total_bytes = self.total_bytes.saturating_add(bytes);
+        if self.total_bytes > self.config.max_total_bytes() {
+            return Err(Error::TotalSizeExceeded { limit: self.config.max_total_bytes() });
+        }
+        Ok(())
+    }
+
+    /// Read a hash key. As in the parser, `STR_UTF8` keys are always
+    /// validated.
+    fn read_key(&mut self) -> Result<&'buf [u8]> {
+        let tag = self.read_tag()? & TYPE_MASK;
+        let limit = self.config.max_key_len();
+
+        match tag {
+            SHORT_BINARY_0...SHORT_BINARY_31 => {
+                let len = (tag - SHORT_BINARY_0) as usize;
+                self.check_len(len, limit)?;
+                Ok(self.reader.read_bytes(len)?)
+            }
+
+            BINARY => {
+                let len = self.read_len(limit)?;
+                Ok(self.reader.read_bytes(len)?)
+            }
+
+            STR_UTF8 => self.read_utf8(limit, true),
+
+            COPY => self.do_copy(|d| d.read_key()),
+
+            REFP => {
+                let p = self.reader.read_varlen()?;
+                self.read_key_ref(p)
+            }
+
+            _ => Err(Error::InvalidType),
+        }
+    }
+
+    /// Read the key a `REFP` key points to. As in the parser, the target
+    /// must be a string tag with the track bit set.
+    fn read_key_ref(&mut self, p: usize) -> Result<&'buf [u8]> {
+        if p == 0 || p >= self.reader.pos() {
+            return Err(Error::InvalidRef(p));
+        }
+
+        let prev = self.reader.set_pos(p - 1);
+        let res = match self.reader.read_u8() {
+            Ok(tag) if tag & TRACK_BIT == 0 => Err(Error::InvalidRef(p)),
+            Ok(tag) => match tag & TYPE_MASK {
+                SHORT_BINARY_0...SHORT_BINARY_31 | BINARY | STR_UTF8 => {
+                    self.reader.set_pos(p - 1);
+                    self.read_key()
+                }
+                _ => Err(Error::InvalidType),
+            },
+            Err(e) => Err(e.into()),
+        };
+        self.reader.set_pos(prev);
+
+        res
+    }
+
+    /// Read a class name, regexp pattern or regexp flags.
+    fn read_str(&mut self) -> Result<&'buf [u8]> {
+        let tag = self.read_tag()? & TYPE_MASK;
+        if tag == COPY {
+            return self.do_copy(|d| d.read_str());
+        }
+
+        match self.scalar(tag)? {
+            Event::Binary(s) | Event::String(s) => Ok(s),
+            _ => Err(Error::InvalidType),
+        }
+    }
+
+    fn read_len(&mut self, limit: u64) -> Result<usize> {
+        let len = self.reader.read_varlen()?;
+        self.check_len(len, limit)?;
+        Ok(len)
+    }
+
+    fn check_len(&mut self, len: usize, limit: u64) -> Result<()> {
+        if len as u64 > limit {
+            return Err(Error::StringTooLarge {
+                len: len,
+                limit: limit,
+            });
+        }
+        self.charge(len as u64)
+    }
+
+    fn read_utf8(&mut self, limit: u64, strict: bool) -> Result<&'buf [u8]> {
+        let len = self.read_len(limit)?;
+        let pos = self.reader.pos();
+        let s = self.reader.read_bytes(len)?;
+
+        if strict && str::from_utf8(s).is_err() {
+            return Err(Error::InvalidUtf8 { pos: pos });
+        }
+
+        Ok(s)
+    }
+
+    fn do_copy<T, F: FnOnce(&mut Self) -> Result<T>>(&mut self, f: F) -> Result<T> {
+        if self.copy_pos != 0 {
+            return Err(Error::InvalidCopy);
+        }
+
+        let pos = self.reader.read_varlen()?;
+        if pos == 0 || pos >= self.reader.pos() {
+            return Err(Error::InvalidCopy);
+        }
+
+        self.copy_pos = self.reader.set_pos(pos - 1);
+
+        let val = f(self);
+
+        self.reader.set_pos(self.copy_pos);
+        self.copy_pos = 0;
+
+        val
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use config::Config;
+    use events::{decode_events, Container, Event};
+    use events::Event::*;
+    use parser::Error;
+
+    fn ev<'a>(s: &'a [u8]) -> Vec<Event<'a>> {
+        decode_events(s, &Config::default()).unwrap()
+    }
+
+    #[test]
+    fn nested() {
+        // { a => [1, -1, "x"], b => \{} }
+        let s = b"\x2a\x02\x61a\x43\x01\x1f\x61x\x61b\x50";
+        assert_eq!(
+            ev(s),
+            vec![
+                Begin(Container::Hash, 2),
+                Key(b"a"),
+                Ref,
+                Begin(Container::Array, 3),
+                U64(1),
+                I64(-1),
+                Binary(b"x"),
+                End,
+                Key(b"b"),
+                Ref,
+                Begin(Container::Hash, 0),
+                End,
+                End,
+            ]
+        );
+    }
+
+    #[test]
+    fn refs_and_objects() {
+        // [ \@a, \@a ] where @a = (1)
+        assert_eq!(
+            ev(b"\x42\x28\xab\x01\x01\x29\x03"),
+            vec![
+                Ref,
+                Begin(Container::Array, 2),
+                Ref,
+                Track(3),
+                Begin(Container::Array, 1),
+                U64(1),
+                End,
+                RefTo(3),
+                End,
+            ]
+        );
+
+        // [ bless(\1, "foo"), bless(\2, "foo") ] with copied and reused class
+        assert_eq!(
+            ev(b"\x42\x2c\x63foo\x28\x01\x2d\x03\x28\x02"),
+            vec![
+                Ref,
+                Begin(Container::Array, 2),
+                Object { class: b"foo", freeze: false },
+                Ref,
+                U64(1),
+                Object { class: b"foo", freeze: false },
+                Ref,
+                U64(2),
+                End,
+            ]
+        );
+
+        assert_eq!(
+            ev(b"\x42\x63foo\x2f\x02"),
+            vec![Ref, Begin(Container::Array, 2), Binary(b"foo"), Binary(b"foo"), End]
+        );
+        assert_eq!(
+            ev(b"\x31\x63a.b\x61i"),
+            vec![Regexp { pattern: b"a.b", flags: b"i" }]
+        );
+        assert_eq!(
+            ev(b"\x3c\x03\x20\x01\x80\x01\x00"),
+            vec![Begin(Container::Array, 3), U64(1), U64(128), U64(0), End]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let config = Config::default().with_max_depth(2);
+        assert!(decode_events(b"\x28\x28\x01", &config).is_err());
+        assert!(decode_events(b"\x2b\x02\x01", &Config::default()).unwrap_err().is_eof());
+        assert!(decode_events(b"\x34", &Config::default()).unwrap_err().is_unknown_tag());
+        assert!(decode_events(b"\x2d\x05\x01", &Config::default()).is_err());
+    }
+
+    #[test]
+    fn refp_keys() {
+        // [ { a => 1 }, { a => 2 } ] with the second key referring to the first
+        assert_eq!(
+            ev(b"\x42\x2a\x01\xe1a\x01\x2a\x01\x29\x04\x02"),
+            vec![
+                Ref,
+                Begin(Container::Array, 2),
+                Begin(Container::Hash, 1),
+                Key(b"a"),
+                U64(1),
+                End,
+                Begin(Container::Hash, 1),
+                Key(b"a"),
+                U64(2),
+                End,
+                End,
+            ]
+        );
+
+        // The target is not tracked, or not a string.
+        let config = Config::default();
+        assert!(decode_events(b"\x42\x2a\x01\x61a\x01\x2a\x01\x29\x04\x02", &config).is_err());
+        assert!(decode_events(b"\x42\x81\x2a\x01\x29\x02\x02", &config).is_err());
+    }
+
+    #[test]
+    fn total_bytes() {
+        // Charged as by the parser: see `arc::test::test_total_bytes`.
+        let mut s = b"\x2b\x64".to_vec();
+        for _ in 0..100 {
+            s.extend_from_slice(b"\x68abcdefgh");
+        }
+
+        let config = Config::default().with_max_total_bytes(4016);
+        assert!(decode_events(&s, &config).is_ok());
+
+        let config = Config::default().with_max_total_bytes(4000);
+        match decode_events(&s, &config) {
+            Err(Error::TotalSizeExceeded { limit: 4000 }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // Hash slots count before any entry is read.
+        let config = Config::default().with_max_total_bytes(100);
+        match decode_events(b"\x2a\x10", &config) {
+            Err(Error::TotalSizeExceeded { limit: 100 }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,396 @@
+//! Flattening a body into `(path, scalar)` pairs, for search and indexing.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::string;
+
+use config::Config;
+use parser::{self, Parser};
+pub use parser::Error;
+pub use parser::Result;
+
+/// A leaf of the document, borrowing strings from the body.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Scalar<'buf> {
+    Undef,
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    Binary(&'buf [u8]),
+    String(&'buf [u8]),
+}
+
+/// Path of a leaf in the style of a JSON pointer: array indices and hash
+/// keys, each preceded by `/`, with `~` and `/` in keys written as `~0`
+/// and `~1`. A document that is a single scalar has the empty path.
+pub type Path = string::String;
+
+pub type Leaves<'buf> = Vec<(Path, Scalar<'buf>)>;
+
+/// The leaves below a value, with paths relative to it.
+///
+/// References, weak references and aliases are transparent, so a value
+/// reached through `REFP` or `ALIAS` is flattened again at every place it
+/// appears. Objects contribute the leaves of the blessed value and lose
+/// their class; a regexp is its pattern. Empty arrays and hashes have no
+/// leaves at all.
+///
+/// The leaves are shared between clones, since the parser records
+/// reference targets before their contents are known.
+#[derive(Clone, Debug)]
+pub struct Value<'buf> {
+    // `None` until the parser has finished the value.
+    leaves: Rc<RefCell<Option<Leaves<'buf>>>>,
+    state: Rc<State>,
+}
+
+impl<'buf> Value<'buf> {
+    /// The leaves, copied only if the value is still shared. Fails with
+    /// the first error met while resolving references so far.
+    pub fn into_leaves(self) -> Result<Leaves<'buf>> {
+        self.state.check()?;
+        let leaves = match Rc::try_unwrap(self.leaves) {
+            Ok(cell) => cell.into_inner(),
+            Err(rc) => rc.borrow().clone(),
+        };
+        Ok(leaves.unwrap_or_default())
+    }
+
+    fn set(&mut self, s: Scalar<'buf>) {
+        self.state.charge(1);
+        self.set_leaves(vec![(Path::new(), s)]);
+    }
+
+    fn set_leaves(&mut self, leaves: Leaves<'buf>) {
+        *self.leaves.borrow_mut() = Some(leaves);
+    }
+
+    /// Take the leaves of `o`. If `o` is shared they are copied, and the
+    /// copy counts against the leaf limit; past the limit it is skipped.
+    fn copy_from(&mut self, o: Self) {
+        let leaves = match Rc::try_unwrap(o.leaves) {
+            Ok(cell) => cell.into_inner(),
+            Err(rc) => {
+                let len = rc.borrow().as_ref().map_or(0, |leaves| leaves.len());
+                if !self.state.charge(len as u64) {
+                    return;
+                }
+                rc.borrow().clone()
+            }
+        };
+
+        match leaves {
+            Some(leaves) => self.set_leaves(leaves),
+            // Only a value that encloses this one can be referenced before
+            // it is finished, and its leaves are not known yet.
+            None => self.state.fail(Error::InvalidRef(self.state.target.get())),
+        }
+    }
+}
+
+/// Shared by a `FlattenBuilder` and its values. `Value`'s setters cannot
+/// fail, so the first error they meet is kept here and reported by
+/// `Value::into_leaves`.
+#[derive(Debug)]
+struct State {
+    limit: u64,
+    leaves: Cell<u64>,
+    // Offset of the reference target being resolved.
+    target: Cell<usize>,
+    error: RefCell<Option<Error>>,
+}
+
+impl State {
+    /// Count `n` more leaves. Returns `false`, and records the error, once
+    /// the count is over the limit.
+    fn charge(&self, n: u64) -> bool {
+        let leaves = self.leaves.get().saturating_add(n);
+        self.leaves.set(leaves);
+        if leaves > self.limit {
+            self.fail(Error::TooManyLeaves { limit: self.limit });
+            return false;
+        }
+        true
+    }
+
+    fn fail(&self, e: Error) {
+        let mut error = self.error.borrow_mut();
+        if error.is_none() {
+            *error = Some(e);
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        match self.error.borrow_mut().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'buf> parser::Value<'buf> for Value<'buf> {
+    type Array = Leaves<'buf>;
+    type Hash = Leaves<'buf>;
+
+    fn set_undef(&mut self) {
+        self.set(Scalar::Undef);
+    }
+
+    fn set_true(&mut self) {
+        self.set(Scalar::Bool(true));
+    }
+
+    fn set_false(&mut self) {
+        self.set(Scalar::Bool(false));
+    }
+
+    fn set_i64(&mut self, v: i64) {
+        self.set(Scalar::I64(v));
+    }
+
+    fn set_u64(&mut self, v: u64) {
+        self.set(Scalar::U64(v));
+    }
+
+    fn set_f32(&mut self, v: f32) {
+        self.set(Scalar::F32(v));
+    }
+
+    fn set_f64(&mut self, v: f64) {
+        self.set(Scalar::F64(v));
+    }
+
+    fn set_ref(&mut self, o: Self) {
+        self.copy_from(o);
+    }
+
+    fn set_weak_ref(&mut self, o: Self) {
+        self.copy_from(o);
+    }
+
+    fn set_alias(&mut self, o: Self) {
+        self.copy_from(o);
+    }
+
+    fn set_array(&mut self, leaves: Self::Array) {
+        self.set_leaves(leaves);
+    }
+
+    fn set_hash(&mut self, leaves: Self::Hash) {
+        self.set_leaves(leaves);
+    }
+
+    fn set_binary(&mut self, s: &'buf [u8]) {
+        self.set(Scalar::Binary(s));
+    }
+
+    fn set_string(&mut self, s: &'buf [u8]) {
+        self.set(Scalar::String(s));
+    }
+
+    fn set_object(&mut self, _: Self, value: Self) -> Result<()> {
+        self.copy_from(value);
+        self.state.check()
+    }
+
+    fn set_object_freeze(&mut self, class: Self, value: Self) -> Result<()> {
+        self.set_object(class, value)
+    }
+
+    fn set_regexp(&mut self, pattern: Self, _: Self) -> Result<()> {
+        self.copy_from(pattern);
+        self.state.check()
+    }
+}
+
+/// Collects the leaves of the entries of an array or hash, prefixing
+/// their paths with the entry's index or key.
+pub struct PathBuilder<'buf> {
+    leaves: Leaves<'buf>,
+    index: u64,
+}
+
+impl<'buf> PathBuilder<'buf> {
+    fn push(&mut self, step: &str, value: Value<'buf>) -> Result<()> {
+        for (path, s) in value.into_leaves()? {
+            let mut full = Path::with_capacity(1 + step.len() + path.len());
+            full.push('/');
+            full.push_str(step);
+            full.push_str(&path);
+            self.leaves.push((full, s));
+        }
+        Ok(())
+    }
+}
+
+impl<'buf> parser::ArrayBuilder<'buf, Value<'buf>> for PathBuilder<'buf> {
+    fn insert(&mut self, value: Value<'buf>) -> Result<()> {
+        let step = self.index.to_string();
+        self.push(&step, value)?;
+        self.index += 1;
+        Ok(())
+    }
+
+    fn finalize(self) -> Leaves<'buf> {
+        self.leaves
+    }
+}
+
+impl<'buf> parser::HashBuilder<'buf, Value<'buf>> for PathBuilder<'buf> {
+    fn insert(&mut self, key: &'buf [u8], value: Value<'buf>) -> Result<()> {
+        let step = string::String::from_utf8_lossy(key)
+            .replace('~', "~0")
+            .replace('/', "~1");
+        self.push(&step, value)
+    }
+
+    fn finalize(self) -> Leaves<'buf> {
+        self.leaves
+    }
+}
+
+/// Builds `Value`s, counting their leaves against the config's
+/// `max_leaves`: the leaves form one flat list, which would otherwise
+/// grow exponentially with chains of references to references.
+pub struct FlattenBuilder {
+    state: Rc<State>,
+}
+
+impl FlattenBuilder {
+    pub fn new(config: &Config) -> FlattenBuilder {
+        FlattenBuilder {
+            state: Rc::new(State {
+                limit: config.max_leaves(),
+                leaves: Cell::new(0),
+                target: Cell::new(0),
+                error: RefCell::new(None),
+            }),
+        }
+    }
+}
+
+impl<'buf> parser::Builder<'buf> for FlattenBuilder {
+    type Value = Value<'buf>;
+    type ArrayBuilder = PathBuilder<'buf>;
+    type HashBuilder = PathBuilder<'buf>;
+
+    fn new(&mut self) -> Value<'buf> {
+        Value {
+            leaves: Rc::default(),
+            state: self.state.clone(),
+        }
+    }
+
+    fn build_array(&mut self, _: u64) -> PathBuilder<'buf> {
+        PathBuilder {
+            leaves: Vec::new(),
+            index: 0,
+        }
+    }
+
+    fn build_hash(&mut self, _: u64) -> PathBuilder<'buf> {
+        PathBuilder {
+            leaves: Vec::new(),
+            index: 0,
+        }
+    }
+
+    fn on_reference(&mut self, target: usize) {
+        self.state.target.set(target);
+    }
+}
+
+/// Parse `s` into its leaves in document order.
+pub fn parse_flat(s: &[u8]) -> Result<Leaves<'_>> {
+    parse_flat_with_config(s, &Config::default())
+}
+
+/// Same as `parse_flat`, with the limits of `config`. Its `max_leaves`
+/// caps the number of leaves, counting every scalar and every leaf copied
+/// again for a reference. A reference to a value that encloses it fails
+/// with `InvalidRef`, since that value has no leaves yet.
+pub fn parse_flat_with_config<'buf>(s: &'buf [u8], config: &Config) -> Result<Leaves<'buf>> {
+    let mut p = Parser::new(FlattenBuilder::new(config), config, s);
+    p.parse()?.into_leaves()
+}
+
+#[cfg(test)]
+mod test {
+    use config::Config;
+    use flat::{parse_flat, parse_flat_with_config, Scalar};
+    use parser::Error;
+
+    // An array of `levels` tracked array refs, each but the first holding
+    // two `REFP`s to the one before it, so every level doubles the leaves.
+    fn doubling(levels: u8) -> Vec<u8> {
+        let mut s = vec![0x2b, levels, 0xc1, 0x01];
+        let mut prev = 3;
+        for _ in 1..levels {
+            let pos = s.len() as u8 + 1;
+            s.extend_from_slice(&[0xc2, 0x29, prev, 0x29, prev]);
+            prev = pos;
+        }
+        s
+    }
+
+    #[test]
+    fn test_hash_of_arrays() {
+        // { a => [1, "x"], b => [[-1]], "c/d~" => undef, e => [] }
+        let s = b"\x54\x61a\x42\x01\x61x\x61b\x41\x41\x1f\x64c/d~\x25\x61e\x40";
+        assert_eq!(
+            parse_flat(s).unwrap(),
+            vec![
+                ("/a/0".to_string(), Scalar::U64(1)),
+                ("/a/1".to_string(), Scalar::Binary(b"x")),
+                ("/b/0/0".to_string(), Scalar::I64(-1)),
+                ("/c~1d~0".to_string(), Scalar::Undef),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scalar() {
+        assert_eq!(
+            parse_flat(b"\x27\x03foo").unwrap(),
+            vec![("".to_string(), Scalar::String(b"foo"))]
+        );
+    }
+
+    #[test]
+    fn test_refs() {
+        // [ {a => 1}, REFP to the hash, ALIAS of the 1 ]
+        let s = b"\x43\xd1\x61a\x81\x29\x02\x2e\x05";
+        assert_eq!(
+            parse_flat(s).unwrap(),
+            vec![
+                ("/0/a".to_string(), Scalar::U64(1)),
+                ("/1/a".to_string(), Scalar::U64(1)),
+                ("/2".to_string(), Scalar::U64(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(parse_flat(b"\x42\x01").unwrap_err().is_eof());
+
+        // [ REFP to the array itself ]
+        match parse_flat(b"\xc1\x29\x01") {
+            Err(Error::InvalidRef(1)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_leaf_limit() {
+        let config = Config::default().with_max_leaves(1000);
+        assert_eq!(parse_flat_with_config(&doubling(5), &config).unwrap().len(), 31);
+
+        match parse_flat_with_config(&doubling(20), &config) {
+            Err(Error::TooManyLeaves { limit: 1000 }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
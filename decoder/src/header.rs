@@ -1,3 +1,5 @@
+use std::error;
+use std::fmt;
 use std::io;
 use std::result;
 use byteorder::{LittleEndian, ReadBytesExt};
@@ -12,42 +14,68 @@ pub enum Error {
     InvalidVersion,
     InvalidType,
     SuffixTooLarge,
+    /// Input ended inside the varint holding `field`.
+    TruncatedVarint { field: &'static str },
     IOError(io::Error),
 }
 
 impl Error {
     pub fn is_invalid_magic(&self) -> bool {
-        match self {
-            &Error::InvalidMagic => true,
-            _ => false,
-        }
+        matches!(*self, Error::InvalidMagic)
     }
 
     pub fn is_invalid_version(&self) -> bool {
-        match self {
-            &Error::InvalidVersion => true,
-            _ => false,
-        }
+        matches!(*self, Error::InvalidVersion)
     }
 
     pub fn is_invalid_type(&self) -> bool {
-        match self {
-            &Error::InvalidType => true,
-            _ => false,
-        }
+        matches!(*self, Error::InvalidType)
     }
 
     pub fn is_suffix_too_large(&self) -> bool {
+        matches!(*self, Error::SuffixTooLarge)
+    }
+
+    /// Name of the header field whose varint was cut off.
+    pub fn as_truncated_varint(&self) -> Option<&'static str> {
         match self {
-            &Error::SuffixTooLarge => true,
-            _ => false,
+            &Error::TruncatedVarint { field } => Some(field),
+            _ => None,
         }
     }
 
     pub fn is_io_error(&self) -> bool {
-        match self {
-            &Error::IOError(_) => true,
-            _ => false,
+        matches!(*self, Error::IOError(_))
+    }
+}
+
+impl fmt::Display for Error {
+    #[allow(deprecated)]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::IOError(ref e) => write!(f, "{}", e),
+            Error::TruncatedVarint { field } => write!(f, "header truncated in {}", field),
+            _ => write!(f, "{}", error::Error::description(self)),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::InvalidMagic => "invalid magic",
+            Error::InvalidVersion => "invalid version",
+            Error::InvalidType => "invalid document type",
+            Error::SuffixTooLarge => "header suffix too large",
+            Error::TruncatedVarint { .. } => "truncated header",
+            Error::IOError(_) => "i/o error",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::IOError(ref e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -60,6 +88,13 @@ impl From<io::Error> for Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
+fn read_varint_field<R: io::Read>(reader: &mut R, field: &'static str) -> Result<u64> {
+    reader.read_varint().map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::TruncatedVarint { field: field },
+        _ => Error::IOError(e),
+    })
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
 pub enum DocumentType {
     Uncompressed,
@@ -71,8 +106,25 @@ pub enum DocumentType {
     ZStd { compressed_size: u64 },
 }
 
+impl DocumentType {
+    pub fn is_compressed(&self) -> bool {
+        *self != DocumentType::Uncompressed
+    }
+
+    /// Name of the codec the body is compressed with.
+    pub fn compression_name(&self) -> Option<&'static str> {
+        match *self {
+            DocumentType::Uncompressed => None,
+            DocumentType::Snappy { .. } => Some("snappy"),
+            DocumentType::ZLib { .. } => Some("zlib"),
+            DocumentType::ZStd { .. } => Some("zstd"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Header {
+    version: u8,
     doc_type: DocumentType,
     metadata: Option<Vec<u8>>,
 }
@@ -87,7 +139,7 @@ impl Header {
 
         let version_type = reader.read_u8()?;
 
-        let suffix_len = reader.read_varint()?;
+        let suffix_len = read_varint_field(reader, "suffix length")?;
         if suffix_len > config.max_suffix_len() {
             return Err(Error::SuffixTooLarge);
         }
@@ -97,7 +149,7 @@ impl Header {
             let size = suffix_len - 1;
 
             // Assumes that sizeof(u64) >= sizeof(usize) for all platforms.
-            if size > (usize::max_value() as u64) {
+            if size > (usize::MAX as u64) {
                 return Err(Error::SuffixTooLarge);
             }
 
@@ -124,33 +176,47 @@ impl Header {
             TYPE_RAW => DocumentType::Uncompressed,
 
             TYPE_SNAPPY if proto >= PROTO_V2 => {
-                DocumentType::Snappy { compressed_size: reader.read_varint()? }
+                DocumentType::Snappy {
+                    compressed_size: read_varint_field(reader, "compressed size")?,
+                }
             }
 
             TYPE_ZLIB if proto >= PROTO_V3 => {
                 DocumentType::ZLib {
-                    uncompressed_size: reader.read_varint()?,
-                    compressed_size: reader.read_varint()?,
+                    uncompressed_size: read_varint_field(reader, "uncompressed size")?,
+                    compressed_size: read_varint_field(reader, "compressed size")?,
                 }
             }
 
             TYPE_ZSTD if proto >= PROTO_V4 => {
-                DocumentType::ZStd { compressed_size: reader.read_varint()? }
+                DocumentType::ZStd {
+                    compressed_size: read_varint_field(reader, "compressed size")?,
+                }
             }
 
             _ => return Err(Error::InvalidType),
         };
 
         Ok(Header {
+            version: proto,
             doc_type: doctype,
             metadata: meta,
         })
     }
 
+    /// Protocol version, 2 to 4.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
     pub fn document_type(&self) -> DocumentType {
         self.doc_type
     }
 
+    pub fn is_compressed(&self) -> bool {
+        self.doc_type.is_compressed()
+    }
+
     pub fn user_metadata(&self) -> &Option<Vec<u8>> {
         &self.metadata
     }
@@ -212,6 +278,7 @@ mod test {
         assert_eq!(
             p(b"=srl\x02\x00"),
             Header {
+                version: 2,
                 doc_type: Uncompressed,
                 metadata: None,
             }
@@ -220,6 +287,7 @@ mod test {
         assert_eq!(
             p(b"=srl\x22\x02\x01\x00\x0a"),
             Header {
+                version: 2,
                 doc_type: Snappy { compressed_size: 10 },
                 metadata: Some(vec![0]),
             }
@@ -231,6 +299,7 @@ mod test {
         assert_eq!(
             p(b"=\xf3rl\x33\x02\x01\x00\x0a\x0b"),
             Header {
+                version: 3,
                 doc_type: ZLib {
                     uncompressed_size: 10,
                     compressed_size: 11,
@@ -245,6 +314,7 @@ mod test {
         assert_eq!(
             p(b"=\xf3rl\x04\x00"),
             Header {
+                version: 4,
                 doc_type: Uncompressed,
                 metadata: None,
             }
@@ -253,9 +323,61 @@ mod test {
         assert_eq!(
             p(b"=\xf3rl\x44\x00\x0a"),
             Header {
+                version: 4,
                 doc_type: ZStd { compressed_size: 10 },
                 metadata: None,
             }
         );
     }
+
+    #[test]
+    fn truncated() {
+        let inputs: &[(&[u8], &str)] = &[
+            (b"=\xf3rl\x04", "suffix length"),
+            (b"=\xf3rl\x04\x80", "suffix length"),
+            (b"=srl\x22\x00", "compressed size"),
+            (b"=\xf3rl\x34\x00", "uncompressed size"),
+            (b"=\xf3rl\x34\x00\x0a", "compressed size"),
+            (b"=\xf3rl\x34\x00\x0a\x80\x80", "compressed size"),
+            (b"=\xf3rl\x44\x02\x01\x00", "compressed size"),
+        ];
+
+        for &(s, field) in inputs {
+            assert_eq!(e(s).as_truncated_varint(), Some(field), "{:?}", s);
+        }
+
+        assert_eq!(
+            format!("{}", e(b"=\xf3rl\x44\x00")),
+            "header truncated in compressed size"
+        );
+
+        // fixed-size fields are still reported as i/o errors
+        assert!(e(b"=\xf3r").is_io_error());
+        assert!(e(b"=\xf3rl").is_io_error());
+        assert!(e(b"=\xf3rl\x44\x02").is_io_error());
+    }
+
+    #[test]
+    fn compression() {
+        let types = [
+            (Uncompressed, None),
+            (Snappy { compressed_size: 1 }, Some("snappy")),
+            (
+                ZLib {
+                    compressed_size: 1,
+                    uncompressed_size: 1,
+                },
+                Some("zlib"),
+            ),
+            (ZStd { compressed_size: 1 }, Some("zstd")),
+        ];
+
+        for &(ty, name) in &types {
+            assert_eq!(ty.compression_name(), name);
+            assert_eq!(ty.is_compressed(), name.is_some());
+        }
+
+        assert!(!p(b"=\xf3rl\x04\x00").is_compressed());
+        assert!(p(b"=\xf3rl\x44\x00\x0a").is_compressed());
+    }
 }
@@ -1,3 +1,7 @@
+#![allow(ellipsis_inclusive_range_patterns)]
+#![cfg_attr(test, allow(non_local_definitions, clippy::assertions_on_constants))]
+#![allow(clippy::redundant_field_names, clippy::match_ref_pats)]
+
 extern crate byteorder;
 extern crate typed_arena;
 extern crate sereal_common;
@@ -6,6 +10,8 @@ extern crate serde;
 #[cfg(test)]
 #[macro_use]
 extern crate serde_derive;
+#[cfg(any(test, feature = "indexmap"))]
+extern crate indexmap;
 
 #[cfg(feature = "comp-snappy")]
 extern crate snap;
@@ -14,6 +20,11 @@ extern crate flate2;
 #[cfg(feature = "comp-zstd")]
 extern crate zstd;
 
+#[cfg(feature = "smallvec")]
+extern crate smallvec;
+#[cfg(feature = "regex")]
+extern crate regex;
+
 pub mod config;
 pub mod header;
 pub mod parser;
@@ -21,12 +32,35 @@ pub mod reader;
 
 pub mod arc;
 pub mod arena;
+pub mod rc;
+pub mod null;
+pub mod count;
+pub mod stats;
 pub mod de;
+pub mod ser;
+pub mod events;
+pub mod flat;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
+#[cfg(feature = "regex")]
+pub mod regexp;
 
+mod cell;
+mod crc32;
 mod varint;
 
+/// Errors of `reader::Reader`, for embedders driving one directly or
+/// writing an `ExtensionHandler`.
+pub use reader::Error as ReaderError;
+
+use std::cmp;
+use std::error;
+use std::fmt;
 use std::io;
 use std::io::Read;
+use std::mem;
+
+use sereal_common::constants::{MAGIC_V1, MAGIC_V3, PACKET_START};
 
 use config::Config;
 use header::Header;
@@ -41,6 +75,104 @@ pub enum Error {
     Parser(parser::Error),
     BodyTooLarge { size: u64, limit: u64 },
     UnsupportedType(DocumentType),
+    /// The document is compressed with a codec whose cargo feature,
+    /// named by `feature`, was not enabled at build time.
+    CompressionUnsupported {
+        document_type: DocumentType,
+        feature: &'static str,
+    },
+    ChecksumMissing,
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// The input ended `available` bytes into a compressed body whose
+    /// header declared `declared` bytes.
+    SizeMismatch { declared: u64, available: u64 },
+    /// More than `limit` documents were nested inside one another.
+    NestingTooDeep { limit: usize },
+    Deserialize(de::Error),
+}
+
+impl Error {
+    /// Name of the cargo feature that would allow decoding this document.
+    pub fn missing_feature(&self) -> Option<&'static str> {
+        match *self {
+            Error::CompressionUnsupported { feature, .. } => Some(feature),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::IO(ref e) => write!(f, "{}", e),
+            Error::Header(ref e) => write!(f, "{}", e),
+            Error::Parser(ref e) => write!(f, "{}", e),
+            Error::BodyTooLarge { size, limit } => {
+                write!(f, "body of {} bytes exceeds the limit of {}", size, limit)
+            }
+            Error::UnsupportedType(ty) => write!(f, "unsupported document type {:?}", ty),
+            Error::CompressionUnsupported {
+                document_type,
+                feature,
+            } => {
+                write!(
+                    f,
+                    "{:?} document needs the {} feature",
+                    document_type,
+                    feature
+                )
+            }
+            Error::ChecksumMissing => write!(f, "no crc32 checksum in user metadata"),
+            Error::ChecksumMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "checksum mismatch: expected 0x{:08x}, got 0x{:08x}",
+                    expected,
+                    actual
+                )
+            }
+            Error::SizeMismatch { declared, available } => {
+                write!(
+                    f,
+                    "compressed body of {} bytes ends after {}",
+                    declared,
+                    available
+                )
+            }
+            Error::NestingTooDeep { limit } => {
+                write!(f, "more than {} nested documents", limit)
+            }
+            Error::Deserialize(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::IO(_) => "i/o error",
+            Error::Header(_) => "invalid header",
+            Error::Parser(_) => "invalid body",
+            Error::BodyTooLarge { .. } => "body too large",
+            Error::UnsupportedType(_) => "unsupported document type",
+            Error::CompressionUnsupported { .. } => "compression unsupported",
+            Error::ChecksumMissing => "checksum missing",
+            Error::ChecksumMismatch { .. } => "checksum mismatch",
+            Error::SizeMismatch { .. } => "size mismatch",
+            Error::NestingTooDeep { .. } => "documents nested too deep",
+            Error::Deserialize(_) => "deserialization failed",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::IO(ref e) => Some(e),
+            Error::Header(ref e) => Some(e),
+            Error::Parser(ref e) => Some(e),
+            Error::Deserialize(ref e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl From<header::Error> for Error {
@@ -55,23 +187,74 @@ impl From<parser::Error> for Error {
     }
 }
 
+impl From<de::Error> for Error {
+    fn from(e: de::Error) -> Error {
+        Error::Deserialize(e)
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
         Error::IO(e)
     }
 }
 
+/// Decompress into `buf` as the data arrives, without trusting any size
+/// announced up front, and fail as soon as the output exceeds `limit`.
+fn read_limited<R: io::Read>(mut rdr: R, limit: u64, buf: &mut Vec<u8>) -> Result<(), Error> {
+    rdr.by_ref().take(limit).read_to_end(buf)?;
+
+    if rdr.read(&mut [0])? != 0 {
+        // The real size is unknown without reading the rest, so report
+        // the first byte past the limit.
+        return Err(Error::BodyTooLarge {
+            size: limit + 1,
+            limit: limit,
+        });
+    }
+
+    Ok(())
+}
+
+/// Consume the rest of a compressed body, which the decompressor may have
+/// left unread, and fail with `SizeMismatch` if the input ends before the
+/// `declared` size.
+#[cfg(any(feature = "comp-snappy", feature = "comp-zlib", feature = "comp-zstd"))]
+fn finish_compressed<R: io::Read>(input: &mut io::Take<R>, declared: u64) -> Result<(), Error> {
+    io::copy(input, &mut io::sink())?;
+    if input.limit() > 0 {
+        return Err(Error::SizeMismatch {
+            declared: declared,
+            available: declared - input.limit(),
+        });
+    }
+    Ok(())
+}
+
 #[cfg(feature = "comp-snappy")]
 fn read_snappy_body<R: io::Read>(
-    mut reader: R,
+    reader: R,
     comp_size: u64,
+    limit: u64,
+    input: &mut Vec<u8>,
     buf: &mut Vec<u8>,
-) -> io::Result<()> {
-    let mut input = vec![0; comp_size as usize];
-    reader.read_exact(&mut input)?;
-    buf.resize(snap::decompress_len(&input)?, 0);
+) -> Result<(), Error> {
+    input.clear();
+    let mut rdr = reader.take(comp_size);
+    rdr.read_to_end(input)?;
+    finish_compressed(&mut rdr, comp_size)?;
+
+    let full_size = snap::decompress_len(input).map_err(io::Error::from)?;
+    if full_size as u64 > limit {
+        return Err(Error::BodyTooLarge {
+            size: full_size as u64,
+            limit: limit,
+        });
+    }
+
+    buf.resize(full_size, 0);
     let mut dec = snap::Decoder::new();
-    dec.decompress(&input, buf)?;
+    dec.decompress(input, buf).map_err(io::Error::from)?;
     Ok(())
 }
 
@@ -81,36 +264,234 @@ fn read_zlib_body<R: io::Read>(
     comp_size: u64,
     full_size: u64,
     buf: &mut Vec<u8>,
-) -> io::Result<()> {
-    let mut rdr = flate2::read::ZlibDecoder::new(reader.take(comp_size));
-    buf.resize(full_size as usize, 0);
-    rdr.read_exact(buf)?;
+) -> Result<(), Error> {
+    let mut input = reader.take(comp_size);
+    let res = read_limited(flate2::read::ZlibDecoder::new(&mut input), full_size, buf);
+    finish_compressed(&mut input, comp_size)?;
+    res?;
+
+    if (buf.len() as u64) < full_size {
+        return Err(Error::IO(io::ErrorKind::UnexpectedEof.into()));
+    }
+
     Ok(())
 }
 
 #[cfg(feature = "comp-zstd")]
-fn read_zstd_body<R: io::Read>(reader: R, comp_size: u64, buf: &mut Vec<u8>) -> io::Result<()> {
-    let mut rdr = zstd::stream::Decoder::new(reader.take(comp_size))?;
-    rdr.read_to_end(buf)?;
-    Ok(())
+fn read_zstd_body<R: io::Read>(
+    reader: R,
+    comp_size: u64,
+    limit: u64,
+    buf: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let mut input = reader.take(comp_size);
+    let res = zstd::stream::Decoder::new(&mut input)
+        .map_err(Error::from)
+        .and_then(|rdr| read_limited(rdr, limit, buf));
+    finish_compressed(&mut input, comp_size)?;
+    res
 }
 
+/// Parse a complete document from `reader`.
+///
+/// The body is decompressed into `buffer`, which is cleared first, so the
+/// same buffer can be passed again for the next document.
 pub fn parse<'buf, R, B>(
-    mut reader: R,
+    reader: R,
     builder: B,
     buffer: &'buf mut Vec<u8>,
 ) -> Result<B::Value, Error>
 where
-    R: io::Read + io::Seek,
+    R: io::Read,
     B: Builder<'buf>,
 {
-    let config = Config::default();
-    let header = Header::read(&mut reader, &config)?;
+    parse_with_config(reader, builder, buffer, &Config::default())
+}
+
+pub fn parse_with_config<'buf, R, B>(
+    reader: R,
+    builder: B,
+    buffer: &'buf mut Vec<u8>,
+    config: &Config,
+) -> Result<B::Value, Error>
+where
+    R: io::Read,
+    B: Builder<'buf>,
+{
+    parse_with_report(reader, builder, buffer, config).map(|(value, _)| value)
+}
+
+/// Sizes of a parsed document body, before and after decompression.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParseReport {
+    document_type: DocumentType,
+    compressed_size: u64,
+    uncompressed_size: u64,
+}
+
+impl ParseReport {
+    pub fn document_type(&self) -> DocumentType {
+        self.document_type
+    }
+
+    /// Size of the body as stored in the document.
+    pub fn compressed_size(&self) -> u64 {
+        self.compressed_size
+    }
+
+    /// Size of the body after decompression.
+    pub fn uncompressed_size(&self) -> u64 {
+        self.uncompressed_size
+    }
+
+    /// Uncompressed size divided by compressed size.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.compressed_size == 0 {
+            1.0
+        } else {
+            self.uncompressed_size as f64 / self.compressed_size as f64
+        }
+    }
+}
+
+pub fn parse_with_report<'buf, R, B>(
+    reader: R,
+    builder: B,
+    buffer: &'buf mut Vec<u8>,
+    config: &Config,
+) -> Result<(B::Value, ParseReport), Error>
+where
+    R: io::Read,
+    B: Builder<'buf>,
+{
+    let report = read_body(reader, buffer, config)?;
+    let mut parser = Parser::new(builder, config, buffer);
+    Ok((parser.parse()?, report))
+}
+
+/// Read the header and the decompressed body into `buffer` without
+/// parsing it, for callers that drive a `Parser` themselves.
+pub fn read_body<R>(reader: R, buffer: &mut Vec<u8>, config: &Config) -> Result<ParseReport, Error>
+where
+    R: io::Read,
+{
+    read_document(reader, buffer, &mut Vec::new(), config)
+}
+
+/// Buffers kept between calls to `parse_with_context`.
+///
+/// The decompressed body and, for Snappy, the compressed input are read
+/// into buffers that keep their capacity from the previous call, so
+/// decoding many similar documents in a row stops allocating them once
+/// they have grown to size.
+#[derive(Debug, Default)]
+pub struct ParseContext {
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl ParseContext {
+    pub fn new() -> ParseContext {
+        ParseContext {
+            input: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+}
+
+/// Same as `parse_with_config`, but reuses the buffers held by `ctx`.
+/// Values borrowing from the body stay valid until `ctx` is used again.
+pub fn parse_with_context<'ctx, R, B>(
+    reader: R,
+    builder: B,
+    ctx: &'ctx mut ParseContext,
+    config: &Config,
+) -> Result<B::Value, Error>
+where
+    R: io::Read,
+    B: Builder<'ctx>,
+{
+    let ParseContext {
+        ref mut input,
+        ref mut output,
+    } = *ctx;
+
+    read_document(reader, output, input, config)?;
+
+    let mut parser = Parser::new(builder, config, output);
+    Ok(parser.parse()?)
+}
+
+/// Read the header and the decompressed body into `buffer`, replacing its
+/// contents, and use `scratch` for compressed input that has to be held
+/// in full.
+#[cfg_attr(not(feature = "comp-snappy"), allow(unused_variables, clippy::ptr_arg))]
+fn read_document<R>(
+    mut reader: R,
+    buffer: &mut Vec<u8>,
+    scratch: &mut Vec<u8>,
+    config: &Config,
+) -> Result<ParseReport, Error>
+where
+    R: io::Read,
+{
+    buffer.clear();
+    let header = Header::read(&mut reader, config)?;
+    read_document_body(reader, &header, buffer, scratch, config)
+}
+
+/// How many documents `Config::with_recursive_decompress` unwraps from
+/// inside a compressed body before giving up with `NestingTooDeep`.
+pub const MAX_NESTED_DOCUMENTS: usize = 4;
+
+/// The part of `read_document` after the header.
+fn read_document_body<R>(
+    reader: R,
+    header: &Header,
+    buffer: &mut Vec<u8>,
+    scratch: &mut Vec<u8>,
+    config: &Config,
+) -> Result<ParseReport, Error>
+where
+    R: io::Read,
+{
+    read_nested_body(reader, header, buffer, scratch, config, 0)
+}
+
+/// Whether a decompressed body starts with a document header. A body
+/// cannot start with `PACKET_START`, so this never mistakes one for the
+/// other.
+fn is_document(body: &[u8]) -> bool {
+    if body.len() < 4 {
+        return false;
+    }
+    let magic = u32::from(body[0]) | u32::from(body[1]) << 8 | u32::from(body[2]) << 16 |
+        u32::from(body[3]) << 24;
+    magic == MAGIC_V1 || magic == MAGIC_V3
+}
+
+/// `read_document_body` for a document found `depth` frames deep.
+#[cfg_attr(
+    not(feature = "comp-snappy"),
+    allow(unused_variables, clippy::ptr_arg, clippy::only_used_in_recursion)
+)]
+fn read_nested_body<R>(
+    reader: R,
+    header: &Header,
+    buffer: &mut Vec<u8>,
+    scratch: &mut Vec<u8>,
+    config: &Config,
+    depth: usize,
+) -> Result<ParseReport, Error>
+where
+    R: io::Read,
+{
+    buffer.clear();
 
     #[allow(unreachable_patterns)]
     match header.document_type() {
         DocumentType::Uncompressed => {
-            reader.read_to_end(buffer)?;
+            read_limited(reader, config.max_uncompressed_size(), buffer)?
         }
 
         #[cfg(feature = "comp-snappy")]
@@ -121,7 +502,21 @@ where
                     limit: config.max_compressed_size(),
                 });
             }
-            read_snappy_body(reader, compressed_size, buffer)?
+            read_snappy_body(
+                reader,
+                compressed_size,
+                config.max_uncompressed_size(),
+                scratch,
+                buffer,
+            )?
+        }
+
+        #[cfg(not(feature = "comp-snappy"))]
+        ty @ DocumentType::Snappy { .. } => {
+            return Err(Error::CompressionUnsupported {
+                document_type: ty,
+                feature: "comp-snappy",
+            })
         }
 
         #[cfg(feature = "comp-zlib")]
@@ -146,6 +541,14 @@ where
             read_zlib_body(reader, compressed_size, uncompressed_size, buffer)?
         }
 
+        #[cfg(not(feature = "comp-zlib"))]
+        ty @ DocumentType::ZLib { .. } => {
+            return Err(Error::CompressionUnsupported {
+                document_type: ty,
+                feature: "comp-zlib",
+            })
+        }
+
         #[cfg(feature = "comp-zstd")]
         DocumentType::ZStd { compressed_size } => {
             if compressed_size > config.max_compressed_size() {
@@ -155,23 +558,616 @@ where
                 });
             }
 
-            read_zstd_body(reader, compressed_size, buffer)?
+            read_zstd_body(
+                reader,
+                compressed_size,
+                config.max_uncompressed_size(),
+                buffer,
+            )?
+        }
+
+        #[cfg(not(feature = "comp-zstd"))]
+        ty @ DocumentType::ZStd { .. } => {
+            return Err(Error::CompressionUnsupported {
+                document_type: ty,
+                feature: "comp-zstd",
+            })
         }
 
         ty => return Err(Error::UnsupportedType(ty)),
     };
 
-    let mut parser = Parser::new(builder, &config, buffer);
-    Ok(parser.parse()?)
+    if config.verify_checksum() {
+        verify_checksum(header, buffer)?;
+    }
+
+    if config.recursive_decompress() && header.document_type() != DocumentType::Uncompressed &&
+        is_document(buffer)
+    {
+        if depth == MAX_NESTED_DOCUMENTS {
+            return Err(Error::NestingTooDeep { limit: MAX_NESTED_DOCUMENTS });
+        }
+
+        let outer = mem::take(buffer);
+        let mut input = &outer[..];
+        let inner = Header::read(&mut input, config)?;
+        read_nested_body(input, &inner, buffer, scratch, config, depth + 1)?;
+    }
+
+    let uncompressed_size = buffer.len() as u64;
+    let report = ParseReport {
+        document_type: header.document_type(),
+        compressed_size: match header.document_type() {
+            DocumentType::Uncompressed => uncompressed_size,
+            DocumentType::Snappy { compressed_size } |
+            DocumentType::ZLib { compressed_size, .. } |
+            DocumentType::ZStd { compressed_size } => compressed_size,
+        },
+        uncompressed_size: uncompressed_size,
+    };
+
+    Ok(report)
+}
+
+/// Checksum convention: the user metadata is a hash (or a reference to
+/// one) whose `crc32` key holds the CRC-32 of the decompressed body.
+fn verify_checksum(header: &Header, body: &[u8]) -> Result<(), Error> {
+    use arena::Inner;
+
+    let meta = match *header.user_metadata() {
+        Some(ref meta) => meta,
+        None => return Err(Error::ChecksumMissing),
+    };
+
+    let arena = arena::Arena::new();
+    let value = arena::parse(meta, &arena)?;
+
+    let hash = match value.0.get() {
+        Inner::Hash(h) => h,
+        Inner::Ref(r) => {
+            match r.0.get() {
+                Inner::Hash(h) => h,
+                _ => return Err(Error::ChecksumMissing),
+            }
+        }
+        _ => return Err(Error::ChecksumMissing),
+    };
+
+    let expected = match hash.get("crc32").map(|v| v.0.get()) {
+        Some(Inner::U64(v)) if v <= u32::MAX as u64 => v as u32,
+        _ => return Err(Error::ChecksumMissing),
+    };
+
+    let actual = crc32::crc32(body);
+    if expected != actual {
+        return Err(Error::ChecksumMismatch {
+            expected: expected,
+            actual: actual,
+        });
+    }
+
+    Ok(())
+}
+
+/// Read only the document header. Exactly the header bytes are consumed,
+/// so `reader` is left at the start of the body.
+pub fn peek_header<R: io::Read>(reader: &mut R, config: &Config) -> Result<Header, Error> {
+    Ok(Header::read(reader, config)?)
+}
+
+/// Read the document header and return its user metadata, leaving the body
+/// unread. The metadata is returned as the raw Sereal body it is encoded as.
+pub fn read_metadata<R: io::Read>(reader: &mut R, config: &Config) -> Result<Option<Vec<u8>>, Error> {
+    let header = peek_header(reader, config)?;
+    Ok(header.user_metadata().clone())
+}
+
+/// Parse a complete in-memory document.
+///
+/// The body is decompressed into an internal buffer, so this only works with
+/// builders whose values do not borrow from the input, such as `ArcBuilder`.
+pub fn parse_slice<B, V>(bytes: &[u8], builder: B) -> Result<V, Error>
+where
+    B: for<'buf> Builder<'buf, Value = V>,
+{
+    let mut buffer = Vec::new();
+    parse(io::Cursor::new(bytes), builder, &mut buffer)
+}
+
+/// Parse a packet that may or may not carry a document header.
+///
+/// Input starting with `PACKET_START` (the first byte of the magic string)
+/// is parsed as a complete document, anything else as a bare body.
+pub fn parse_packet<'buf, B>(
+    input: &'buf [u8],
+    builder: B,
+    buffer: &'buf mut Vec<u8>,
+) -> Result<B::Value, Error>
+where
+    B: Builder<'buf>,
+{
+    if input.first() == Some(&PACKET_START) {
+        parse(io::Cursor::new(input), builder, buffer)
+    } else {
+        Ok(parser::parse(input, builder)?)
+    }
+}
+
+/// How much of an uncompressed body `parse_stream` reads ahead at first.
+const STREAM_CHUNK: u64 = 4096;
+
+/// Iterator over the documents of a stream, returned by `parse_stream`.
+pub struct ParseStream<R, F> {
+    reader: R,
+    make_builder: F,
+    config: Config,
+    buffer: Vec<u8>,
+    scratch: Vec<u8>,
+    done: bool,
+}
+
+/// Parse documents written back to back into one stream, as some log
+/// formats do. `make_builder` is called once per document, and after each
+/// document the reader is left at the start of the next one.
+///
+/// An uncompressed body does not record its length, so it is read ahead in
+/// growing chunks until the parser finds the end of the root value, and the
+/// reader is then moved back to that point. `require_eof` is ignored.
+/// Iteration stops after the first error.
+pub fn parse_stream<R, F, B, V>(reader: R, make_builder: F, config: &Config) -> ParseStream<R, F>
+where
+    R: io::Read + io::Seek,
+    F: FnMut() -> B,
+    B: for<'buf> Builder<'buf, Value = V>,
+{
+    ParseStream {
+        reader: reader,
+        make_builder: make_builder,
+        config: config.clone().with_require_eof(false),
+        buffer: Vec::new(),
+        scratch: Vec::new(),
+        done: false,
+    }
+}
+
+impl<R, F, B, V> ParseStream<R, F>
+where
+    R: io::Read + io::Seek,
+    F: FnMut() -> B,
+    B: for<'buf> Builder<'buf, Value = V>,
+{
+    fn next_document(&mut self) -> Result<Option<V>, Error> {
+        let start = self.reader.stream_position()?;
+        if self.reader.read(&mut [0])? == 0 {
+            return Ok(None);
+        }
+        self.reader.seek(io::SeekFrom::Start(start))?;
+
+        let header = Header::read(&mut self.reader, &self.config)?;
+        let body_start = self.reader.stream_position()?;
+
+        if header.document_type() == DocumentType::Uncompressed {
+            return self.parse_unframed(&header, body_start).map(Some);
+        }
+
+        let report = read_document_body(
+            &mut self.reader,
+            &header,
+            &mut self.buffer,
+            &mut self.scratch,
+            &self.config,
+        )?;
+        self.reader.seek(io::SeekFrom::Start(body_start + report.compressed_size()))?;
+
+        let mut parser = Parser::new((self.make_builder)(), &self.config, &self.buffer);
+        Ok(Some(parser.parse()?))
+    }
+
+    fn parse_unframed(&mut self, header: &Header, body_start: u64) -> Result<V, Error> {
+        let limit = self.config.max_uncompressed_size();
+        let mut chunk = cmp::min(STREAM_CHUNK, limit);
+
+        loop {
+            self.buffer.clear();
+            self.reader.seek(io::SeekFrom::Start(body_start))?;
+            self.reader.by_ref().take(chunk).read_to_end(&mut self.buffer)?;
+            // A full chunk may have cut the body short.
+            let full = self.buffer.len() as u64 == chunk;
+
+            let builder = (self.make_builder)();
+            let mut parser = Parser::new(builder, &self.config, &self.buffer);
+            match parser.parse() {
+                Ok(value) => {
+                    let len = parser.bytes_consumed();
+                    if self.config.verify_checksum() {
+                        verify_checksum(header, &self.buffer[..len])?;
+                    }
+                    self.reader.seek(io::SeekFrom::Start(body_start + len as u64))?;
+                    return Ok(value);
+                }
+                Err(ref e) if e.is_eof() && full => {
+                    if chunk == limit {
+                        return Err(Error::BodyTooLarge {
+                            size: limit + 1,
+                            limit: limit,
+                        });
+                    }
+                    chunk = cmp::min(chunk.saturating_mul(2), limit);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl<R, F, B, V> Iterator for ParseStream<R, F>
+where
+    R: io::Read + io::Seek,
+    F: FnMut() -> B,
+    B: for<'buf> Builder<'buf, Value = V>,
+{
+    type Item = Result<V, Error>;
+
+    fn next(&mut self) -> Option<Result<V, Error>> {
+        if self.done {
+            return None;
+        }
+
+        match self.next_document() {
+            Ok(Some(value)) => Some(Ok(value)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use std::io;
     use std::io::Cursor;
     use arc::ArcBuilder;
     use arc::Value;
     use arc::Inner;
     use parse;
+    use parse_packet;
+    use parse_slice;
+    use parse_with_config;
+    use read_metadata;
+    use config::Config;
+    use Error;
+
+    #[test]
+    fn uncompressed_limit() {
+        let raw = b"=srl\x02\x00\x43\x01\x02\x03";
+
+        let config = Config::default().with_max_uncompressed_size(4);
+        let val =
+            parse_with_config(Cursor::new(&raw[..]), ArcBuilder::new(), &mut Vec::new(), &config);
+        assert!(val.is_ok());
+
+        let config = Config::default().with_max_uncompressed_size(3);
+        let val =
+            parse_with_config(Cursor::new(&raw[..]), ArcBuilder::new(), &mut Vec::new(), &config);
+        match val.unwrap_err() {
+            Error::BodyTooLarge { size: 4, limit: 3 } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn display() {
+        use parser;
+
+        let err = Error::Parser(parser::Error::ArrayTooLarge {
+            count: 100_000,
+            limit: 10_000,
+        });
+        assert_eq!(
+            err.to_string(),
+            "array of 100000 elements exceeds the limit of 10000"
+        );
+
+        let raw = b"=srl\x02\x00\x43\x01\x02\x03";
+        let config = Config::default().with_max_uncompressed_size(3);
+        let err =
+            parse_with_config(Cursor::new(&raw[..]), ArcBuilder::new(), &mut Vec::new(), &config)
+                .unwrap_err();
+        assert_eq!(err.to_string(), "body of 4 bytes exceeds the limit of 3");
+
+        let err = parse(Cursor::new(&b"=srk"[..]), ArcBuilder::new(), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.to_string(), "invalid magic");
+    }
+
+    #[test]
+    fn source() {
+        use std::error::Error as StdError;
+        use header;
+        use parser;
+
+        let err =
+            parse(Cursor::new(&b"=srl\x02\x00\x42\x01"[..]), ArcBuilder::new(), &mut Vec::new())
+                .unwrap_err();
+        match err.source().and_then(|e| e.downcast_ref::<parser::Error>()) {
+            Some(&parser::Error::UnexpectedEof) => (),
+            e => panic!("unexpected source {:?}", e),
+        }
+
+        let err = parse(Cursor::new(&b"=srl"[..]), ArcBuilder::new(), &mut Vec::new()).unwrap_err();
+        let source = err.source().unwrap();
+        assert!(source.downcast_ref::<header::Error>().unwrap().is_io_error());
+        assert!(source.source().unwrap().is::<::std::io::Error>());
+
+        assert!(Error::ChecksumMissing.source().is_none());
+    }
+
+    #[test]
+    fn checksum() {
+        let config = Config::default().with_verify_checksum(true);
+        let p = |raw: &[u8]| {
+            parse_with_config(Cursor::new(raw), ArcBuilder::new(), &mut Vec::new(), &config)
+        };
+
+        let good = b"=srl\x02\x0e\x01\x51\x65crc32\x20\xad\xb8\xf1\xfd\x0d\x41\x01";
+        assert!(p(good).is_ok());
+
+        let bad = b"=srl\x02\x0e\x01\x51\x65crc32\x20\xad\xb8\xf1\xfd\x0d\x41\x02";
+        match p(bad).unwrap_err() {
+            Error::ChecksumMismatch {
+                expected: 0xdfbc5c2d,
+                actual: _,
+            } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        match p(b"=srl\x02\x00\x41\x01").unwrap_err() {
+            Error::ChecksumMissing => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let config = Config::default();
+        let val =
+            parse_with_config(Cursor::new(&bad[..]), ArcBuilder::new(), &mut Vec::new(), &config);
+        assert!(val.is_ok());
+    }
+
+    #[test]
+    fn slice() {
+        let raw = b"=\xf3rl\x04\x00\x42\x01\x63foo".to_vec();
+        let val = parse_slice(&raw, ArcBuilder::new()).unwrap();
+        drop(raw);
+
+        assert_eq!(
+            val,
+            Value::new(Inner::Ref(Value::new(Inner::Array(vec![
+                Value::new(Inner::U64(1)),
+                Value::new(Inner::String(b"foo".to_vec())),
+            ]))))
+        );
+
+        assert!(parse_slice(b"=srl\x02\x00\x42\x01", ArcBuilder::new()).is_err());
+    }
+
+    #[test]
+    fn packet() {
+        let full = parse_packet(b"=srl\x02\x00\x41\x01", ArcBuilder::new(), &mut Vec::new());
+        let bare = parse_packet(b"\x41\x01", ArcBuilder::new(), &mut Vec::new());
+        let expected = Value::new(Inner::Ref(Value::new(
+            Inner::Array(vec![Value::new(Inner::U64(1))]),
+        )));
+
+        assert_eq!(full.unwrap(), expected);
+        assert_eq!(bare.unwrap(), expected);
+    }
+
+    #[test]
+    fn context() {
+        use parse_with_context;
+        use ParseContext;
+
+        let config = Config::default();
+        let mut ctx = ParseContext::new();
+
+        let raw = b"=srl\x02\x00\x43\x01\x02\x63foo";
+        let val = parse_with_context(Cursor::new(&raw[..]), ArcBuilder::new(), &mut ctx, &config);
+        assert!(val.is_ok());
+
+        let ptr = ctx.output.as_ptr();
+        let capacity = ctx.output.capacity();
+
+        for _ in 0..10 {
+            let raw = b"=srl\x02\x00\x42\x01\x02";
+            let val =
+                parse_with_context(Cursor::new(&raw[..]), ArcBuilder::new(), &mut ctx, &config);
+            assert_eq!(
+                val.unwrap(),
+                Value::new(Inner::Ref(Value::new(Inner::Array(vec![
+                    Value::new(Inner::U64(1)),
+                    Value::new(Inner::U64(2)),
+                ]))))
+            );
+            assert_eq!(ctx.output, b"\x42\x01\x02");
+            assert_eq!(ctx.output.as_ptr(), ptr);
+            assert_eq!(ctx.output.capacity(), capacity);
+        }
+
+        let raw = b"=srl\x02\x00\x42\x01";
+        assert!(parse_with_context(Cursor::new(&raw[..]), ArcBuilder::new(), &mut ctx, &config)
+            .is_err());
+    }
+
+    #[cfg(feature = "comp-snappy")]
+    #[test]
+    fn snappy_context() {
+        use parse_with_context;
+        use ParseContext;
+
+        let raw = b"\
+            \x3d\xf3\x72\x6c\x23\x00\xb8\x00\x84\x08\x10\x28\x2b\x80\x08\x00\
+            \xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\
+            \x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\
+            \x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfa\x01\x00\
+        ";
+        let config = Config::default();
+        let mut ctx = ParseContext::new();
+
+        let first = parse_with_context(Cursor::new(&raw[..]), ArcBuilder::new(), &mut ctx, &config);
+        let buffers = (ctx.input.as_ptr(), ctx.output.as_ptr());
+        assert_eq!(ctx.input.len(), 56);
+        assert_eq!(ctx.output.len(), 1028);
+
+        for _ in 0..10 {
+            let val =
+                parse_with_context(Cursor::new(&raw[..]), ArcBuilder::new(), &mut ctx, &config);
+            assert_eq!(val.unwrap(), *first.as_ref().unwrap());
+            assert_eq!((ctx.input.as_ptr(), ctx.output.as_ptr()), buffers);
+        }
+    }
+
+    #[cfg(not(feature = "comp-snappy"))]
+    #[test]
+    fn snappy_unsupported() {
+        use header::DocumentType;
+
+        let raw = b"=\xf3rl\x24\x00\x01\x00";
+        let err = parse(Cursor::new(&raw[..]), ArcBuilder::new(), &mut Vec::new()).unwrap_err();
+        match err {
+            Error::CompressionUnsupported {
+                document_type: DocumentType::Snappy { compressed_size: 1 },
+                feature: "comp-snappy",
+            } => (),
+            ref e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(
+            format!("{}", err),
+            "Snappy { compressed_size: 1 } document needs the comp-snappy feature"
+        );
+    }
+
+    #[cfg(not(feature = "comp-zlib"))]
+    #[test]
+    fn zlib_unsupported() {
+        let raw = b"=\xf3rl\x34\x00\x01\x01\x00";
+        let err = parse(Cursor::new(&raw[..]), ArcBuilder::new(), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.missing_feature(), Some("comp-zlib"));
+    }
+
+    #[cfg(not(feature = "comp-zstd"))]
+    #[test]
+    fn zstd_unsupported() {
+        use header::DocumentType;
+
+        let raw = b"=\xf3rl\x44\x00\x01\x00";
+        let err = parse(Cursor::new(&raw[..]), ArcBuilder::new(), &mut Vec::new()).unwrap_err();
+        match err {
+            Error::CompressionUnsupported {
+                document_type: DocumentType::ZStd { compressed_size: 1 },
+                feature: "comp-zstd",
+            } => (),
+            ref e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(err.missing_feature(), Some("comp-zstd"));
+    }
+
+    #[test]
+    fn unseekable_reader() {
+        struct Pipe<'a>(&'a [u8]);
+
+        impl<'a> io::Read for Pipe<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+
+        let raw = b"=\xf3rl\x04\x00\x42\x01\x02";
+        let val = parse(Pipe(&raw[..]), ArcBuilder::new(), &mut Vec::new()).unwrap();
+        assert_eq!(format!("{}", val), "[1,2]");
+
+        let config = Config::default().with_max_uncompressed_size(2);
+        let val = parse_with_config(Pipe(&raw[..]), ArcBuilder::new(), &mut Vec::new(), &config);
+        match val.unwrap_err() {
+            Error::BodyTooLarge { size: 3, limit: 2 } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn reuse_buffer() {
+        let mut buf = Vec::new();
+
+        let raw = b"=\xf3rl\x04\x00\x42\x01\x02";
+        let val = parse(Cursor::new(&raw[..]), ArcBuilder::new(), &mut buf).unwrap();
+        assert_eq!(format!("{}", val), "[1,2]");
+
+        let raw = b"=\xf3rl\x04\x00\x03";
+        let val = parse(Cursor::new(&raw[..]), ArcBuilder::new(), &mut buf).unwrap();
+        assert_eq!(val, Value::new(Inner::U64(3)));
+        assert_eq!(buf, b"\x03");
+    }
+
+    #[test]
+    fn padded_body() {
+        let raw = b"=\xf3rl\x04\x00\x3f\x3f\x01";
+        let val = parse(Cursor::new(&raw[..]), ArcBuilder::new(), &mut Vec::new()).unwrap();
+        assert_eq!(val, Value::new(Inner::U64(1)));
+    }
+
+    #[test]
+    fn peek() {
+        use header::DocumentType;
+        use peek_header;
+
+        let config = Config::default();
+        let cases: &[(&[u8], u8, DocumentType)] = &[
+            (b"=srl\x02\x00\x01", 2, DocumentType::Uncompressed),
+            (
+                b"=srl\x22\x02\x01\x00\x0a\x01",
+                2,
+                DocumentType::Snappy { compressed_size: 10 },
+            ),
+            (
+                b"=\xf3rl\x33\x00\x0a\x0b\x01",
+                3,
+                DocumentType::ZLib {
+                    uncompressed_size: 10,
+                    compressed_size: 11,
+                },
+            ),
+            (b"=\xf3rl\x04\x03\x01\x61x\x01", 4, DocumentType::Uncompressed),
+            (b"=\xf3rl\x44\x00\x0a\x01", 4, DocumentType::ZStd { compressed_size: 10 }),
+        ];
+
+        for &(raw, version, document_type) in cases {
+            let mut rdr = Cursor::new(raw);
+            let header = peek_header(&mut rdr, &config).unwrap();
+            assert_eq!(header.version(), version);
+            assert_eq!(header.document_type(), document_type);
+            // Every fixture has a one-byte body.
+            assert_eq!(rdr.position(), raw.len() as u64 - 1);
+        }
+
+        assert!(peek_header(&mut Cursor::new(&b"=srl"[..]), &config).is_err());
+    }
+
+    #[test]
+    fn metadata() {
+        // zstd document with "x" as metadata and a truncated body
+        let raw = b"=\xf3rl\x44\x03\x01\x61x\xff\x01\x28";
+        let config = Config::default();
+
+        let mut rdr = Cursor::new(&raw[..]);
+        assert_eq!(read_metadata(&mut rdr, &config).unwrap(), Some(b"\x61x".to_vec()));
+        assert_eq!(rdr.position(), 11);
+        assert!(parse(Cursor::new(&raw[..]), ArcBuilder::new(), &mut Vec::new()).is_err());
+
+        let raw = b"=\xf3rl\x04\x00\x01";
+        assert_eq!(read_metadata(&mut Cursor::new(&raw[..]), &config).unwrap(), None);
+    }
 
     #[cfg(feature = "comp-snappy")]
     #[test]
@@ -182,7 +1178,7 @@ mod test {
             \x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\
             \x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfa\x01\x00\
         ";
-        let val = parse(Cursor::new(&raw[..]), ArcBuilder, &mut Vec::new()).unwrap();
+        let val = parse(Cursor::new(&raw[..]), ArcBuilder::new(), &mut Vec::new()).unwrap();
         assert_eq!(
             val,
             Value::new(Inner::Ref(Value::new(
@@ -199,7 +1195,7 @@ mod test {
             \x00\x00\x0c\x02\xc1\x8e\x95\x42\x82\x49\xa4\x23\x84\x3f\x39\x7f\
             \x00\x66\x15\x72\x5a\x00\xdc\
         ";
-        let val = parse(Cursor::new(&raw[..]), ArcBuilder, &mut Vec::new()).unwrap();
+        let val = parse(Cursor::new(&raw[..]), ArcBuilder::new(), &mut Vec::new()).unwrap();
         assert_eq!(
             val,
             Value::new(Inner::Ref(Value::new(
@@ -207,4 +1203,208 @@ mod test {
             )))
         );
     }
+
+    #[cfg(feature = "comp-snappy")]
+    #[test]
+    fn snappy_limit() {
+        let raw = b"\
+            \x3d\xf3\x72\x6c\x23\x00\xb8\x00\x84\x08\x10\x28\x2b\x80\x08\x00\
+            \xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\
+            \x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\
+            \x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfe\x01\x00\xfa\x01\x00\
+        ";
+        let config = Config::default().with_max_uncompressed_size(1000);
+        let val =
+            parse_with_config(Cursor::new(&raw[..]), ArcBuilder::new(), &mut Vec::new(), &config);
+        match val.unwrap_err() {
+            Error::BodyTooLarge {
+                size: 1028,
+                limit: 1000,
+            } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[cfg(feature = "comp-zlib")]
+    #[test]
+    fn zlib_limit() {
+        // Same body as simple_zlib, but the header claims 1000 bytes.
+        let raw = b"\
+            \x3d\xf3\x72\x6c\x33\x00\xe8\x07\x9d\x00\x78\x01\xed\xc0\x31\x0d\
+            \x00\x00\x0c\x02\xc1\x8e\x95\x42\x82\x49\xa4\x23\x84\x3f\x39\x7f\
+            \x00\x66\x15\x72\x5a\x00\xdc\
+        ";
+        let val = parse(Cursor::new(&raw[..]), ArcBuilder::new(), &mut Vec::new());
+        match val.unwrap_err() {
+            Error::BodyTooLarge {
+                size: 1001,
+                limit: 1000,
+            } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[cfg(feature = "comp-zlib")]
+    #[test]
+    fn zlib_size_mismatch() {
+        // Same body as simple_zlib, but the header claims 40 compressed
+        // bytes where the input has 29.
+        let raw = b"\
+            \x3d\xf3\x72\x6c\x33\x00\x84\x08\xa8\x00\x78\x01\xed\xc0\x31\x0d\
+            \x00\x00\x0c\x02\xc1\x8e\x95\x42\x82\x49\xa4\x23\x84\x3f\x39\x7f\
+            \x00\x66\x15\x72\x5a\x00\xdc\
+        ";
+        let val = parse(Cursor::new(&raw[..]), ArcBuilder::new(), &mut Vec::new());
+        match val.unwrap_err() {
+            Error::SizeMismatch {
+                declared: 40,
+                available: 29,
+            } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // Cut short inside the zlib stream itself.
+        let val = parse(Cursor::new(&raw[..24]), ArcBuilder::new(), &mut Vec::new());
+        match val.unwrap_err() {
+            Error::SizeMismatch {
+                declared: 40,
+                available: 14,
+            } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[cfg(feature = "comp-snappy")]
+    #[test]
+    fn snappy_size_mismatch() {
+        // The header claims 100 compressed bytes where the input has 4.
+        let raw = b"\x3d\xf3\x72\x6c\x23\x00\xe4\x00\x03\x08\x2b\x00";
+        let val = parse(Cursor::new(&raw[..]), ArcBuilder::new(), &mut Vec::new());
+        match val.unwrap_err() {
+            Error::SizeMismatch {
+                declared: 100,
+                available: 4,
+            } => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[cfg(feature = "comp-snappy")]
+    #[test]
+    fn nested_snappy() {
+        use config::Config;
+        use MAX_NESTED_DOCUMENTS;
+
+        fn wrap(doc: &[u8]) -> Vec<u8> {
+            let body = snap::Encoder::new().compress_vec(doc).unwrap();
+            assert!(body.len() < 0x80);
+            let mut out = b"\x3d\xf3\x72\x6c\x23\x00".to_vec();
+            out.push(body.len() as u8);
+            out.extend(body);
+            out
+        }
+
+        let mut doc = b"\x3d\xf3\x72\x6c\x03\x00\x42\x01\x63foo".to_vec();
+        for _ in 0..2 {
+            doc = wrap(&doc);
+        }
+
+        let config = Config::default().with_recursive_decompress(true);
+        let val = parse_with_config(Cursor::new(&doc), ArcBuilder::new(), &mut Vec::new(), &config);
+        assert_eq!(
+            val.unwrap(),
+            Value::new(Inner::Ref(Value::new(Inner::Array(vec![
+                Value::new(Inner::U64(1)),
+                Value::new(Inner::String(b"foo".to_vec())),
+            ])))),
+        );
+
+        let val = parse(Cursor::new(&doc), ArcBuilder::new(), &mut Vec::new());
+        match val.unwrap_err() {
+            Error::Parser(_) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        for _ in 2..MAX_NESTED_DOCUMENTS {
+            doc = wrap(&doc);
+        }
+        let val = parse_with_config(Cursor::new(&doc), ArcBuilder::new(), &mut Vec::new(), &config);
+        assert!(val.is_ok());
+
+        doc = wrap(&doc);
+        let val = parse_with_config(Cursor::new(&doc), ArcBuilder::new(), &mut Vec::new(), &config);
+        match val.unwrap_err() {
+            Error::NestingTooDeep { limit } => assert_eq!(limit, MAX_NESTED_DOCUMENTS),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[cfg(feature = "comp-zlib")]
+    #[test]
+    fn zlib_report() {
+        use header::DocumentType;
+        use parse_with_report;
+
+        let raw = b"\
+            \x3d\xf3\x72\x6c\x33\x00\x84\x08\x9d\x00\x78\x01\xed\xc0\x31\x0d\
+            \x00\x00\x0c\x02\xc1\x8e\x95\x42\x82\x49\xa4\x23\x84\x3f\x39\x7f\
+            \x00\x66\x15\x72\x5a\x00\xdc\
+        ";
+        let config = Config::default();
+        let (_, report) =
+            parse_with_report(Cursor::new(&raw[..]), ArcBuilder::new(), &mut Vec::new(), &config)
+                .unwrap();
+
+        assert_eq!(
+            report.document_type(),
+            DocumentType::ZLib {
+                compressed_size: 29,
+                uncompressed_size: 1028,
+            }
+        );
+        assert_eq!(report.compressed_size(), 29);
+        assert_eq!(report.uncompressed_size(), 1028);
+        assert!(report.compression_ratio() > 35.0);
+    }
+
+    #[test]
+    fn stream() {
+        use std::io::Seek;
+        use arc;
+        use ser::to_writer;
+        use parse_stream;
+
+        // The middle document is larger than the first read-ahead chunk.
+        let mut long = b"\x2b\x90\x08".to_vec();
+        for _ in 0..1040 {
+            long.extend_from_slice(b"\x68abcdefgh");
+        }
+        let docs = vec![
+            arc::parse(b"\x42\x01\x02").unwrap(),
+            arc::parse(&long).unwrap(),
+            arc::parse(b"\x51\x63foo\x63bar").unwrap(),
+        ];
+
+        let mut file = Cursor::new(Vec::new());
+        for doc in &docs {
+            to_writer(&mut file, doc).unwrap();
+        }
+        let end = file.position();
+        file.set_position(0);
+
+        let config = Config::default();
+        let mut stream = parse_stream(&mut file, ArcBuilder::new, &config);
+        for doc in &docs {
+            assert_eq!(&stream.next().unwrap().unwrap(), doc);
+        }
+        assert!(stream.next().is_none());
+        drop(stream);
+        assert_eq!(file.stream_position().unwrap(), end);
+
+        let mut garbage = Cursor::new(b"=\xf3rl\x04\x00\x01=\xf3rl\x04\x00\x3e".to_vec());
+        let mut stream = parse_stream(&mut garbage, ArcBuilder::new, &config);
+        assert_eq!(stream.next().unwrap().unwrap(), Value::new(Inner::U64(1)));
+        assert!(stream.next().unwrap().is_err());
+        assert!(stream.next().is_none());
+    }
 }
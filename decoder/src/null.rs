@@ -0,0 +1,113 @@
+use parser;
+pub use parser::Error;
+pub use parser::Result;
+
+/// Placeholder produced by `NullBuilder`. Carries no data.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Value;
+
+impl<'buf> parser::Value<'buf> for Value {
+    type Array = ();
+    type Hash = ();
+
+    fn set_undef(&mut self) {}
+    fn set_true(&mut self) {}
+    fn set_false(&mut self) {}
+
+    fn set_i64(&mut self, _: i64) {}
+    fn set_u64(&mut self, _: u64) {}
+    fn set_f32(&mut self, _: f32) {}
+    fn set_f64(&mut self, _: f64) {}
+
+    fn set_ref(&mut self, _: Self) {}
+    fn set_weak_ref(&mut self, _: Self) {}
+    fn set_alias(&mut self, _: Self) {}
+
+    fn set_array(&mut self, _: ()) {}
+    fn set_hash(&mut self, _: ()) {}
+
+    fn set_binary(&mut self, _: &'buf [u8]) {}
+    fn set_string(&mut self, _: &'buf [u8]) {}
+
+    fn set_object(&mut self, _: Self, _: Self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_object_freeze(&mut self, _: Self, _: Self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_regexp(&mut self, _: Self, _: Self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Builder that discards everything, for checking that a document is
+/// well-formed without constructing it.
+pub struct NullBuilder;
+
+impl<'buf> parser::Builder<'buf> for NullBuilder {
+    type Value = Value;
+    type ArrayBuilder = ();
+    type HashBuilder = ();
+
+    fn new(&mut self) -> Value {
+        Value
+    }
+
+    fn build_array(&mut self, _: u64) {}
+
+    fn build_hash(&mut self, _: u64) {}
+}
+
+impl<'buf> parser::ArrayBuilder<'buf, Value> for () {
+    fn insert(&mut self, _: Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn finalize(self) {}
+}
+
+impl<'buf> parser::HashBuilder<'buf, Value> for () {
+    fn insert(&mut self, _: &'buf [u8], _: Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn finalize(self) {}
+}
+
+pub fn parse(s: &[u8]) -> Result<()> {
+    parser::parse(s, NullBuilder).map(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use arc;
+    use null::parse;
+
+    #[test]
+    fn test_valid() {
+        assert!(parse(b"\x01").is_ok());
+        assert!(parse(b"\x2a\x02\x63foo\x63bar\x64ook\x00\x64eek\x00").is_ok());
+        assert!(parse(b"\x43\x41\x01\x2f\x02\x2f\x02").is_ok());
+        assert!(parse(b"\x42\x2c\x63foo\x28\x2a\x00\x2d\x03\x28\x2a\x00").is_ok());
+        assert!(parse(b"\xa9\x01").is_ok());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let inputs: &[&[u8]] = &[
+            b"\x2b\x02\x00",
+            b"\x2a\x01\x63foo\x2a\x01\x63bar",
+            b"\x2a\x01\x00\x63foo",
+            b"\x42\x01\x2f\x01",
+            b"\x29\x05",
+            b"\x34",
+        ];
+
+        for s in inputs {
+            let expected = format!("{:?}", arc::parse(s).unwrap_err());
+            assert_eq!(format!("{:?}", parse(s).unwrap_err()), expected);
+        }
+    }
+}
@@ -1,5 +1,9 @@
+use std::error;
+use std::fmt;
 use std::result;
-use std::collections::HashMap;
+use std::str;
+use std::collections::{HashMap, HashSet};
+use byteorder::{ByteOrder, LittleEndian};
 use config::Config;
 use reader::{self, Reader};
 
@@ -13,20 +17,104 @@ pub enum Error {
     VarintOverflow,
     ArrayTooLarge { count: u64, limit: u64 },
     HashTooLarge { count: u64, limit: u64 },
+    StringTooLarge { len: usize, limit: u64 },
+    DepthLimitExceeded { limit: u64 },
+    TooManyRefs { limit: u64 },
+    TooManyClasses { limit: u64 },
+    TotalSizeExceeded { limit: u64 },
+    TooManyLeaves { limit: u64 },
+    TrailingData { pos: usize },
+    UnknownTag(u8),
+    UnsupportedExtension(u8),
+    InvalidUtf8 { pos: usize },
+    DuplicateKey(Vec<u8>),
 }
 
 impl Error {
     pub fn is_eof(&self) -> bool {
+        matches!(*self, Error::UnexpectedEof)
+    }
+
+    pub fn is_invalid_copy(&self) -> bool {
+        matches!(*self, Error::InvalidCopy)
+    }
+
+    pub fn is_unknown_tag(&self) -> bool {
+        matches!(*self, Error::UnknownTag(_))
+    }
+
+    pub fn as_unknown_tag(&self) -> Option<u8> {
         match *self {
-            Error::UnexpectedEof => true,
-            _ => false,
+            Error::UnknownTag(tag) => Some(tag),
+            _ => None,
         }
     }
 
-    pub fn is_invalid_copy(&self) -> bool {
+    pub fn as_unsupported_extension(&self) -> Option<u8> {
+        match *self {
+            Error::UnsupportedExtension(sel) => Some(sel),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    #[allow(deprecated)]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
         match *self {
-            Error::InvalidCopy => true,
-            _ => false,
+            InvalidRef(p) => write!(f, "invalid reference to offset {}", p),
+            ArrayTooLarge { count, limit } => {
+                write!(f, "array of {} elements exceeds the limit of {}", count, limit)
+            }
+            HashTooLarge { count, limit } => {
+                write!(f, "hash of {} entries exceeds the limit of {}", count, limit)
+            }
+            StringTooLarge { len, limit } => {
+                write!(f, "string of {} bytes exceeds the limit of {}", len, limit)
+            }
+            DepthLimitExceeded { limit } => write!(f, "nesting deeper than {} levels", limit),
+            TooManyRefs { limit } => write!(f, "more than {} tracked values", limit),
+            TooManyClasses { limit } => write!(f, "more than {} object classes", limit),
+            TotalSizeExceeded { limit } => {
+                write!(f, "decoded values would take more than {} bytes", limit)
+            }
+            TooManyLeaves { limit } => write!(f, "more than {} leaves", limit),
+            TrailingData { pos } => write!(f, "trailing data at {}", pos),
+            UnknownTag(tag) => write!(f, "unknown tag 0x{:02x}", tag),
+            UnsupportedExtension(sel) => write!(f, "unsupported extension 0x{:02x}", sel),
+            InvalidUtf8 { pos } => write!(f, "invalid utf-8 string at {}", pos),
+            DuplicateKey(ref key) => {
+                write!(f, "duplicate hash key {:?}", String::from_utf8_lossy(key))
+            }
+            _ => write!(f, "{}", error::Error::description(self)),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        use self::Error::*;
+        match *self {
+            InvalidType => "invalid type",
+            InvalidRef(_) => "invalid reference",
+            InvalidCopy => "invalid copy",
+            UnexpectedEof => "unexpected eof",
+            OffsetOverflow => "offset overflow",
+            VarintOverflow => "varint overflow",
+            ArrayTooLarge { .. } => "array too large",
+            HashTooLarge { .. } => "hash too large",
+            StringTooLarge { .. } => "string too large",
+            DepthLimitExceeded { .. } => "nesting too deep",
+            TooManyRefs { .. } => "too many tracked values",
+            TooManyClasses { .. } => "too many object classes",
+            TotalSizeExceeded { .. } => "total size exceeded",
+            TooManyLeaves { .. } => "too many leaves",
+            TrailingData { .. } => "trailing data",
+            UnknownTag(_) => "unknown tag",
+            UnsupportedExtension(_) => "unsupported extension",
+            InvalidUtf8 { .. } => "invalid utf-8",
+            DuplicateKey(_) => "duplicate hash key",
         }
     }
 }
@@ -43,6 +131,65 @@ impl From<reader::Error> for Error {
 
 pub type Result<V> = result::Result<V, Error>;
 
+/// An `Error` together with the body offset where parsing stopped, as
+/// returned by `Parser::parse_positioned`.
+#[derive(Debug)]
+pub struct PositionedError {
+    pub error: Error,
+    pub pos: usize,
+}
+
+impl fmt::Display for PositionedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.error, self.pos)
+    }
+}
+
+impl error::Error for PositionedError {
+    #[allow(deprecated)]
+    fn description(&self) -> &str {
+        error::Error::description(&self.error)
+    }
+
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Scalar decoded from the payload of an `EXTEND` tag.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Extension {
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+}
+
+/// Reads the payload that follows an `EXTEND` tag and its sub-tag byte.
+/// Registered per sub-tag with `Config::with_extension`.
+pub type ExtensionHandler = fn(&mut Reader) -> reader::Result<Extension>;
+
+/// `ExtensionHandler` for IEEE 754 half-precision floats, stored as two
+/// little-endian bytes and decoded as `F32`. Sereal assigns no sub-tag to
+/// them, so it only takes effect once registered.
+pub fn half_float(reader: &mut Reader) -> reader::Result<Extension> {
+    let h = LittleEndian::read_u16(reader.read_bytes(2)?);
+    let sign = (h as u32 >> 15) << 31;
+    let exp = (h as u32 >> 10) & 0x1f;
+    let frac = h as u32 & 0x3ff;
+
+    let bits = match exp {
+        // Subnormal: frac * 2^-24, exact in an f32.
+        0 => {
+            let v = frac as f32 / (1 << 24) as f32;
+            return Ok(Extension::F32(if sign != 0 { -v } else { v }));
+        }
+        0x1f => sign | 0x7f80_0000 | (frac << 13),
+        _ => sign | ((exp + 127 - 15) << 23) | (frac << 13),
+    };
+    Ok(Extension::F32(f32::from_bits(bits)))
+}
+
 pub trait Value<'buf>: Clone {
     type Array;
     type Hash;
@@ -51,6 +198,16 @@ pub trait Value<'buf>: Clone {
     fn set_true(&mut self);
     fn set_false(&mut self);
 
+    /// Called for the `TRUE` and `FALSE` tags. Builders that represent
+    /// booleans differently, e.g. as 0 and 1, can override this alone.
+    fn set_bool(&mut self, b: bool) {
+        if b {
+            self.set_true()
+        } else {
+            self.set_false()
+        }
+    }
+
     fn set_i64(&mut self, v: i64);
     fn set_u64(&mut self, v: u64);
     fn set_f32(&mut self, v: f32);
@@ -86,18 +243,98 @@ pub trait Builder<'buf> {
     type ArrayBuilder: ArrayBuilder<'buf, Self::Value>;
     type HashBuilder: HashBuilder<'buf, Self::Value>;
 
+    #[allow(clippy::wrong_self_convention)]
     fn new(&mut self) -> Self::Value;
 
     fn build_array(&mut self, size: u64) -> Self::ArrayBuilder;
     fn build_hash(&mut self, size: u64) -> Self::HashBuilder;
+
+    /// Called when a value at offset `pos` is recorded as a reference target.
+    fn on_track(&mut self, _pos: usize) {}
+
+    /// Called when a `REFP`, `ALIAS` or `OBJECTV` resolves offset `target`.
+    fn on_reference(&mut self, _target: usize) {}
+
+    /// Bless `value` into `class`. Builders that keep state about classes
+    /// can override this; by default it is `Value::set_object`.
+    fn set_object(
+        &mut self,
+        value: &mut Self::Value,
+        class: Self::Value,
+        obj: Self::Value,
+    ) -> Result<()> {
+        value.set_object(class, obj)
+    }
+
+    /// As `set_object`, for `OBJECT_FREEZE` and `OBJECTV_FREEZE`.
+    fn set_object_freeze(
+        &mut self,
+        value: &mut Self::Value,
+        class: Self::Value,
+        obj: Self::Value,
+    ) -> Result<()> {
+        value.set_object_freeze(class, obj)
+    }
+
+    /// Number of distinct class names the builder keeps, checked against
+    /// `Config::max_object_classes` after every object. Builders that do
+    /// not intern class names keep none.
+    fn class_count(&self) -> u64 {
+        0
+    }
+}
+
+/// A container waiting for its children in `Parser::parse_iterative`.
+enum Frame<'buf, B: Builder<'buf>> {
+    Ref(B::Value),
+    WeakRef(B::Value),
+    Copy(B::Value),
+    Array {
+        value: B::Value,
+        items: B::ArrayBuilder,
+        remaining: u64,
+        wrap: bool,
+    },
+    Hash {
+        value: B::Value,
+        items: B::HashBuilder,
+        remaining: u64,
+        key: &'buf [u8],
+        keys: Option<HashSet<&'buf [u8]>>,
+        wrap: bool,
+        old_copy_pos: usize,
+    },
+    Object {
+        value: B::Value,
+        class: Option<B::Value>,
+        freeze: bool,
+    },
+    Regexp {
+        value: B::Value,
+        pattern: Option<B::Value>,
+    },
 }
 
+/// Estimated cost in bytes of one value, or of one slot reserved for it in
+/// an array or hash, counted against `Config::max_total_bytes`. String
+/// contents are counted by their length on top of that.
+pub const VALUE_COST: u64 = 16;
+
 pub struct Parser<'a, 'buf, B: Builder<'buf>> {
     config: &'a Config,
     reader: Reader<'buf>,
+    // Keyed by Sereal offset: the 1-based position of the tag byte within
+    // the body, which is `reader.pos()` right after the tag has been read.
+    // Seeking to an offset `p` therefore means `set_pos(p - 1)`.
+    // The map allocates on first insert, so documents without tracked
+    // values never pay for it.
     track: HashMap<usize, B::Value>,
     builder: B,
     copy_pos: usize,
+    depth: u64,
+    total_bytes: u64,
+    // Reserved tags skipped because of `allow_unknown_tags`.
+    skipped_tags: Vec<u8>,
 }
 
 impl<'a, 'buf, B: Builder<'buf>> Parser<'a, 'buf, B> {
@@ -108,65 +345,480 @@ impl<'a, 'buf, B: Builder<'buf>> Parser<'a, 'buf, B> {
             track: HashMap::new(),
             builder: builder,
             copy_pos: 0,
+            depth: 0,
+            total_bytes: 0,
+            skipped_tags: Vec::new(),
         }
     }
 
+    /// Parse the root value. Input after it is ignored, unless the config
+    /// sets `require_eof`.
     pub fn parse(&mut self) -> Result<B::Value> {
-        self.parse_inner(false)
+        let value = self.parse_inner(false)?;
+        if self.config.require_eof() {
+            self.check_eof()?;
+        }
+        Ok(value)
+    }
+
+    /// Same as `parse`, but always fails with `TrailingData` unless the
+    /// root value spans the whole input.
+    pub fn parse_exact(&mut self) -> Result<B::Value> {
+        let value = self.parse_inner(false)?;
+        self.check_eof()?;
+        Ok(value)
+    }
+
+    /// Same as `parse`, but errors carry the position in the body where
+    /// parsing stopped, as reported by `pos()` afterwards.
+    pub fn parse_positioned(&mut self) -> result::Result<B::Value, PositionedError> {
+        self.parse().map_err(|e| PositionedError {
+            error: e,
+            pos: self.reader.pos(),
+        })
+    }
+
+    /// Length of the input taken by the values parsed so far. After a
+    /// successful `parse`, whatever follows the root value in the slice
+    /// starts at this offset.
+    pub fn bytes_consumed(&self) -> usize {
+        self.reader.pos()
+    }
+
+    /// Reserved tags skipped so far because the config sets
+    /// `allow_unknown_tags`, in the order they were met and without the
+    /// track bit. Callers can use this to warn about such documents.
+    pub fn skipped_tags(&self) -> &[u8] {
+        &self.skipped_tags
+    }
+
+    /// Position in the body and the bytes around it, as in
+    /// `Reader::context`. After an error this is where parsing stopped.
+    pub fn context(&self, radius: usize) -> (usize, &'buf [u8]) {
+        self.reader.context(radius)
+    }
+
+    /// Same as `parse`, but keeps pending containers on a heap-allocated
+    /// stack instead of recursing, so nesting depth is bounded by memory
+    /// rather than by the thread's stack size.
+    pub fn parse_iterative(&mut self) -> Result<B::Value> {
+        use sereal_common::constants::*;
+
+        let mut stack: Vec<Frame<'buf, B>> = Vec::new();
+        let mut force_track = false;
+
+        loop {
+            self.check_depth(stack.len() as u64 + 1)?;
+            let (tag, mut value) = self.start_value(force_track)?;
+            force_track = false;
+
+            let mut done = match tag {
+                REFN => {
+                    stack.push(Frame::Ref(value));
+                    continue;
+                }
+
+                WEAKEN => {
+                    stack.push(Frame::WeakRef(value));
+                    continue;
+                }
+
+                COPY => {
+                    if self.copy_pos != 0 {
+                        return Err(Error::InvalidCopy);
+                    }
+
+                    let pos = self.reader.read_varlen()?;
+                    if pos == 0 || pos >= self.reader.pos() {
+                        return Err(Error::InvalidCopy);
+                    }
+
+                    self.copy_pos = self.reader.set_pos(pos - 1);
+                    stack.push(Frame::Copy(value));
+                    continue;
+                }
+
+                ARRAY | ARRAYREF_0...ARRAYREF_15 => {
+                    let wrap = tag != ARRAY;
+                    let count = if wrap {
+                        (tag - ARRAYREF_0) as u64
+                    } else {
+                        self.reader.read_varint()?
+                    };
+
+                    self.check_array_size(count)?;
+                    let items = self.builder.build_array(count);
+
+                    if count > 0 {
+                        stack.push(Frame::Array {
+                            value: value,
+                            items: items,
+                            remaining: count,
+                            wrap: wrap,
+                        });
+                        continue;
+                    }
+
+                    self.finish_array(value, items.finalize(), wrap)
+                }
+
+                HASH | HASHREF_0...HASHREF_15 => {
+                    let wrap = tag != HASH;
+                    let count = if wrap {
+                        (tag - HASHREF_0) as u64
+                    } else {
+                        self.reader.read_varint()?
+                    };
+
+                    self.check_hash_size(count)?;
+                    let items = self.builder.build_hash(count);
+
+                    if count > 0 {
+                        let old_copy_pos = self.copy_pos;
+                        self.copy_pos = 0;
+
+                        let mut keys = self.key_set(count);
+                        let key = self.parse_str()?;
+                        check_key(&mut keys, key)?;
+                        stack.push(Frame::Hash {
+                            value: value,
+                            items: items,
+                            remaining: count,
+                            key: key,
+                            keys: keys,
+                            wrap: wrap,
+                            old_copy_pos: old_copy_pos,
+                        });
+                        continue;
+                    }
+
+                    self.finish_hash(value, items.finalize(), wrap)
+                }
+
+                OBJECT | OBJECT_FREEZE => {
+                    stack.push(Frame::Object {
+                        value: value,
+                        class: None,
+                        freeze: tag == OBJECT_FREEZE,
+                    });
+                    force_track = true;
+                    continue;
+                }
+
+                OBJECTV | OBJECTV_FREEZE => {
+                    let pos = self.reader.read_varlen()?;
+                    stack.push(Frame::Object {
+                        value: value,
+                        class: Some(self.get(pos)?),
+                        freeze: tag == OBJECTV_FREEZE,
+                    });
+                    continue;
+                }
+
+                REGEXP => {
+                    stack.push(Frame::Regexp {
+                        value: value,
+                        pattern: None,
+                    });
+                    continue;
+                }
+
+                _ => {
+                    self.parse_value(tag, &mut value)?;
+                    value
+                }
+            };
+
+            // Hand the finished value to the enclosing containers, closing
+            // every one that is now complete.
+            loop {
+                let frame = match stack.pop() {
+                    Some(frame) => frame,
+                    None => {
+                        if self.config.require_eof() {
+                            self.check_eof()?;
+                        }
+                        return Ok(done);
+                    }
+                };
+
+                match frame {
+                    Frame::Ref(mut value) => {
+                        value.set_ref(done);
+                        done = value;
+                    }
+
+                    Frame::WeakRef(mut value) => {
+                        value.set_weak_ref(done);
+                        done = value;
+                    }
+
+                    Frame::Copy(mut value) => {
+                        self.reader.set_pos(self.copy_pos);
+                        self.copy_pos = 0;
+                        value.set_alias(done);
+                        done = value;
+                    }
+
+                    Frame::Array {
+                        value,
+                        mut items,
+                        remaining,
+                        wrap,
+                    } => {
+                        items.insert(done)?;
+
+                        if remaining > 1 {
+                            stack.push(Frame::Array {
+                                value: value,
+                                items: items,
+                                remaining: remaining - 1,
+                                wrap: wrap,
+                            });
+                            break;
+                        }
+
+                        done = self.finish_array(value, items.finalize(), wrap);
+                    }
+
+                    Frame::Hash {
+                        value,
+                        mut items,
+                        remaining,
+                        key,
+                        mut keys,
+                        wrap,
+                        old_copy_pos,
+                    } => {
+                        items.insert(key, done)?;
+
+                        if remaining > 1 {
+                            let key = self.parse_str()?;
+                            check_key(&mut keys, key)?;
+                            stack.push(Frame::Hash {
+                                value: value,
+                                items: items,
+                                remaining: remaining - 1,
+                                key: key,
+                                keys: keys,
+                                wrap: wrap,
+                                old_copy_pos: old_copy_pos,
+                            });
+                            break;
+                        }
+
+                        self.copy_pos = old_copy_pos;
+                        done = self.finish_hash(value, items.finalize(), wrap);
+                    }
+
+                    Frame::Object {
+                        value,
+                        class: None,
+                        freeze,
+                    } => {
+                        stack.push(Frame::Object {
+                            value: value,
+                            class: Some(done),
+                            freeze: freeze,
+                        });
+                        break;
+                    }
+
+                    Frame::Object {
+                        mut value,
+                        class: Some(class),
+                        freeze,
+                    } => {
+                        self.bless(&mut value, class, done, freeze)?;
+                        done = value;
+                    }
+
+                    Frame::Regexp {
+                        value,
+                        pattern: None,
+                    } => {
+                        stack.push(Frame::Regexp {
+                            value: value,
+                            pattern: Some(done),
+                        });
+                        break;
+                    }
+
+                    Frame::Regexp {
+                        mut value,
+                        pattern: Some(pattern),
+                    } => {
+                        value.set_regexp(pattern, done)?;
+                        done = value;
+                    }
+                }
+            }
+        }
+    }
+
+    fn finish_array(
+        &mut self,
+        mut value: B::Value,
+        array: <B::Value as Value<'buf>>::Array,
+        wrap: bool,
+    ) -> B::Value {
+        if wrap {
+            let mut inner = self.builder.new();
+            inner.set_array(array);
+            value.set_ref(inner);
+        } else {
+            value.set_array(array);
+        }
+        value
+    }
+
+    fn finish_hash(
+        &mut self,
+        mut value: B::Value,
+        hash: <B::Value as Value<'buf>>::Hash,
+        wrap: bool,
+    ) -> B::Value {
+        if wrap {
+            let mut inner = self.builder.new();
+            inner.set_hash(hash);
+            value.set_ref(inner);
+        } else {
+            value.set_hash(hash);
+        }
+        value
     }
 
     fn parse_str(&mut self) -> Result<&'buf [u8]> {
         use sereal_common::constants::*;
 
-        let tag = self.reader.read_tag()?;
+        let tag = self.read_tag()?;
         let tag = tag & TYPE_MASK;
 
         match tag {
             SHORT_BINARY_0...SHORT_BINARY_31 => {
-                let len = tag - SHORT_BINARY_0;
-                Ok(self.reader.read_bytes(len.into())?)
+                let len = (tag - SHORT_BINARY_0) as usize;
+                self.check_len(len, self.config.max_key_len())?;
+                Ok(self.reader.read_bytes(len)?)
             }
 
-            STR_UTF8 | BINARY => {
-                let len = self.reader.read_varlen()?;
+            BINARY => {
+                let len = self.read_len(self.config.max_key_len())?;
                 Ok(self.reader.read_bytes(len)?)
             }
 
+            // Keys are always validated: unlike values, they are handed to
+            // `HashBuilder::insert` as raw bytes, with the tag lost.
+            STR_UTF8 => self.read_utf8(self.config.max_key_len(), true),
+
             COPY => Ok(self.do_copy(|p| p.parse_str())?),
 
+            // Written by encoders that deduplicate keys by reference.
+            REFP => {
+                let p = self.reader.read_varlen()?;
+                self.parse_str_ref(p)
+            }
+
             _ => Err(Error::InvalidType),
         }
     }
 
+    /// Read the string a `REFP` key points to. The target must be a string
+    /// tag with the track bit set.
+    fn parse_str_ref(&mut self, p: usize) -> Result<&'buf [u8]> {
+        use sereal_common::constants::*;
+
+        if p == 0 || p >= self.reader.pos() {
+            return Err(Error::InvalidRef(p));
+        }
+
+        let prev = self.reader.set_pos(p - 1);
+        let res = match self.reader.read_u8() {
+            Ok(tag) if tag & TRACK_BIT == 0 => Err(Error::InvalidRef(p)),
+            Ok(tag) => match tag & TYPE_MASK {
+                SHORT_BINARY_0...SHORT_BINARY_31 | BINARY | STR_UTF8 => {
+                    self.reader.set_pos(p - 1);
+                    self.parse_str()
+                }
+                _ => Err(Error::InvalidType),
+            },
+            Err(e) => Err(e.into()),
+        };
+        self.reader.set_pos(prev);
+
+        res
+    }
+
     fn parse_inner(&mut self, force_track: bool) -> Result<B::Value> {
+        self.depth += 1;
+
+        let res = self.check_depth(self.depth).and_then(|_| {
+            let (tag, mut value) = self.start_value(force_track)?;
+            self.parse_value(tag, &mut value)?;
+            Ok(value)
+        });
+
+        self.depth -= 1;
+        res
+    }
+
+    /// Read the next tag, skipping reserved tags if the config allows them.
+    fn read_tag(&mut self) -> Result<u8> {
+        use sereal_common::constants::*;
+
+        loop {
+            let tag = self.reader.read_tag()?;
+            match tag & TYPE_MASK {
+                t @ RESERVED_0...RESERVED_4 if self.config.allow_unknown_tags() => {
+                    self.skipped_tags.push(t);
+                }
+                _ => return Ok(tag),
+            }
+        }
+    }
+
+    /// Read the next tag and create the value it describes, recording it
+    /// as a reference target if needed. Returns the tag without track bit.
+    fn start_value(&mut self, force_track: bool) -> Result<(u8, B::Value)> {
         use sereal_common::constants::*;
 
-        let tag = self.reader.read_tag()?;
+        let tag = self.read_tag()?;
 
         let track = tag & TRACK_BIT != 0;
         let tag = tag & TYPE_MASK;
 
-        let mut value = self.builder.new();
+        self.charge(VALUE_COST)?;
+        let value = self.builder.new();
 
         if track || force_track {
-            self.track.insert(self.reader.pos(), value.clone());
+            let pos = self.reader.pos();
+            self.track.insert(pos, value.clone());
+            if self.track.len() as u64 > self.config.max_tracked_refs() {
+                return Err(Error::TooManyRefs { limit: self.config.max_tracked_refs() });
+            }
+            self.builder.on_track(pos);
         }
 
+        Ok((tag, value))
+    }
+
+    fn parse_value(&mut self, tag: u8, value: &mut B::Value) -> Result<()> {
+        use sereal_common::constants::*;
+
         match tag {
             UNDEF | CANONICAL_UNDEF => value.set_undef(),
 
             POS_0...POS_15 => value.set_u64(tag as u64),
-            NEG_16...NEG_1 => value.set_i64((tag | 0xf0) as i64),
+            NEG_16...NEG_1 => value.set_i64((tag | 0xf0) as i8 as i64),
 
             VARINT => value.set_u64(self.reader.read_varint()?),
             ZIGZAG => value.set_i64(self.reader.read_zigzag()?),
             FLOAT => value.set_f32(self.reader.read_f32()?),
             DOUBLE => value.set_f64(self.reader.read_f64()?),
 
-            TRUE => value.set_true(),
-            FALSE => value.set_false(),
+            TRUE => value.set_bool(true),
+            FALSE => value.set_bool(false),
 
-            REFN => value.set_ref(self.parse()?),
+            REFN => value.set_ref(self.parse_inner(false)?),
 
             REFP => {
                 let p = self.reader.read_varlen()?;
@@ -178,15 +830,20 @@ impl<'a, 'buf, B: Builder<'buf>> Parser<'a, 'buf, B> {
                 value.set_alias(self.get(p)?)
             }
 
-            COPY => value.set_alias(self.do_copy(|p| p.parse())?),
+            COPY => value.set_alias(self.do_copy(|p| p.parse_inner(false))?),
 
-            WEAKEN => value.set_weak_ref(self.parse()?),
+            WEAKEN => value.set_weak_ref(self.parse_inner(false)?),
 
             ARRAY => {
                 let len = self.reader.read_varint()?;
                 value.set_array(self.parse_array(len)?);
             }
 
+            MANY => {
+                let len = self.reader.read_varint()?;
+                value.set_array(self.parse_many(len)?);
+            }
+
             ARRAYREF_0...ARRAYREF_15 => {
                 let len = tag - ARRAYREF_0;
                 let array = self.parse_array(len as u64)?;
@@ -209,49 +866,83 @@ impl<'a, 'buf, B: Builder<'buf>> Parser<'a, 'buf, B> {
             }
 
             BINARY => {
-                let len = self.reader.read_varlen()?;
+                let len = self.read_len(self.config.max_string_len())?;
                 value.set_binary(self.reader.read_bytes(len)?);
             }
 
             STR_UTF8 => {
-                let len = self.reader.read_varlen()?;
-                value.set_string(self.reader.read_bytes(len)?);
+                let limit = self.config.max_string_len();
+                value.set_string(self.read_utf8(limit, self.config.strict_utf8())?)
             }
 
             SHORT_BINARY_0...SHORT_BINARY_31 => {
                 let len = tag - SHORT_BINARY_0;
+                self.charge(len.into())?;
                 value.set_binary(self.reader.read_bytes(len.into())?);
             }
 
-            OBJECT => value.set_object(self.parse_inner(true)?, self.parse()?)?,
+            OBJECT => {
+                let class = self.parse_inner(true)?;
+                let obj = self.parse_inner(false)?;
+                self.bless(value, class, obj, false)?;
+            }
 
             OBJECTV => {
                 let pos = self.reader.read_varlen()?;
-                value.set_object(self.get(pos)?, self.parse()?)?;
+                let class = self.get(pos)?;
+                let obj = self.parse_inner(false)?;
+                self.bless(value, class, obj, false)?;
             }
 
             OBJECT_FREEZE => {
-                value.set_object_freeze(
-                    self.parse_inner(true)?,
-                    self.parse()?,
-                )?
+                let class = self.parse_inner(true)?;
+                let obj = self.parse_inner(false)?;
+                self.bless(value, class, obj, true)?;
             }
 
             OBJECTV_FREEZE => {
                 let pos = self.reader.read_varlen()?;
-                value.set_object_freeze(self.get(pos)?, self.parse()?)?;
+                let class = self.get(pos)?;
+                let obj = self.parse_inner(false)?;
+                self.bless(value, class, obj, true)?;
             }
 
-            REGEXP => value.set_regexp(self.parse()?, self.parse()?)?,
+            REGEXP => value.set_regexp(self.parse_inner(false)?, self.parse_inner(false)?)?,
+
+            // Decoded by the handler the config registers for the sub-tag.
+            EXTEND => {
+                let sel = self.reader.read_u8()?;
+                let handler = self.config.extension(sel).ok_or(Error::UnsupportedExtension(sel))?;
+                match handler(&mut self.reader)? {
+                    Extension::I64(v) => value.set_i64(v),
+                    Extension::U64(v) => value.set_u64(v),
+                    Extension::F32(v) => value.set_f32(v),
+                    Extension::F64(v) => value.set_f64(v),
+                }
+            }
 
-            _ => unimplemented!(),
+            _ => return Err(Error::UnknownTag(tag)),
         };
 
-        Ok(value)
+        Ok(())
+    }
+
+    fn read_utf8(&mut self, limit: u64, strict: bool) -> Result<&'buf [u8]> {
+        let len = self.read_len(limit)?;
+        let pos = self.reader.pos();
+        let s = self.reader.read_bytes(len)?;
+
+        if strict && str::from_utf8(s).is_err() {
+            return Err(Error::InvalidUtf8 { pos: pos });
+        }
+
+        Ok(s)
     }
 
-    fn get(&self, p: usize) -> Result<B::Value> {
-        self.track.get(&p).cloned().ok_or(Error::InvalidRef(p))
+    fn get(&mut self, p: usize) -> Result<B::Value> {
+        let value = self.track.get(&p).cloned().ok_or(Error::InvalidRef(p))?;
+        self.builder.on_reference(p);
+        Ok(value)
     }
 
     fn do_copy<T, F: FnOnce(&mut Self) -> Result<T>>(&mut self, f: F) -> Result<T> {
@@ -260,6 +951,10 @@ impl<'a, 'buf, B: Builder<'buf>> Parser<'a, 'buf, B> {
         }
 
         let pos = self.reader.read_varlen()?;
+        if pos == 0 || pos >= self.reader.pos() {
+            return Err(Error::InvalidCopy);
+        }
+
         self.copy_pos = self.reader.set_pos(pos - 1);
 
         let val = f(self);
@@ -270,37 +965,165 @@ impl<'a, 'buf, B: Builder<'buf>> Parser<'a, 'buf, B> {
         val
     }
 
-    fn parse_array(&mut self, count: u64) -> Result<<B::Value as Value<'buf>>::Array> {
+    /// Read the length of a `BINARY` or `STR_UTF8` payload.
+    fn read_len(&mut self, limit: u64) -> Result<usize> {
+        let len = self.reader.read_varlen()?;
+        self.check_len(len, limit)?;
+        Ok(len)
+    }
+
+    fn check_len(&mut self, len: usize, limit: u64) -> Result<()> {
+        if len as u64 > limit {
+            return Err(Error::StringTooLarge {
+                len: len,
+                limit: limit,
+            });
+        }
+        self.charge(len as u64)
+    }
+
+    fn check_depth(&self, depth: u64) -> Result<()> {
+        if depth > self.config.max_depth() {
+            return Err(Error::DepthLimitExceeded { limit: self.config.max_depth() });
+        }
+        Ok(())
+    }
+
+    fn check_array_size(&mut self, count: u64) -> Result<()> {
         if count > self.config.max_array_size() {
             return Err(Error::ArrayTooLarge {
                 count: count,
                 limit: self.config.max_array_size(),
             });
         }
+        self.charge(count.saturating_mul(VALUE_COST))
+    }
+
+    fn check_hash_size(&mut self, count: u64) -> Result<()> {
+        if count > self.config.max_hash_size() {
+            return Err(Error::HashTooLarge {
+                count: count,
+                limit: self.config.max_hash_size(),
+            });
+        }
+        self.charge(count.saturating_mul(VALUE_COST))
+    }
+
+    /// Add `bytes` to the estimated size of the decoded document.
+    fn charge(&mut self, bytes: u64) -> Result<()> {
+        self.total_bytes = self.total_bytes.saturating_add(bytes);
+        if self.total_bytes > self.config.max_total_bytes() {
+            return Err(Error::TotalSizeExceeded { limit: self.config.max_total_bytes() });
+        }
+        Ok(())
+    }
+
+    /// Bless `value` through the builder, then check the class names it
+    /// keeps against `max_object_classes`.
+    fn bless(
+        &mut self,
+        value: &mut B::Value,
+        class: B::Value,
+        obj: B::Value,
+        freeze: bool,
+    ) -> Result<()> {
+        if freeze {
+            self.builder.set_object_freeze(value, class, obj)?;
+        } else {
+            self.builder.set_object(value, class, obj)?;
+        }
+
+        if self.builder.class_count() > self.config.max_object_classes() {
+            return Err(Error::TooManyClasses { limit: self.config.max_object_classes() });
+        }
+        Ok(())
+    }
+
+    fn parse_array(&mut self, count: u64) -> Result<<B::Value as Value<'buf>>::Array> {
+        self.check_array_size(count)?;
 
         let mut v = self.builder.build_array(count);
         for _ in 0..count {
-            let value = self.parse()?;
+            let value = self.parse_inner(false)?;
             v.insert(value)?;
         }
         Ok(v.finalize())
     }
 
-    fn parse_hash(&mut self, count: u64) -> Result<<B::Value as Value<'buf>>::Hash> {
-        if count > self.config.max_hash_size() {
-            return Err(Error::HashTooLarge {
-                count: count,
-                limit: self.config.max_hash_size(),
-            });
+    /// Decode a `MANY` body: a single element tag followed by `count`
+    /// untagged payloads of that type. Only scalar types are allowed.
+    fn parse_many(&mut self, count: u64) -> Result<<B::Value as Value<'buf>>::Array> {
+        use sereal_common::constants::*;
+
+        self.check_array_size(count)?;
+
+        let tag = self.reader.read_u8()? & TYPE_MASK;
+        match tag {
+            UNDEF | CANONICAL_UNDEF | TRUE | FALSE => (),
+            POS_0...NEG_1 | VARINT | ZIGZAG | FLOAT | DOUBLE => (),
+            BINARY | STR_UTF8 => (),
+            _ => return Err(Error::InvalidType),
+        }
+
+        let mut v = self.builder.build_array(count);
+        for _ in 0..count {
+            self.charge(VALUE_COST)?;
+            let mut value = self.builder.new();
+
+            match tag {
+                UNDEF | CANONICAL_UNDEF => value.set_undef(),
+                TRUE => value.set_bool(true),
+                FALSE => value.set_bool(false),
+                POS_0...POS_15 => value.set_u64(tag as u64),
+                NEG_16...NEG_1 => value.set_i64((tag | 0xf0) as i8 as i64),
+                VARINT => value.set_u64(self.reader.read_varint()?),
+                ZIGZAG => value.set_i64(self.reader.read_zigzag()?),
+                FLOAT => value.set_f32(self.reader.read_f32()?),
+                DOUBLE => value.set_f64(self.reader.read_f64()?),
+                BINARY => {
+                    let len = self.read_len(self.config.max_string_len())?;
+                    value.set_binary(self.reader.read_bytes(len)?);
+                }
+                STR_UTF8 => {
+                    let limit = self.config.max_string_len();
+                    value.set_string(self.read_utf8(limit, self.config.strict_utf8())?)
+                }
+                _ => unreachable!(),
+            }
+
+            v.insert(value)?;
         }
+        Ok(v.finalize())
+    }
+
+    fn check_eof(&self) -> Result<()> {
+        if !self.reader.is_empty() {
+            return Err(Error::TrailingData { pos: self.reader.pos() });
+        }
+        Ok(())
+    }
+
+    /// Set of keys seen so far in a hash, if duplicates are to be rejected.
+    fn key_set(&self, count: u64) -> Option<HashSet<&'buf [u8]>> {
+        if self.config.reject_duplicate_keys() {
+            Some(HashSet::with_capacity(count as usize))
+        } else {
+            None
+        }
+    }
+
+    fn parse_hash(&mut self, count: u64) -> Result<<B::Value as Value<'buf>>::Hash> {
+        self.check_hash_size(count)?;
 
         let old_copy_pos = self.copy_pos;
         self.copy_pos = 0;
 
+        let mut keys = self.key_set(count);
         let mut m = self.builder.build_hash(count);
         for _ in 0..count {
             let k = self.parse_str()?;
-            let v = self.parse()?;
+            check_key(&mut keys, k)?;
+            let v = self.parse_inner(false)?;
             m.insert(k, v)?;
         }
 
@@ -310,6 +1133,85 @@ impl<'a, 'buf, B: Builder<'buf>> Parser<'a, 'buf, B> {
     }
 }
 
+/// Decodes the elements of a top-level array one at a time, without
+/// building the array itself.
+///
+/// The body must hold an `ARRAY`, an `ARRAYREF_N`, or a `REFN` followed by
+/// an `ARRAY`. Elements share one parser, so references from an element to
+/// values tracked in earlier ones resolve as usual. The array and the
+/// reference to it are never built, so references to them fail with
+/// `InvalidRef`. Iteration stops after the first error.
+pub struct ArrayStream<'a, 'buf, B: Builder<'buf>> {
+    parser: Parser<'a, 'buf, B>,
+    remaining: u64,
+}
+
+impl<'a, 'buf, B: Builder<'buf>> ArrayStream<'a, 'buf, B> {
+    /// Read the array header from `input`, leaving the elements unread.
+    pub fn new(builder: B, config: &'a Config, input: &'buf [u8]) -> Result<Self> {
+        use sereal_common::constants::*;
+
+        let mut parser = Parser::new(builder, config, input);
+        let mut tag = parser.read_tag()? & TYPE_MASK;
+        parser.depth = 1;
+        if tag == REFN {
+            tag = parser.read_tag()? & TYPE_MASK;
+            parser.depth = 2;
+            if tag != ARRAY {
+                return Err(Error::InvalidType);
+            }
+        }
+
+        let count = match tag {
+            ARRAY => parser.reader.read_varint()?,
+            ARRAYREF_0...ARRAYREF_15 => {
+                parser.depth = 2;
+                (tag - ARRAYREF_0) as u64
+            }
+            _ => return Err(Error::InvalidType),
+        };
+        parser.check_array_size(count)?;
+
+        Ok(ArrayStream {
+            parser: parser,
+            remaining: count,
+        })
+    }
+
+    /// Number of elements not yet decoded.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// The parser decoding the elements, for `skipped_tags` or `context`.
+    pub fn parser(&self) -> &Parser<'a, 'buf, B> {
+        &self.parser
+    }
+}
+
+impl<'a, 'buf, B: Builder<'buf>> Iterator for ArrayStream<'a, 'buf, B> {
+    type Item = Result<B::Value>;
+
+    fn next(&mut self) -> Option<Result<B::Value>> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let res = self.parser.parse_inner(false);
+        self.remaining = if res.is_ok() { self.remaining - 1 } else { 0 };
+        Some(res)
+    }
+}
+
+fn check_key<'buf>(keys: &mut Option<HashSet<&'buf [u8]>>, key: &'buf [u8]) -> Result<()> {
+    if let Some(ref mut keys) = *keys {
+        if !keys.insert(key) {
+            return Err(Error::DuplicateKey(key.to_vec()));
+        }
+    }
+    Ok(())
+}
+
 pub fn parse<'buf, B: Builder<'buf>>(s: &'buf [u8], builder: B) -> Result<B::Value> {
     let config = Config::default();
     let mut p = Parser::new(builder, &config, s);
@@ -0,0 +1,242 @@
+use std::cell::RefCell;
+use std::collections::{HashSet, HashMap};
+use std::fmt;
+use std::rc::{Rc, Weak};
+
+use cell::{self, Cell, Item, View};
+use parser;
+pub use parser::Error;
+pub use parser::Result;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Inner {
+    Undef,
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    String(Vec<u8>),
+    Ref(Value),
+    WeakRef(Value),
+    Array(Vec<Value>),
+    Hash(HashMap<Vec<u8>, Value>),
+    Object(Vec<u8>, Value),
+    Bool(bool),
+    Regexp(Vec<u8>, Vec<u8>),
+}
+
+/// Single-threaded counterpart of `arc::Value`.
+///
+/// Slots joined by `ALIAS` share one cell, so a `set` through either of
+/// them is visible through the other.
+#[derive(Clone)]
+pub enum Value {
+    Strong(Rc<RefCell<Inner>>),
+    Weak(Weak<RefCell<Inner>>),
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        cell::debug_fmt(self, f, &mut HashSet::new())
+    }
+}
+
+impl Value {
+    pub fn new(v: Inner) -> Value {
+        Value::Strong(Rc::new(RefCell::new(v)))
+    }
+
+    /// A copy of the current contents. Reads through an expired weak
+    /// reference return `Undef`.
+    pub fn read(&self) -> Inner {
+        match self.upgrade() {
+            Some(ref r) => r.borrow().clone(),
+            None => Inner::Undef,
+        }
+    }
+
+    /// Replace the contents of the cell, for every slot that shares it.
+    pub fn set(&self, v: Inner) {
+        let r = self.upgrade().expect("writing expired weak-ref");
+        *r.borrow_mut() = v;
+    }
+
+    /// Whether both values point to the same cell.
+    pub fn ptr_eq(&self, other: &Value) -> bool {
+        match (self.upgrade(), other.upgrade()) {
+            (Some(ref a), Some(ref b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    fn upgrade(&self) -> Option<Rc<RefCell<Inner>>> {
+        match *self {
+            Value::Strong(ref r) => Some(r.clone()),
+            Value::Weak(ref w) => w.upgrade(),
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        self.read() == other.read()
+    }
+}
+
+impl Cell for Value {
+    type Inner = Inner;
+    type Array = Vec<Value>;
+    type Hash = HashMap<Vec<u8>, Value>;
+
+    fn with_inner<R, F: FnOnce(usize, &Inner) -> R>(&self, f: F) -> Option<R> {
+        self.upgrade().map(|r| f(r.as_ref() as *const _ as usize, &r.borrow()))
+    }
+
+    fn view(inner: &Inner) -> View<'_, Value> {
+        match *inner {
+            Inner::String(ref s) => View::String(s),
+            Inner::Ref(ref v) => View::Ref(v),
+            Inner::WeakRef(ref v) => View::WeakRef(v),
+            Inner::Array(ref a) => View::Array(a),
+            Inner::Hash(ref h) => View::Hash(h.iter().map(|(k, v)| (&k[..], v)).collect()),
+            Inner::Object(ref class, ref obj) => View::Object(class, obj),
+            _ => View::Scalar,
+        }
+    }
+
+    fn store(&self, item: Item<Value>) {
+        self.set(match item {
+            Item::Undef => Inner::Undef,
+            Item::Bool(b) => Inner::Bool(b),
+            Item::I64(v) => Inner::I64(v),
+            Item::U64(v) => Inner::U64(v),
+            Item::F32(v) => Inner::F32(v),
+            Item::F64(v) => Inner::F64(v),
+            Item::String(s) => Inner::String(s),
+            Item::Ref(v) => Inner::Ref(v),
+            Item::WeakRef(v) => Inner::WeakRef(v),
+            Item::Array(a) => Inner::Array(a),
+            Item::Hash(h) => Inner::Hash(h),
+            Item::Object(class, v) => Inner::Object(class, v),
+            Item::Regexp(pattern, flags) => Inner::Regexp(pattern, flags),
+        })
+    }
+
+    fn weaken(self) -> Value {
+        match self {
+            Value::Strong(r) => Value::Weak(Rc::downgrade(&r)),
+            _ => self,
+        }
+    }
+}
+
+pub struct RcBuilder;
+
+impl<'buf> parser::Builder<'buf> for RcBuilder {
+    type Value = Value;
+    type ArrayBuilder = Vec<Value>;
+    type HashBuilder = HashMap<Vec<u8>, Value>;
+
+    fn new(&mut self) -> Value {
+        Value::new(Inner::Undef)
+    }
+
+    fn build_array(&mut self, count: u64) -> Vec<Value> {
+        Vec::with_capacity(count as usize)
+    }
+
+    fn build_hash(&mut self, count: u64) -> HashMap<Vec<u8>, Value> {
+        HashMap::with_capacity(count as usize)
+    }
+}
+
+impl<'buf> parser::ArrayBuilder<'buf, Value> for Vec<Value> {
+    fn insert(&mut self, value: Value) -> Result<()> {
+        self.push(value);
+        Ok(())
+    }
+
+    fn finalize(self) -> Self {
+        self
+    }
+}
+
+impl<'buf> parser::HashBuilder<'buf, Value> for HashMap<Vec<u8>, Value> {
+    fn insert(&mut self, key: &'buf [u8], value: Value) -> Result<()> {
+        self.insert(key.to_vec(), value);
+        Ok(())
+    }
+
+    fn finalize(self) -> Self {
+        self
+    }
+}
+
+pub fn parse(s: &[u8]) -> Result<Value> {
+    parser::parse(s, RcBuilder)
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use rc::{parse, Inner, Value};
+
+    fn items(v: &Value) -> Vec<Value> {
+        match v.read() {
+            Inner::Ref(a) => match a.read() {
+                Inner::Array(items) => items,
+                _ => panic!("expecting array"),
+            },
+            _ => panic!("expecting reference"),
+        }
+    }
+
+    #[test]
+    fn test_alias() {
+        // [ $x, $x ] where the second slot is an alias of the first
+        let v = parse(b"\x42\x81\x2e\x02").unwrap();
+        let items = items(&v);
+
+        match (&items[0], &items[1]) {
+            (Value::Strong(a), Value::Strong(b)) => assert!(Rc::ptr_eq(a, b)),
+            _ => panic!("unexpected weak ref"),
+        }
+
+        items[1].set(Inner::U64(2));
+        assert_eq!(items[0].read(), Inner::U64(2));
+    }
+
+    #[test]
+    fn test_refp() {
+        // [ \@a, \@a ] where @a = (1)
+        let v = parse(b"\x42\x28\xab\x01\x01\x29\x03").unwrap();
+        let items = items(&v);
+
+        let (a, b) = match (items[0].read(), items[1].read()) {
+            (Inner::Ref(a), Inner::Ref(b)) => (a, b),
+            _ => panic!("expecting references"),
+        };
+
+        assert!(a.ptr_eq(&b));
+        assert!(!items[0].ptr_eq(&items[1]));
+        assert_eq!(a.read(), Inner::Array(vec![Value::new(Inner::U64(1))]));
+    }
+
+    #[test]
+    fn test_scalars() {
+        assert_eq!(parse(b"\x3a").unwrap().read(), Inner::Bool(false));
+        assert_eq!(parse(b"\x63foo").unwrap().read(), Inner::String(b"foo".to_vec()));
+    }
+
+    #[test]
+    fn test_debug() {
+        fn dbg(s: &[u8]) -> String {
+            format!("{:?}", parse(s).unwrap())
+        }
+
+        assert_eq!(dbg(b"\xa9\x01"), "\\<loop>");
+        assert_eq!(dbg(b"\x42\x01\x63foo"), "\\Array(U64(1)String([102, 111, 111]))");
+        assert_eq!(dbg(b"\x2c\x63foo\x01"), "<[102, 111, 111]=U64(1)>");
+    }
+}
@@ -5,6 +5,7 @@ use byteorder::ByteOrder;
 use sereal_common::constants::{TYPE_MASK, PAD};
 use varint;
 
+#[derive(Debug)]
 pub enum Error {
     UnexpectedEof,
     OffsetOverflow,
@@ -25,6 +26,7 @@ impl From<varint::Error> for Error {
 pub struct Reader<'buf> {
     input: &'buf [u8],
     pos: usize,
+    pad_skipped: usize,
 }
 
 impl<'buf> Reader<'buf> {
@@ -32,6 +34,7 @@ impl<'buf> Reader<'buf> {
         Reader {
             input: input,
             pos: 0,
+            pad_skipped: 0,
         }
     }
 
@@ -46,9 +49,34 @@ impl<'buf> Reader<'buf> {
             if tag & TYPE_MASK != PAD {
                 return Ok(tag);
             }
+
+            self.pad_skipped += 1;
+        }
+    }
+
+    /// Consume and return the next byte if `pred` accepts it. Nothing is
+    /// consumed at the end of input or if `pred` returns false.
+    #[inline]
+    pub fn next_if<F: FnOnce(u8) -> bool>(&mut self, pred: F) -> Option<u8> {
+        match self.input.get(self.pos) {
+            Some(&b) if pred(b) => {
+                self.pos += 1;
+                Some(b)
+            }
+            _ => None,
         }
     }
 
+    pub fn read_u8(&mut self) -> Result<u8> {
+        if self.pos >= self.input.len() {
+            return Err(Error::UnexpectedEof);
+        }
+
+        let byte = self.input[self.pos];
+        self.pos += 1;
+        Ok(byte)
+    }
+
     pub fn read_f32(&mut self) -> Result<f32> {
         let buf = &self.input[self.pos..];
         if buf.len() < 4 {
@@ -79,23 +107,30 @@ impl<'buf> Reader<'buf> {
         Ok(val)
     }
 
+    /// Read a varint used as an offset or a length. Values that do not fit
+    /// in `usize` fail with `OffsetOverflow`.
     pub fn read_varlen(&mut self) -> Result<usize> {
         let len = self.read_varint()?;
-        if len < usize::max_value() as u64 {
-            Ok(len as usize)
-        } else {
-            Err(Error::OffsetOverflow)
-        }
+        check_len(len, usize::MAX as u64, None)
     }
 
+    /// Read the length of a payload that follows it. Besides `read_varlen`'s
+    /// check, lengths past the end of the input fail with `UnexpectedEof`.
+    pub fn read_len(&mut self) -> Result<usize> {
+        let len = self.read_varint()?;
+        check_len(len, usize::MAX as u64, Some(self.remaining()))
+    }
+
+    /// Read `len` bytes. On error the position is left unchanged.
     pub fn read_bytes(&mut self, len: usize) -> Result<&'buf [u8]> {
         let beg = self.pos;
-        self.pos = self.pos.checked_add(len).ok_or(Error::OffsetOverflow)?;
-        if self.pos <= self.input.len() {
-            Ok(&self.input[beg..self.pos])
-        } else {
-            Err(Error::UnexpectedEof)
+        let end = beg.checked_add(len).ok_or(Error::OffsetOverflow)?;
+        if end > self.input.len() {
+            return Err(Error::UnexpectedEof);
         }
+
+        self.pos = end;
+        Ok(&self.input[beg..end])
     }
 
     pub fn pos(&self) -> usize {
@@ -105,4 +140,210 @@ impl<'buf> Reader<'buf> {
     pub fn set_pos(&mut self, new: usize) -> usize {
         mem::replace(&mut self.pos, new)
     }
+
+    /// Sereal offset of the next byte to be read.
+    ///
+    /// Offsets used by `REFP`, `ALIAS`, `COPY` and `OBJECTV` are 1-based
+    /// positions within the body, so this is always `pos() + 1`.
+    pub fn tell(&self) -> usize {
+        self.pos + 1
+    }
+
+    /// Move to a Sereal offset, so that the next byte read is the one at
+    /// `offset`. `seek(tell())` leaves the reader unchanged.
+    pub fn seek(&mut self, offset: usize) -> Result<()> {
+        if offset == 0 || offset > self.input.len() + 1 {
+            return Err(Error::OffsetOverflow);
+        }
+
+        self.pos = offset - 1;
+        Ok(())
+    }
+
+    /// Number of bytes left to read.
+    pub fn remaining(&self) -> usize {
+        self.input.len().saturating_sub(self.pos)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// The current position, and the input from `radius` bytes before it
+    /// to `radius` bytes after it, for showing where an error occurred.
+    /// Positions past the end are reported as the end.
+    pub fn context(&self, radius: usize) -> (usize, &'buf [u8]) {
+        let pos = self.pos.min(self.input.len());
+        let beg = pos.saturating_sub(radius);
+        let end = pos.saturating_add(radius).min(self.input.len());
+        (pos, &self.input[beg..end])
+    }
+
+    /// Number of PAD bytes skipped by `read_tag` so far.
+    pub fn padding_count(&self) -> usize {
+        self.pad_skipped
+    }
+}
+
+/// Convert `len` for a target whose `usize::MAX` is `max`, and check it
+/// against the `remaining` input if given.
+fn check_len(len: u64, max: u64, remaining: Option<usize>) -> Result<usize> {
+    if len > max {
+        return Err(Error::OffsetOverflow);
+    }
+
+    match remaining {
+        Some(n) if len > n as u64 => Err(Error::UnexpectedEof),
+        _ => Ok(len as usize),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Reader;
+
+    #[test]
+    fn padding_count() {
+        let mut r = Reader::new(b"\x3f\x3f\xbf\x01\x02");
+        assert_eq!(r.read_tag().ok(), Some(0x01));
+        assert_eq!(r.padding_count(), 3);
+        assert_eq!(r.read_tag().ok(), Some(0x02));
+        assert_eq!(r.padding_count(), 3);
+        assert!(r.read_tag().is_err());
+    }
+
+    #[test]
+    fn tell_seek() {
+        let mut r = Reader::new(b"\x01\x63foo\x02");
+        assert_eq!(r.read_tag().ok(), Some(0x01));
+
+        let off = r.tell();
+        assert_eq!(off, 2);
+        assert_eq!(r.read_tag().ok(), Some(0x63));
+        assert_eq!(r.read_bytes(3).ok(), Some(&b"foo"[..]));
+        assert_eq!(r.read_tag().ok(), Some(0x02));
+
+        assert!(r.seek(off).is_ok());
+        assert_eq!(r.read_tag().ok(), Some(0x63));
+
+        let off = r.tell();
+        assert!(r.seek(off).is_ok());
+        assert_eq!(r.tell(), off);
+
+        assert!(r.seek(0).is_err());
+        assert!(r.seek(7).is_ok());
+        assert!(r.seek(8).is_err());
+    }
+
+    #[test]
+    fn seek_copy_target() {
+        // [ "foo", COPY(3) ]
+        let mut r = Reader::new(b"\x2b\x02\x63foo\x2f\x03");
+        assert_eq!(r.read_tag().ok(), Some(0x2b));
+        assert_eq!(r.read_varint().ok(), Some(2));
+        assert_eq!(r.tell(), 3);
+        assert_eq!(r.read_tag().ok(), Some(0x63));
+        assert_eq!(r.read_bytes(3).ok(), Some(&b"foo"[..]));
+
+        assert_eq!(r.read_tag().ok(), Some(0x2f));
+        let target = r.read_varint().ok().unwrap() as usize;
+        let resume = r.tell();
+        assert!(r.is_empty());
+
+        assert!(r.seek(target).is_ok());
+        assert_eq!(r.read_tag().ok(), Some(0x63));
+        assert_eq!(r.read_bytes(3).ok(), Some(&b"foo"[..]));
+
+        assert!(r.seek(resume).is_ok());
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn remaining() {
+        let mut r = Reader::new(b"\x01\x02\x03");
+        assert_eq!(r.remaining(), 3);
+        assert!(!r.is_empty());
+
+        r.read_bytes(2).ok().unwrap();
+        assert_eq!(r.remaining(), 1);
+
+        r.read_u8().ok().unwrap();
+        assert_eq!(r.remaining(), 0);
+        assert!(r.is_empty());
+
+        r.set_pos(10);
+        assert_eq!(r.remaining(), 0);
+    }
+
+    #[test]
+    fn context() {
+        let mut r = Reader::new(b"\x01\x02\x03\x04\x05\x06");
+        assert_eq!(r.context(2), (0, &b"\x01\x02"[..]));
+
+        r.set_pos(3);
+        assert_eq!(r.context(2), (3, &b"\x02\x03\x04\x05"[..]));
+        assert_eq!(r.context(0), (3, &b""[..]));
+        assert_eq!(r.context(100), (3, &b"\x01\x02\x03\x04\x05\x06"[..]));
+
+        r.set_pos(10);
+        assert_eq!(r.context(2), (6, &b"\x05\x06"[..]));
+    }
+
+    #[test]
+    fn read_bytes_overflow() {
+        use super::Error;
+
+        let mut r = Reader::new(b"\x01\x02\x03");
+        assert_eq!(r.read_bytes(1).ok(), Some(&b"\x01"[..]));
+
+        // pos + len wraps around on any pointer width.
+        match r.read_bytes(usize::MAX) {
+            Err(Error::OffsetOverflow) => (),
+            _ => panic!("expected offset overflow"),
+        }
+        assert_eq!(r.pos(), 1);
+
+        match r.read_bytes(usize::MAX - 1) {
+            Err(Error::UnexpectedEof) => (),
+            _ => panic!("expected eof"),
+        }
+        assert_eq!(r.pos(), 1);
+
+        assert_eq!(r.read_bytes(2).ok(), Some(&b"\x02\x03"[..]));
+    }
+
+    #[test]
+    fn read_len() {
+        use super::{check_len, Error};
+
+        let mut r = Reader::new(b"\x03abc");
+        assert_eq!(r.read_len().ok(), Some(3));
+
+        let mut r = Reader::new(b"\x04abc");
+        match r.read_len() {
+            Err(Error::UnexpectedEof) => (),
+            _ => panic!("expected eof"),
+        }
+
+        // 2^64 - 1 declared as the length of a three-byte payload.
+        let mut r = Reader::new(b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01abc");
+        assert!(r.read_len().is_err());
+
+        // 32-bit target: 2^32 does not fit, 2^32 - 1 fits but is past the end.
+        let max = 0xffff_ffff;
+        match check_len(max + 1, max, None) {
+            Err(Error::OffsetOverflow) => (),
+            _ => panic!("expected offset overflow"),
+        }
+        match check_len(max + 1, max, Some(10)) {
+            Err(Error::OffsetOverflow) => (),
+            _ => panic!("expected offset overflow"),
+        }
+        match check_len(max, max, Some(10)) {
+            Err(Error::UnexpectedEof) => (),
+            _ => panic!("expected eof"),
+        }
+        assert_eq!(check_len(max, max, None).ok(), Some(max as usize));
+        assert_eq!(check_len(10, max, Some(10)).ok(), Some(10));
+    }
 }
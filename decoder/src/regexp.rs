@@ -0,0 +1,126 @@
+//! Compiling decoded `REGEXP` values with the `regex` crate. Available with
+//! the `regex` feature.
+
+use std::error;
+use std::fmt;
+use std::str;
+
+use regex::{self, Regex, RegexBuilder};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The pattern is not valid UTF-8.
+    InvalidUtf8,
+    /// A modifier with no `RegexBuilder` equivalent.
+    UnsupportedFlag(u8),
+    /// The pattern does not compile, typically because it uses a construct
+    /// only Perl supports, such as backreferences or lookaround.
+    Regex(regex::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidUtf8 => write!(f, "pattern is not valid utf-8"),
+            Error::UnsupportedFlag(flag) => write!(f, "unsupported modifier {:?}", flag as char),
+            Error::Regex(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::InvalidUtf8 => "invalid utf-8",
+            Error::UnsupportedFlag(_) => "unsupported modifier",
+            Error::Regex(_) => "invalid pattern",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Regex(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(e: regex::Error) -> Error {
+        Error::Regex(e)
+    }
+}
+
+/// Compile the pattern and modifiers of a `REGEXP` value, as found in
+/// `arc::Inner::Regexp`.
+///
+/// The modifiers `i`, `m`, `s` and `x` map to the `RegexBuilder` options of
+/// the same meaning. `u` is accepted and ignored, since `regex` matches
+/// Unicode by default; any other modifier is an error.
+pub fn sereal_regex_to_rust(pattern: &[u8], flags: &[u8]) -> Result<Regex, Error> {
+    let pattern = str::from_utf8(pattern).map_err(|_| Error::InvalidUtf8)?;
+    let mut builder = RegexBuilder::new(pattern);
+
+    for &flag in flags {
+        let enable = match flag {
+            b'i' => RegexBuilder::case_insensitive,
+            b'm' => RegexBuilder::multi_line,
+            b's' => RegexBuilder::dot_matches_new_line,
+            b'x' => RegexBuilder::ignore_whitespace,
+            b'u' => continue,
+            _ => return Err(Error::UnsupportedFlag(flag)),
+        };
+        enable(&mut builder, true);
+    }
+
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sereal_regex_to_rust, Error};
+
+    #[test]
+    fn flags() {
+        let re = sereal_regex_to_rust(b"^foo$", b"").unwrap();
+        assert!(re.is_match("foo"));
+        assert!(!re.is_match("FOO"));
+        assert!(!re.is_match("bar\nfoo"));
+
+        let re = sereal_regex_to_rust(b"^foo$", b"i").unwrap();
+        assert!(re.is_match("FOO"));
+
+        let re = sereal_regex_to_rust(b"^foo$", b"m").unwrap();
+        assert!(re.is_match("bar\nfoo"));
+
+        let re = sereal_regex_to_rust(b"a.b", b"s").unwrap();
+        assert!(re.is_match("a\nb"));
+        assert!(!sereal_regex_to_rust(b"a.b", b"").unwrap().is_match("a\nb"));
+
+        let re = sereal_regex_to_rust(b"a b # comment", b"x").unwrap();
+        assert!(re.is_match("ab"));
+
+        let re = sereal_regex_to_rust(b"^foo$", b"imsxu").unwrap();
+        assert!(re.is_match("bar\nFOO"));
+    }
+
+    #[test]
+    fn errors() {
+        match sereal_regex_to_rust(b"(a)\\1", b"") {
+            Err(Error::Regex(_)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match sereal_regex_to_rust(b"foo(?=bar)", b"") {
+            Err(Error::Regex(_)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match sereal_regex_to_rust(b"foo", b"ig") {
+            Err(Error::UnsupportedFlag(b'g')) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match sereal_regex_to_rust(b"\xff", b"") {
+            Err(Error::InvalidUtf8) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
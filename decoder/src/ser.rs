@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use sereal_common::constants::*;
+
+use arc::{Inner, Map, Value};
+use varint::{write_varint, write_zigzag};
+pub use parser::Error;
+pub use parser::Result;
+
+/// Encode a value tree as a Sereal document body (without header).
+///
+/// Cells reachable more than once are written once with the track bit set
+/// and referenced afterwards: through `REFP` from a reference, through
+/// `ALIAS` from an array or hash slot. Weak references that have expired
+/// are written as a weakened `UNDEF`.
+pub fn encode(value: &Value) -> Result<Vec<u8>> {
+    let mut enc = Encoder::new(None);
+
+    enc.count(value);
+    enc.write_slot(value);
+
+    Ok(enc.out)
+}
+
+/// Same as `encode`, but writes hash entries sorted by key bytes, so equal
+/// values always encode to the same bytes regardless of map order.
+pub fn encode_canonical(value: &Value) -> Result<Vec<u8>> {
+    let mut enc = Encoder::new(None);
+    enc.canonical = true;
+
+    enc.count(value);
+    enc.write_slot(value);
+
+    Ok(enc.out)
+}
+
+/// Write `value` to `writer` as a complete protocol 4 document with an
+/// uncompressed body and no header suffix.
+///
+/// The body is handed to `writer` in chunks of about `FLUSH_SIZE` bytes as
+/// it is encoded, so memory use does not grow with the document. A
+/// compressed document could not be streamed this way: its header carries
+/// the compressed length, so the whole body has to be encoded first.
+pub fn to_writer<W: io::Write>(mut writer: W, value: &Value) -> io::Result<()> {
+    writer.write_u32::<LittleEndian>(MAGIC_V3)?;
+    writer.write_all(&[PROTO_V4 | (TYPE_RAW << 4), 0])?;
+
+    let mut enc = Encoder::new(Some(&mut writer));
+    enc.count(value);
+    enc.write_slot(value);
+    enc.finish()
+}
+
+/// Buffered body size at which `to_writer` passes it on to the writer.
+pub const FLUSH_SIZE: usize = 8192;
+
+struct Encoder<'a> {
+    out: Vec<u8>,
+    // Where `out` goes once it reaches `FLUSH_SIZE`. `None` keeps the
+    // whole body in `out`.
+    sink: Option<&'a mut dyn io::Write>,
+    // Bytes already passed to `sink`.
+    flushed: usize,
+    // First error from `sink`; later output is dropped.
+    error: Option<io::Error>,
+    // Whether hash entries are sorted by key.
+    canonical: bool,
+    // How many times each cell is reachable, keyed by address.
+    seen: HashMap<usize, usize>,
+    // Sereal offset of each cell written with the track bit.
+    offsets: HashMap<usize, usize>,
+    // Sereal offset of each class name written by `OBJECT`.
+    classes: HashMap<Vec<u8>, usize>,
+}
+
+fn cell(v: &Value) -> Option<Arc<RwLock<Inner>>> {
+    match *v {
+        Value::Strong(ref a) => Some(a.clone()),
+        Value::Weak(ref w) => w.upgrade(),
+    }
+}
+
+fn id(a: &Arc<RwLock<Inner>>) -> usize {
+    a.as_ref() as *const _ as usize
+}
+
+impl<'a> Encoder<'a> {
+    fn new(sink: Option<&'a mut dyn io::Write>) -> Encoder<'a> {
+        Encoder {
+            out: Vec::new(),
+            sink: sink,
+            flushed: 0,
+            error: None,
+            canonical: false,
+            seen: HashMap::new(),
+            offsets: HashMap::new(),
+            classes: HashMap::new(),
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(ref mut sink) = self.sink {
+            if self.error.is_none() {
+                if let Err(e) = sink.write_all(&self.out) {
+                    self.error = Some(e);
+                }
+            }
+            self.flushed += self.out.len();
+            self.out.clear();
+        }
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.flush();
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn count(&mut self, v: &Value) {
+        let a = match cell(v) {
+            Some(a) => a,
+            None => return,
+        };
+
+        let n = self.seen.entry(id(&a)).or_insert(0);
+        *n += 1;
+        if *n > 1 {
+            return;
+        }
+
+        let inner = a.read().unwrap().clone();
+        match inner {
+            Inner::Ref(ref v) | Inner::WeakRef(ref v) | Inner::Object(_, ref v) => self.count(v),
+            Inner::Array(ref items) => {
+                for v in items {
+                    self.count(v);
+                }
+            }
+            Inner::Hash(ref items) => {
+                for v in items.values() {
+                    self.count(v);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn is_shared(&self, a: &Arc<RwLock<Inner>>) -> bool {
+        self.seen.get(&id(a)).cloned().unwrap_or(0) > 1
+    }
+
+    /// Sereal offset of the next byte written.
+    fn pos(&self) -> usize {
+        self.flushed + self.out.len() + 1
+    }
+
+    /// Write a value in a position that holds it directly: the document
+    /// root, an array element, a hash value or the body of a weak ref.
+    fn write_slot(&mut self, v: &Value) {
+        if self.out.len() >= FLUSH_SIZE {
+            self.flush();
+        }
+
+        let a = match cell(v) {
+            Some(a) => a,
+            None => return self.out.push(UNDEF),
+        };
+
+        if let Some(&offset) = self.offsets.get(&id(&a)) {
+            self.out.push(ALIAS);
+            write_varint(&mut self.out, offset as u64);
+            return;
+        }
+
+        let track = if self.is_shared(&a) {
+            self.offsets.insert(id(&a), self.pos());
+            TRACK_BIT
+        } else {
+            0
+        };
+
+        let inner = a.read().unwrap().clone();
+        self.write_inner(&inner, track);
+    }
+
+    fn write_inner(&mut self, inner: &Inner, track: u8) {
+        match *inner {
+            Inner::Undef => self.out.push(UNDEF | track),
+
+            Inner::I64(v) => {
+                self.out.push(ZIGZAG | track);
+                write_zigzag(&mut self.out, v);
+            }
+
+            Inner::U64(v) if v < 16 => self.out.push((POS_0 + v as u8) | track),
+
+            Inner::U64(v) => {
+                self.out.push(VARINT | track);
+                write_varint(&mut self.out, v);
+            }
+
+            Inner::F32(v) => {
+                self.out.push(FLOAT | track);
+                self.out.write_f32::<LittleEndian>(v).unwrap();
+            }
+
+            Inner::F64(v) => {
+                self.out.push(DOUBLE | track);
+                self.out.write_f64::<LittleEndian>(v).unwrap();
+            }
+
+            Inner::Bool(true) => self.out.push(TRUE | track),
+            Inner::Bool(false) => self.out.push(FALSE | track),
+
+            Inner::String(ref s) => self.write_binary(s, track),
+
+            Inner::Ref(ref v) => self.write_ref(v, track),
+
+            Inner::WeakRef(ref v) => {
+                self.out.push(WEAKEN | track);
+                self.write_slot(v);
+            }
+
+            Inner::Array(ref items) => {
+                self.out.push(ARRAY | track);
+                write_varint(&mut self.out, items.len() as u64);
+                self.write_items(items);
+            }
+
+            Inner::Hash(ref items) => {
+                self.out.push(HASH | track);
+                write_varint(&mut self.out, items.len() as u64);
+                self.write_pairs(items);
+            }
+
+            Inner::Object(ref class, ref v) => {
+                match self.classes.get(&**class).cloned() {
+                    Some(offset) => {
+                        self.out.push(OBJECTV | track);
+                        write_varint(&mut self.out, offset as u64);
+                    }
+                    None => {
+                        self.out.push(OBJECT | track);
+                        let offset = self.pos();
+                        self.classes.insert(class.to_vec(), offset);
+                        self.write_binary(class, 0);
+                    }
+                }
+                self.write_slot(v);
+            }
+
+            Inner::Regexp(ref pattern, ref flags) => {
+                self.out.push(REGEXP | track);
+                self.write_binary(pattern, 0);
+                self.write_binary(flags, 0);
+            }
+        }
+    }
+
+    /// Write a strong reference to `v`, using the compact `ARRAYREF_N` and
+    /// `HASHREF_N` forms when the target is not shared.
+    fn write_ref(&mut self, v: &Value, track: u8) {
+        let a = match cell(v) {
+            Some(a) => a,
+            None => {
+                self.out.push(REFN | track);
+                return self.out.push(UNDEF);
+            }
+        };
+
+        if let Some(&offset) = self.offsets.get(&id(&a)) {
+            self.out.push(REFP | track);
+            write_varint(&mut self.out, offset as u64);
+            return;
+        }
+
+        if !self.is_shared(&a) {
+            let inner = a.read().unwrap().clone();
+            match inner {
+                Inner::Array(ref items) if items.len() < 16 => {
+                    self.out.push((ARRAYREF_0 + items.len() as u8) | track);
+                    return self.write_items(items);
+                }
+                Inner::Hash(ref items) if items.len() < 16 => {
+                    self.out.push((HASHREF_0 + items.len() as u8) | track);
+                    return self.write_pairs(items);
+                }
+                _ => (),
+            }
+        }
+
+        self.out.push(REFN | track);
+        self.write_slot(v);
+    }
+
+    fn write_items(&mut self, items: &[Value]) {
+        for v in items {
+            self.write_slot(v);
+        }
+    }
+
+    fn write_pairs(&mut self, items: &Map) {
+        if self.canonical {
+            let mut sorted: Vec<_> = items.iter().collect();
+            sorted.sort_by(|a, b| a.0.cmp(b.0));
+            for (k, v) in sorted {
+                self.write_binary(k, 0);
+                self.write_slot(v);
+            }
+        } else {
+            for (k, v) in items {
+                self.write_binary(k, 0);
+                self.write_slot(v);
+            }
+        }
+    }
+
+    fn write_binary(&mut self, s: &[u8], track: u8) {
+        if s.len() < 32 {
+            self.out.push((SHORT_BINARY_0 + s.len() as u8) | track);
+        } else {
+            self.out.push(BINARY | track);
+            write_varint(&mut self.out, s.len() as u64);
+        }
+        self.out.extend_from_slice(s);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arc::{self, ArcBuilder, Inner, Map, Value};
+    use ser::{encode, encode_canonical, to_writer, FLUSH_SIZE};
+
+    fn inner(v: &Value) -> Inner {
+        match *v {
+            Value::Strong(ref a) => a.read().unwrap().clone(),
+            Value::Weak(_) => panic!("unexpected weak ref"),
+        }
+    }
+
+    fn ptr_eq(a: &Value, b: &Value) -> bool {
+        match (a, b) {
+            (Value::Strong(a), Value::Strong(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    fn roundtrip(s: &[u8]) -> Value {
+        let v = arc::parse(s).unwrap();
+        let encoded = encode(&v).unwrap();
+        let decoded = arc::parse(&encoded).unwrap();
+        assert_eq!(decoded, v, "{:?} encoded as {:?}", s, encoded);
+        decoded
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let inputs: &[&[u8]] = &[
+            b"\x01",
+            b"\x25",
+            b"\x3b",
+            b"\x20\x80\x01",
+            b"\x21\x03",
+            b"\x22\x00\x00\xc0\x3f",
+            b"\x23\x00\x00\x00\x00\x00\x00\xf8\x3f",
+            b"\x26\x20aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            b"\x2a\x02\x63foo\x63bar\x64ook\x00\x64eek\x00",
+            b"\x2a\x01\x63foo\x2a\x00",
+            b"\x2b\x11\x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\x0f\x20\x10",
+            b"\x42\x2c\x63foo\x28\x2a\x00\x2d\x03\x28\x2a\x00",
+            b"\x43\x41\x01\x2f\x02\x2f\x02",
+            b"\x43\x61a\x51\x2f\x02\x01\x2f\x04",
+            b"\x42\x31\x63a.b\x61i\x30\x28\x01",
+            b"\x3c\x03\x20\x01\x80\x01\x00",
+        ];
+
+        for s in inputs {
+            roundtrip(s);
+        }
+    }
+
+    #[test]
+    fn test_shared() {
+        fn targets(v: &Value) -> Vec<Value> {
+            let items = match inner(v) {
+                Inner::Ref(a) => match inner(&a) {
+                    Inner::Array(items) => items,
+                    _ => panic!("expecting array"),
+                },
+                _ => panic!("expecting reference"),
+            };
+
+            items
+                .into_iter()
+                .map(|v| match inner(&v) {
+                    Inner::Ref(a) => a,
+                    _ => panic!("expecting reference"),
+                })
+                .collect()
+        }
+
+        // [ \@a, \@a ] where @a = (1)
+        let v = roundtrip(b"\x42\x28\xab\x01\x01\x29\x03");
+        let t = targets(&v);
+        assert!(ptr_eq(&t[0], &t[1]));
+
+        // [ \$x, \$y ] where $y is an alias of $x
+        let v = arc::parse(b"\x42\x28\x81\x28\x2e\x03").unwrap();
+        let encoded = encode(&v).unwrap();
+        let t = targets(&arc::parse(&encoded).unwrap());
+        assert!(ptr_eq(&t[0], &t[1]));
+    }
+
+    #[cfg(feature = "indexmap")]
+    #[test]
+    fn test_key_order() {
+        let s = b"\x53\x63zzz\x01\x61a\x02\x63mmm\x03";
+        assert_eq!(encode(&arc::parse(s).unwrap()).unwrap(), &s[..]);
+    }
+
+    #[test]
+    fn test_self_ref() {
+        let v = arc::parse(b"\xa9\x01").unwrap();
+        assert_eq!(encode(&v).unwrap(), b"\xa9\x01");
+
+        let v = arc::parse(&encode(&v).unwrap()).unwrap();
+        match inner(&v) {
+            Inner::Ref(ref a) => assert!(ptr_eq(a, &v)),
+            _ => panic!("expecting reference"),
+        }
+    }
+
+    #[test]
+    fn test_to_writer() {
+        // [ \@a, \@a, ... ] where @a holds enough strings to be flushed
+        // before the second reference to it.
+        let mut s = b"\x42\x28\xab\x90\x08".to_vec();
+        for _ in 0..1040 {
+            s.extend_from_slice(b"\x68abcdefgh");
+        }
+        s.extend_from_slice(b"\x29\x03");
+        let v = arc::parse(&s).unwrap();
+
+        let mut out = Vec::new();
+        to_writer(&mut out, &v).unwrap();
+        assert!(out.len() > FLUSH_SIZE);
+        assert_eq!(&out[..6], b"=\xf3rl\x04\x00");
+        assert_eq!(&out[6..], &encode(&v).unwrap()[..]);
+
+        let mut buffer = Vec::new();
+        let decoded = ::parse(&out[..], ArcBuilder::new(), &mut buffer).unwrap();
+        assert_eq!(decoded, v);
+    }
+
+    #[test]
+    fn test_canonical() {
+        let keys: &[&[u8]] = &[b"b", b"ccc", b"a", b"aa", b"zz", b"m"];
+
+        let hash = |keys: &[&[u8]]| {
+            let mut h = Map::new();
+            for k in keys {
+                h.insert(k.to_vec(), Value::new(Inner::U64(k.len() as u64)));
+            }
+            Value::new(Inner::Hash(h))
+        };
+        let mut reversed = keys.to_vec();
+        reversed.reverse();
+
+        let a = encode_canonical(&hash(keys)).unwrap();
+        let b = encode_canonical(&hash(&reversed)).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(
+            a,
+            &b"\x2a\x06\x61a\x01\x62aa\x02\x61b\x01\x63ccc\x03\x61m\x01\x62zz\x02"[..]
+        );
+    }
+}
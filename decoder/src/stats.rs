@@ -0,0 +1,186 @@
+use std::cmp;
+
+use parser;
+pub use parser::Error;
+pub use parser::Result;
+
+/// Shape of a document, gathered in a single parse pass.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DocumentStats {
+    /// Deepest nesting of arrays and hashes. A plain scalar has depth 0.
+    pub max_depth: u64,
+    /// Total number of array elements and hash entries.
+    pub elements: u64,
+    pub largest_array: u64,
+    pub largest_hash: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Value {
+    depth: u64,
+}
+
+impl<'buf> parser::Value<'buf> for Value {
+    type Array = u64;
+    type Hash = u64;
+
+    fn set_undef(&mut self) {}
+    fn set_true(&mut self) {}
+    fn set_false(&mut self) {}
+
+    fn set_i64(&mut self, _: i64) {}
+    fn set_u64(&mut self, _: u64) {}
+    fn set_f32(&mut self, _: f32) {}
+    fn set_f64(&mut self, _: f64) {}
+
+    fn set_ref(&mut self, o: Self) {
+        self.depth = o.depth;
+    }
+
+    fn set_weak_ref(&mut self, o: Self) {
+        self.depth = o.depth;
+    }
+
+    fn set_alias(&mut self, o: Self) {
+        *self = o;
+    }
+
+    fn set_array(&mut self, depth: u64) {
+        self.depth = depth + 1;
+    }
+
+    fn set_hash(&mut self, depth: u64) {
+        self.depth = depth + 1;
+    }
+
+    fn set_binary(&mut self, _: &'buf [u8]) {}
+    fn set_string(&mut self, _: &'buf [u8]) {}
+
+    fn set_object(&mut self, _: Self, value: Self) -> Result<()> {
+        self.depth = value.depth;
+        Ok(())
+    }
+
+    fn set_object_freeze(&mut self, class: Self, value: Self) -> Result<()> {
+        self.set_object(class, value)
+    }
+
+    fn set_regexp(&mut self, _: Self, _: Self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Tracks the deepest child inserted into an array or hash.
+pub struct DepthBuilder(u64);
+
+impl<'buf> parser::ArrayBuilder<'buf, Value> for DepthBuilder {
+    fn insert(&mut self, value: Value) -> Result<()> {
+        self.0 = cmp::max(self.0, value.depth);
+        Ok(())
+    }
+
+    fn finalize(self) -> u64 {
+        self.0
+    }
+}
+
+impl<'buf> parser::HashBuilder<'buf, Value> for DepthBuilder {
+    fn insert(&mut self, _: &'buf [u8], value: Value) -> Result<()> {
+        self.0 = cmp::max(self.0, value.depth);
+        Ok(())
+    }
+
+    fn finalize(self) -> u64 {
+        self.0
+    }
+}
+
+pub struct StatsBuilder<'a> {
+    stats: &'a mut DocumentStats,
+}
+
+impl<'a> StatsBuilder<'a> {
+    pub fn new(stats: &'a mut DocumentStats) -> StatsBuilder<'a> {
+        StatsBuilder { stats: stats }
+    }
+}
+
+impl<'a, 'buf> parser::Builder<'buf> for StatsBuilder<'a> {
+    type Value = Value;
+    type ArrayBuilder = DepthBuilder;
+    type HashBuilder = DepthBuilder;
+
+    fn new(&mut self) -> Value {
+        Value { depth: 0 }
+    }
+
+    fn build_array(&mut self, count: u64) -> DepthBuilder {
+        self.stats.elements += count;
+        self.stats.largest_array = cmp::max(self.stats.largest_array, count);
+        DepthBuilder(0)
+    }
+
+    fn build_hash(&mut self, count: u64) -> DepthBuilder {
+        self.stats.elements += count;
+        self.stats.largest_hash = cmp::max(self.stats.largest_hash, count);
+        DepthBuilder(0)
+    }
+}
+
+pub fn parse_stats(s: &[u8]) -> Result<DocumentStats> {
+    let mut stats = DocumentStats::default();
+    let root = parser::parse(s, StatsBuilder::new(&mut stats))?;
+    stats.max_depth = root.depth;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use stats::{parse_stats, DocumentStats};
+
+    #[test]
+    fn test_scalar() {
+        assert_eq!(parse_stats(b"\x01").unwrap(), DocumentStats::default());
+    }
+
+    #[test]
+    fn test_nested() {
+        // { foo => [1, [2, 3]], bar => {} }
+        let stats = parse_stats(b"\x52\x63foo\x42\x01\x42\x02\x03\x63bar\x50").unwrap();
+
+        assert_eq!(
+            stats,
+            DocumentStats {
+                max_depth: 3,
+                elements: 6,
+                largest_array: 2,
+                largest_hash: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_deep_iterative() {
+        use config::Config;
+        use parser::Parser;
+        use stats::StatsBuilder;
+
+        let depth = 1_000_000;
+        let mut s = vec![0x41; depth];
+        s.push(0x01);
+
+        let config = Config::default().with_max_depth(depth as u64 + 1);
+        let mut stats = DocumentStats::default();
+        let root = Parser::new(StatsBuilder::new(&mut stats), &config, &s)
+            .parse_iterative()
+            .unwrap();
+
+        assert_eq!(root.depth, depth as u64);
+        assert_eq!(stats.elements, depth as u64);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(parse_stats(b"\x2b\x02\x00").unwrap_err().is_eof());
+    }
+}
@@ -0,0 +1,130 @@
+//! Checks of the encoder against the decoder, for tests and fuzz targets.
+//! Available with the `testutil` feature.
+
+use std::collections::HashMap;
+
+use arc::{ArcBuilder, Inner, Value};
+use ser;
+use Error;
+
+/// Decode `bytes` into an `arc::Value`, encode it again with `ser::encode`,
+/// decode the result and report whether the two values have the same
+/// structure. As in `parse_packet`, `bytes` is a complete document if it
+/// starts with the magic string and a bare body otherwise.
+///
+/// Cells that are shared, or part of a cycle, in one value must be shared
+/// the same way in the other. Floats are compared bit for bit, so a NaN
+/// matches itself.
+pub fn roundtrip_check(bytes: &[u8]) -> Result<bool, Error> {
+    let mut buffer = Vec::new();
+    let first = ::parse_packet(bytes, ArcBuilder::new(), &mut buffer)?;
+    let encoded = ser::encode(&first)?;
+    let second = ::parser::parse(&encoded, ArcBuilder::new())?;
+    Ok(Pairs::default().same(&first, &second))
+}
+
+/// Cells already matched, in both directions, so that each cell of one
+/// value corresponds to exactly one cell of the other.
+#[derive(Default)]
+struct Pairs {
+    left: HashMap<usize, usize>,
+    right: HashMap<usize, usize>,
+}
+
+fn cell(v: &Value) -> Option<(usize, Inner)> {
+    let a = match *v {
+        Value::Strong(ref a) => a.clone(),
+        Value::Weak(ref w) => w.upgrade()?,
+    };
+    let inner = a.read().unwrap().clone();
+    Some((a.as_ref() as *const _ as usize, inner))
+}
+
+impl Pairs {
+    fn same(&mut self, a: &Value, b: &Value) -> bool {
+        let ((ia, a), (ib, b)) = match (cell(a), cell(b)) {
+            (Some(a), Some(b)) => (a, b),
+            (None, None) => return true,
+            _ => return false,
+        };
+
+        match (self.left.get(&ia), self.right.get(&ib)) {
+            (Some(&x), Some(&y)) => return x == ib && y == ia,
+            (None, None) => (),
+            _ => return false,
+        }
+        self.left.insert(ia, ib);
+        self.right.insert(ib, ia);
+
+        match (a, b) {
+            (Inner::Ref(ref x), Inner::Ref(ref y)) |
+            (Inner::WeakRef(ref x), Inner::WeakRef(ref y)) => self.same(x, y),
+            (Inner::Object(ref c, ref x), Inner::Object(ref d, ref y)) => {
+                c == d && self.same(x, y)
+            }
+            (Inner::Array(ref x), Inner::Array(ref y)) => {
+                x.len() == y.len() && x.iter().zip(y).all(|(x, y)| self.same(x, y))
+            }
+            (Inner::Hash(ref x), Inner::Hash(ref y)) => {
+                x.len() == y.len() &&
+                    x.iter().all(|(k, v)| match y.get(k) {
+                        Some(w) => self.same(v, w),
+                        None => false,
+                    })
+            }
+            (Inner::F32(x), Inner::F32(y)) => x.to_bits() == y.to_bits(),
+            (Inner::F64(x), Inner::F64(y)) => x.to_bits() == y.to_bits(),
+            (a, b) => a == b,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use arc;
+    use testutil::{roundtrip_check, Pairs};
+
+    #[test]
+    fn test_roundtrip_check() {
+        // Bodies from the `arc` tests, plus one complete document.
+        let inputs: &[&[u8]] = &[
+            b"\x01",
+            b"\x1f",
+            b"\x10",
+            b"\x60",
+            b"\x61\x00",
+            b"\x3b",
+            b"\x3c\x02\x3a",
+            b"\x3f\x42\x3f\x01\x3f\x3f\x02",
+            b"\xbf\x28\x3f\x81",
+            b"\x2b\x02\x00\x00",
+            b"\x2a\x02\x63foo\x63bar\x64ook\x00\x64eek\x00",
+            b"\x2a\x01\x63foo\x2a\x00",
+            b"\x54\x63zzz\x01\x61a\x02\x63mmm\x03\x61b\x04",
+            b"\xa9\x01",
+            b"\x42\x28\xab\x01\x01\x29\x03",
+            b"\x42\x28\x81\x28\x2e\x03",
+            b"\x2b\x03\x2c\x61a\x01\x2d\x04\x01\x2d\x04\x01",
+            b"\x42\x31\x63a.b\x61i\x30\x28\x01",
+            b"\x23\x00\x00\x00\x00\x00\x00\xf8\x7f",
+            b"=\xf3rl\x04\x00\x42\x01\x02",
+        ];
+
+        for s in inputs {
+            assert!(roundtrip_check(s).unwrap(), "{:?}", s);
+        }
+
+        assert!(roundtrip_check(b"\x2b\x02\x00").is_err());
+    }
+
+    #[test]
+    fn test_sharing_differs() {
+        // [ \@a, \@a ] against [ \@a, \@b ] with equal contents.
+        let shared = arc::parse(b"\x42\x28\xab\x01\x01\x29\x03").unwrap();
+        let copied = arc::parse(b"\x42\x28\x2b\x01\x01\x28\x2b\x01\x01").unwrap();
+        assert_eq!(shared, copied);
+        assert!(!Pairs::default().same(&shared, &copied));
+        assert!(!Pairs::default().same(&copied, &shared));
+        assert!(Pairs::default().same(&shared, &shared.clone()));
+    }
+}
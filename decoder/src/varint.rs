@@ -10,17 +10,11 @@ pub enum Error {
 
 impl Error {
     pub fn is_overflow(&self) -> bool {
-        match self {
-            &Error::Overflow => true,
-            _ => false,
-        }
+        matches!(*self, Error::Overflow)
     }
 
     pub fn is_eof(&self) -> bool {
-        match self {
-            &Error::UnexpectedEof => true,
-            _ => false,
-        }
+        matches!(*self, Error::UnexpectedEof)
     }
 }
 
@@ -30,8 +24,13 @@ fn straighten(v: u64) -> i64 {
     (v >> 1) as i64 ^ -((v & 1) as i64)
 }
 
+fn bend(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
 pub trait VarintReaderExt {
     fn read_varint(&mut self) -> io::Result<u64>;
+    #[allow(dead_code)]
     fn read_zigzag(&mut self) -> io::Result<i64> {
         let v = self.read_varint()?;
         Ok(straighten(v))
@@ -53,7 +52,7 @@ impl<R: io::Read> VarintReaderExt for R {
 
             o += 7;
             if o >= 64 {
-                return Err(io::Error::new(io::ErrorKind::Other, "varint overflow"));
+                return Err(io::Error::other("varint overflow"));
             }
         }
     }
@@ -84,6 +83,18 @@ pub fn parse_zigzag(buf: &[u8]) -> Result<(i64, usize)> {
     Ok((straighten(val), len))
 }
 
+pub fn write_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+pub fn write_zigzag(out: &mut Vec<u8>, v: i64) {
+    write_varint(out, bend(v))
+}
+
 #[cfg(test)]
 mod test {
     use std::io;
@@ -92,7 +103,6 @@ mod test {
 
     #[test]
     fn test_varint() {
-        use std::u64::MAX;
 
         fn r(s: &[u8]) -> io::Result<u64> {
             Cursor::new(s).read_varint()
@@ -113,7 +123,7 @@ mod test {
         assert_eq!(t(b"\x81\x01"), 129);
         assert_eq!(t(b"\x81\x81\x00"), 129);
         assert_eq!(t(b"\x80\x80\x80\x80\x80\x80\x80\x80\x80\x00"), 0);
-        assert_eq!(t(b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x7f"), MAX);
+        assert_eq!(t(b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x7f"), u64::MAX);
 
         assert_eq!(e(b"\x80").kind(), io::ErrorKind::UnexpectedEof);
         assert_eq!(
@@ -124,7 +134,6 @@ mod test {
 
     #[test]
     fn test_zigzag() {
-        use std::i64::{MIN, MAX};
 
         fn r(s: &[u8]) -> io::Result<i64> {
             Cursor::new(s).read_zigzag()
@@ -148,8 +157,8 @@ mod test {
         assert_eq!(t(b"\x81\x01"), -65);
         assert_eq!(t(b"\x81\x81\x00"), -65);
         assert_eq!(t(b"\x80\x80\x80\x80\x80\x80\x80\x80\x80\x00"), 0);
-        assert_eq!(t(b"\xfe\xff\xff\xff\xff\xff\xff\xff\xff\x7f"), MAX);
-        assert_eq!(t(b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x7f"), MIN);
+        assert_eq!(t(b"\xfe\xff\xff\xff\xff\xff\xff\xff\xff\x7f"), i64::MAX);
+        assert_eq!(t(b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x7f"), i64::MIN);
 
         assert_eq!(e(b"\x80").kind(), io::ErrorKind::UnexpectedEof);
         assert_eq!(
@@ -157,4 +166,147 @@ mod test {
             "varint overflow"
         );
     }
+
+    #[test]
+    fn test_parse_varint() {
+        use super::{parse_varint, Error};
+
+        fn t(s: &[u8]) -> (u64, usize) {
+            parse_varint(s).unwrap()
+        }
+
+        fn e(s: &[u8]) -> Error {
+            parse_varint(s).unwrap_err()
+        }
+
+        assert_eq!(t(b"\x00"), (0, 1));
+        assert_eq!(t(b"\x01"), (1, 1));
+        assert_eq!(t(b"\x80\x01"), (128, 2));
+        assert_eq!(t(b"\x80\x80\x01"), (16384, 3));
+        assert_eq!(t(b"\x81\x01"), (129, 2));
+        assert_eq!(t(b"\x81\x81\x00"), (129, 3));
+        assert_eq!(t(b"\x80\x80\x80\x80\x80\x80\x80\x80\x80\x00"), (0, 10));
+        assert_eq!(t(b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x7f"), (u64::MAX, 10));
+        assert_eq!(t(b"\x01\x02"), (1, 1));
+
+        assert!(e(b"").is_eof());
+        assert!(e(b"\x80").is_eof());
+        assert!(e(b"\x80\x80\x80\x80\x80\x80\x80\x80\x80\x80\x00").is_overflow());
+    }
+
+    #[test]
+    fn test_parse_zigzag() {
+        use super::parse_zigzag;
+
+        fn t(s: &[u8]) -> i64 {
+            parse_zigzag(s).unwrap().0
+        }
+
+        assert_eq!(t(b"\x00"), 0);
+        assert_eq!(t(b"\x01"), -1);
+        assert_eq!(t(b"\x02"), 1);
+        assert_eq!(t(b"\x03"), -2);
+        assert_eq!(t(b"\x04"), 2);
+        assert_eq!(t(b"\x80\x01"), 64);
+        assert_eq!(t(b"\x80\x80\x01"), 8192);
+        assert_eq!(t(b"\x81\x01"), -65);
+        assert_eq!(t(b"\x81\x81\x00"), -65);
+        assert_eq!(t(b"\x80\x80\x80\x80\x80\x80\x80\x80\x80\x00"), 0);
+        assert_eq!(t(b"\xfe\xff\xff\xff\xff\xff\xff\xff\xff\x7f"), i64::MAX);
+        assert_eq!(t(b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x7f"), i64::MIN);
+
+        assert!(parse_zigzag(b"\x80").unwrap_err().is_eof());
+    }
+
+    #[test]
+    fn test_consistency() {
+        use super::{parse_varint, write_varint, Error};
+
+        // Read `s` through both implementations and check that they agree on
+        // the value and the number of bytes consumed, or on the error.
+        fn check(s: &[u8]) {
+            let mut cur = Cursor::new(s);
+            let stream = cur.read_varint();
+            let slice = parse_varint(s);
+
+            match (stream, slice) {
+                (Ok(a), Ok((b, len))) => {
+                    assert_eq!(a, b, "{:?}", s);
+                    assert_eq!(cur.position() as usize, len, "{:?}", s);
+                }
+                (Err(ref a), Err(Error::UnexpectedEof)) => {
+                    assert_eq!(a.kind(), io::ErrorKind::UnexpectedEof, "{:?}", s)
+                }
+                (Err(ref a), Err(Error::Overflow)) => {
+                    assert_eq!(format!("{}", a), "varint overflow", "{:?}", s)
+                }
+                (a, b) => panic!("{:?}: stream {:?}, slice {:?}", s, a, b),
+            }
+        }
+
+        let inputs: &[&[u8]] = &[
+            b"",
+            b"\x00",
+            b"\x7f",
+            b"\x80",
+            b"\x80\x00",
+            b"\xff\xff\xff\xff\xff\xff\xff\xff\xff",
+            b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01",
+            b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x7f",
+            b"\x80\x80\x80\x80\x80\x80\x80\x80\x80\x80",
+            b"\x80\x80\x80\x80\x80\x80\x80\x80\x80\x80\x00",
+            b"\x80\x80\x80\x80\x80\x80\x80\x80\x80\x80\x80\x80\x00",
+        ];
+
+        for s in inputs {
+            check(s);
+        }
+
+        for &v in &[0, 1, 127, 128, 16383, 16384, 1 << 63, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, v);
+            buf.push(0x55);
+            for len in 0..buf.len() + 1 {
+                check(&buf[..len]);
+            }
+        }
+
+        // xorshift, so that the generated cases are the same on every run
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..10_000 {
+            let mut buf = Vec::new();
+            loop {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+
+                buf.push(state as u8 & 0x7f | if state & 0x300 != 0 { 0x80 } else { 0 });
+                if state & 0x300 == 0 || buf.len() > 12 {
+                    break;
+                }
+            }
+            check(&buf);
+        }
+    }
+
+    #[test]
+    fn test_write() {
+        use super::{write_varint, write_zigzag};
+
+        for &v in &[0, 1, 127, 128, 129, 16384, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, v);
+            assert_eq!(Cursor::new(&buf).read_varint().unwrap(), v);
+        }
+
+        for &v in &[0, 1, -1, 64, -65, i64::MIN, i64::MAX] {
+            let mut buf = Vec::new();
+            write_zigzag(&mut buf, v);
+            assert_eq!(Cursor::new(&buf).read_zigzag().unwrap(), v);
+        }
+
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, b"\xac\x02");
+    }
 }
@@ -0,0 +1,83 @@
+// Counts the allocations made while decoding. Lives in its own binary
+// because it installs a counting global allocator. The count is kept per
+// thread, so tests running in parallel do not see each other's.
+
+extern crate sereal_decoder;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use sereal_decoder::arc::{ArcBuilder, Pools};
+use sereal_decoder::config::Config;
+use sereal_decoder::null::NullBuilder;
+use sereal_decoder::parser::Parser;
+
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const ROUNDS: usize = 10_000;
+
+// [ [1, 2, 3], { a => 1, b => [2] }, [4, 5] ]
+const DOC: &[u8] = b"\x43\x43\x01\x02\x03\x52\x61a\x01\x61b\x41\x02\x42\x04\x05";
+
+fn allocations<F: FnMut()>(mut f: F) -> usize {
+    let start = ALLOCATIONS.with(|n| n.get());
+    for _ in 0..ROUNDS {
+        f();
+    }
+    ALLOCATIONS.with(|n| n.get()) - start
+}
+
+#[test]
+fn pools_reduce_allocations() {
+    let config = Config::default();
+
+    let plain = allocations(|| {
+        Parser::new(ArcBuilder::new(), &config, DOC).parse().unwrap();
+    });
+
+    let pools = Pools::new();
+    let pooled = allocations(|| {
+        let v = Parser::new(ArcBuilder::with_pools(&pools), &config, DOC).parse().unwrap();
+        pools.recycle(v);
+    });
+
+    // Every container after the first round comes from the pools, which
+    // leaves only the value cells and hash keys to allocate.
+    assert!(plain - pooled >= 4 * (ROUNDS - 1), "{} pooled vs {} plain", pooled, plain);
+}
+
+#[test]
+fn untracked_documents_do_not_allocate() {
+    let config = Config::default();
+
+    // The map of tracked values is only allocated on first insert, so a
+    // document without track bits costs nothing beyond its values.
+    let untracked = allocations(|| {
+        Parser::new(NullBuilder, &config, DOC).parse().unwrap();
+    });
+    assert_eq!(untracked, 0);
+
+    // [ \[1], REFP to it ]
+    let tracked = allocations(|| {
+        Parser::new(NullBuilder, &config, b"\x42\xc1\x01\x29\x02").parse().unwrap();
+    });
+    assert!(tracked >= ROUNDS, "{} allocations", tracked);
+}
@@ -1,16 +1,19 @@
 extern crate clap;
 extern crate sereal_decoder;
 
-use std::io::stdout;
 use std::fs::File;
-use std::io::Write;
+use std::io;
 
 use clap::App;
 use clap::Arg;
 
 use sereal_decoder::arena::{ Arena, ArenaBuilder };
-use sereal_decoder::parse;
-use sereal_decoder::Error;
+use sereal_decoder::config::Config;
+use sereal_decoder::parser::Parser;
+use sereal_decoder::read_body;
+
+/// Bytes shown on each side of the position where parsing failed.
+const CONTEXT_RADIUS: usize = 16;
 
 fn main() {
     let matches = App::new("sereal-dump")
@@ -20,6 +23,7 @@ fn main() {
             .long("quiet")
             .help("do not dump the contents, just parse the file"))
         .arg(Arg::with_name("input")
+            .help("file to read, or - for stdin")
             .required(true)
             .index(1))
         .get_matches();
@@ -28,15 +32,31 @@ fn main() {
     let quiet = matches.is_present("quiet");
 
     if let Err(err) = process(fname, quiet) {
-        writeln!(stdout(), "{}: {:?}", fname, err).unwrap();
+        println!("{}: {}", fname, err);
     }
 }
 
-fn process(name: &str, quiet: bool) -> Result<(), Error> {
-    let file = File::open(name)?;
+fn process(name: &str, quiet: bool) -> Result<(), String> {
+    let input: Box<dyn io::Read> = if name == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(name).map_err(|e| e.to_string())?)
+    };
+
+    let config = Config::default();
     let mut buf = Vec::new();
+    read_body(input, &mut buf, &config).map_err(|e| e.to_string())?;
+
     let arena = Arena::new();
-    let value = parse(file, ArenaBuilder::new(&arena), &mut buf)?;
+    let mut parser = Parser::new(ArenaBuilder::new(&arena), &config, &buf);
+    let value = match parser.parse() {
+        Ok(value) => value,
+        Err(err) => {
+            let (pos, window) = parser.context(CONTEXT_RADIUS);
+            let context = hex_context(pos, window);
+            return Err(format!("{}, stopped at byte {} of the body\n{}", err, pos, context));
+        }
+    };
 
     if !quiet {
         println!("{:#?}", value);
@@ -44,3 +64,22 @@ fn process(name: &str, quiet: bool) -> Result<(), Error> {
 
     Ok(())
 }
+
+/// Hex dump of `window`, with a `|` where the parser stopped at `pos`.
+fn hex_context(pos: usize, window: &[u8]) -> String {
+    let start = pos - pos.min(CONTEXT_RADIUS);
+    let mut out = format!("{:8}:", start);
+
+    for (i, b) in window.iter().enumerate() {
+        if start + i == pos {
+            out.push_str(" |");
+        }
+        out.push_str(&format!(" {:02x}", b));
+    }
+
+    if start + window.len() == pos {
+        out.push_str(" |");
+    }
+
+    out
+}